@@ -0,0 +1,14 @@
+use super::AuthAdapter;
+use crate::error::Error;
+use async_trait::async_trait;
+use bson::Document;
+
+/// Anonymous users only carry a client generated id, there is nothing to verify.
+pub struct AnonymousAdapter;
+
+#[async_trait(?Send)]
+impl AuthAdapter for AnonymousAdapter {
+    async fn validate_auth_data(&self, _auth_data: &Document) -> Result<(), Error> {
+        Ok(())
+    }
+}
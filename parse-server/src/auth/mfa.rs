@@ -0,0 +1,404 @@
+use super::{AuthAdapter, StoredUpdate};
+use crate::error::Error;
+use crate::util;
+use async_trait::async_trait;
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+
+/// Time based one-time passwords (RFC 6238) as an additional login factor.
+///
+/// Enrollment sends `{"secret": "<base32>", "token": "123456"}` through
+/// `authData.mfa`, logins then need `authData: {"mfa": {"token": ...}}` where
+/// the token may also be one of the recovery codes returned at enrollment.
+/// Replacing the secret also needs `oldToken`, a token or recovery code of
+/// the enrolled one, and `{"mfa": {"oldToken": ...}}` alone removes it.
+///
+/// Recovery codes are stored hashed and the secret encrypted with the key
+/// of the adapter, since tokens are computed from it. Each token is accepted
+/// once, the time step of the last one is stored as `last`.
+#[derive(Clone)]
+pub struct MfaAdapter {
+    pub digits: u32,
+    /// Seconds each token is valid for.
+    pub period: u64,
+    /// Tokens of this many periods before and after now are accepted.
+    pub window: u64,
+    pub recovery_codes: usize,
+    key: [u8; 32],
+}
+
+fn mfa_error() -> Error {
+    Error::MfaError("Invalid MFA token".to_string())
+}
+
+/// Decodes RFC 4648 base32, ignoring case and padding.
+fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0_u32, 0);
+    for c in value.trim_end_matches('=').bytes() {
+        let index = ALPHABET.iter().position(|x| *x == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | index as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    digest::digest(&digest::SHA256, code.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+impl MfaAdapter {
+    /// `encryption_key` encrypts the stored secrets, changing it disables
+    /// the enrolled factors.
+    pub fn new(encryption_key: &str) -> Self {
+        let mut key = [0; 32];
+        key.copy_from_slice(digest::digest(&digest::SHA256, encryption_key.as_bytes()).as_ref());
+        MfaAdapter {
+            digits: 6,
+            period: 30,
+            window: 1,
+            recovery_codes: 2,
+            key,
+        }
+    }
+
+    fn cipher(&self) -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.key).expect("Invalid key length"))
+    }
+
+    /// AES-256-GCM with a random nonce, stored as base64 of nonce and
+    /// ciphertext.
+    fn encrypt(&self, secret: &[u8]) -> Result<String, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| Error::Internal("Could not encrypt MFA secret".to_string()))?;
+        let mut sealed = secret.to_vec();
+        self.cipher()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| Error::Internal("Could not encrypt MFA secret".to_string()))?;
+        Ok(base64::encode([&nonce[..], &sealed].concat()))
+    }
+
+    fn decrypt(&self, stored: &str) -> Option<Vec<u8>> {
+        let mut sealed = base64::decode(stored).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let mut in_out = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).ok()?;
+        let secret = self
+            .cipher()
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .ok()?;
+        Some(secret.to_vec())
+    }
+
+    fn hotp(&self, secret: &[u8], counter: u64) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+        let hash = hmac::sign(&key, &counter.to_be_bytes());
+        let hash = hash.as_ref();
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let code = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            code % 10_u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+
+    /// The time step of `token` at `now` (epoch seconds), only steps after
+    /// `last` are accepted so a token can't be replayed.
+    fn token_step(&self, secret: &[u8], token: &str, now: i64, last: Option<u64>) -> Option<u64> {
+        let counter = now as u64 / self.period;
+        // `None` is before any step
+        (counter.saturating_sub(self.window)..=counter + self.window)
+            .filter(|x| last < Some(*x))
+            .find(|x| self.hotp(secret, *x) == token)
+    }
+
+    /// The time step of `token` if it is valid for the enrolled factor.
+    fn verify_token(&self, stored: &Document, token: &str) -> Option<u64> {
+        let secret = self.decrypt(stored.get_str("secret").ok()?)?;
+        let last = stored.get_i64("last").ok().map(|x| x as u64);
+        self.token_step(&secret, token, Utc::now().timestamp(), last)
+    }
+}
+
+#[async_trait(?Send)]
+impl AuthAdapter for MfaAdapter {
+    async fn validate_auth_data(&self, _auth_data: &Document) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_additional(&self) -> bool {
+        true
+    }
+
+    async fn validate_setup(
+        &self,
+        auth_data: &Document,
+    ) -> Result<(Option<Document>, Option<Document>), Error> {
+        let secret = match auth_data.get_str("secret") {
+            Ok(secret) => secret,
+            Err(_) => return Ok((None, None)),
+        };
+        // 20 bytes as recommended by RFC 4226, 16 at the very least
+        let secret = match decode_base32(secret) {
+            Some(secret) if secret.len() >= 16 => secret,
+            _ => return Err(Error::MfaError("Invalid MFA secret".to_string())),
+        };
+        let token = auth_data.get_str("token").unwrap_or("");
+        let last = self
+            .token_step(&secret, token, Utc::now().timestamp(), None)
+            .ok_or_else(mfa_error)?;
+
+        let codes: Vec<String> = (0..self.recovery_codes)
+            .map(|_| util::new_token())
+            .collect();
+        let hashed: Vec<Bson> = codes
+            .iter()
+            .map(|x| Bson::String(hash_recovery_code(x)))
+            .collect();
+        Ok((
+            Some(doc! {
+                "secret": self.encrypt(&secret)?,
+                "recovery": hashed,
+                "last": last as i64,
+            }),
+            Some(doc! {"recovery": codes}),
+        ))
+    }
+
+    async fn validate_login(
+        &self,
+        auth_data: &Document,
+        stored: &Document,
+    ) -> Result<Option<StoredUpdate>, Error> {
+        let token = auth_data.get_str("token").unwrap_or("");
+        if token.is_empty() {
+            return Err(Error::MfaTokenRequired(
+                "Missing additional authData mfa".to_string(),
+            ));
+        }
+        if let Some(step) = self.verify_token(stored, token) {
+            // The set only applies while no other login used a newer token
+            let filter = match stored.get_i64("last") {
+                Ok(last) => doc! {"last": last},
+                Err(_) => doc! {"last": {"$exists": false}},
+            };
+            return Ok(Some(StoredUpdate {
+                filter,
+                update: doc! {"$set": {"last": step as i64}},
+            }));
+        }
+
+        // Recovery codes can only be used once, the pull only applies while
+        // the code is still there
+        let hashed = hash_recovery_code(token);
+        let recovery = stored.get_array("recovery").map_err(|_| mfa_error())?;
+        if !recovery.contains(&Bson::String(hashed.clone())) {
+            return Err(mfa_error());
+        }
+        Ok(Some(StoredUpdate {
+            filter: doc! {"recovery": &hashed},
+            update: doc! {"$pull": {"recovery": &hashed}},
+        }))
+    }
+
+    async fn validate_update(&self, auth_data: &Document, stored: &Document) -> Result<(), Error> {
+        let old_token = auth_data.get_str("oldToken").unwrap_or("");
+        if old_token.is_empty() {
+            return Err(Error::MfaTokenRequired(
+                "Missing additional authData mfa oldToken".to_string(),
+            ));
+        }
+        if self.verify_token(stored, old_token).is_some() {
+            return Ok(());
+        }
+        let hashed = Bson::String(hash_recovery_code(old_token));
+        match stored.get_array("recovery") {
+            Ok(recovery) if recovery.contains(&hashed) => Ok(()),
+            _ => Err(mfa_error()),
+        }
+    }
+
+    fn after_find(&self, _stored: &Document) -> Document {
+        doc! {"status": "enabled"}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::System;
+
+    /// The RFC 4226 and RFC 6238 secret `12345678901234567890`.
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn adapter() -> MfaAdapter {
+        MfaAdapter::new("server key")
+    }
+
+    #[test]
+    fn decodes_rfc_4648_base32() {
+        let vectors = vec![
+            ("", ""),
+            ("MY======", "f"),
+            ("MZXQ====", "fo"),
+            ("MZXW6===", "foo"),
+            ("MZXW6YQ=", "foob"),
+            ("MZXW6YTB", "fooba"),
+            ("MZXW6YTBOI======", "foobar"),
+            ("mzxw6ytboi", "foobar"),
+        ];
+        for (encoded, decoded) in vectors {
+            assert_eq!(decode_base32(encoded), Some(decoded.as_bytes().to_vec()));
+        }
+        assert_eq!(
+            decode_base32(SECRET),
+            Some(b"12345678901234567890".to_vec())
+        );
+        assert_eq!(decode_base32("MZXW1"), None);
+    }
+
+    #[test]
+    fn computes_rfc_4226_hotp() {
+        let codes = vec![
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(
+                adapter().hotp(b"12345678901234567890", counter as u64),
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn computes_rfc_6238_totp() {
+        let adapter = MfaAdapter {
+            digits: 8,
+            window: 0,
+            ..adapter()
+        };
+        let vectors = vec![
+            (59, "94287082"),
+            (1_111_111_109, "07081804"),
+            (1_111_111_111, "14050471"),
+            (1_234_567_890, "89005924"),
+            (2_000_000_000, "69279037"),
+            (20_000_000_000, "65353130"),
+        ];
+        for (time, token) in vectors {
+            let step = adapter.token_step(b"12345678901234567890", token, time, None);
+            assert_eq!(step, Some(time as u64 / 30));
+        }
+    }
+
+    #[test]
+    fn rejects_tokens_at_or_before_the_last_step() {
+        let adapter = adapter();
+        let secret = b"12345678901234567890";
+        let now = 1_111_111_111;
+        let step = now as u64 / 30;
+        let previous = adapter.hotp(secret, step - 1);
+        let current = adapter.hotp(secret, step);
+        assert_eq!(
+            adapter.token_step(secret, &previous, now, None),
+            Some(step - 1)
+        );
+        assert_eq!(
+            adapter.token_step(secret, &previous, now, Some(step - 1)),
+            None
+        );
+        assert_eq!(
+            adapter.token_step(secret, &current, now, Some(step - 1)),
+            Some(step)
+        );
+        assert_eq!(adapter.token_step(secret, &current, now, Some(step)), None);
+        // Outside of the window
+        let old = adapter.hotp(secret, step - 2);
+        assert_eq!(adapter.token_step(secret, &old, now, None), None);
+    }
+
+    #[test]
+    fn encrypts_the_secret_with_the_server_key() {
+        let encrypted = adapter().encrypt(b"secret").unwrap();
+        assert!(!encrypted.contains("secret"));
+        assert_ne!(encrypted, adapter().encrypt(b"secret").unwrap());
+        assert_eq!(adapter().decrypt(&encrypted), Some(b"secret".to_vec()));
+        assert_eq!(MfaAdapter::new("other key").decrypt(&encrypted), None);
+        assert_eq!(adapter().decrypt("c2VjcmV0"), None);
+    }
+
+    #[test]
+    fn enrolls_then_accepts_each_token_once() {
+        System::new("test").block_on(async {
+            let adapter = adapter();
+            let step = Utc::now().timestamp() as u64 / adapter.period;
+            let token = adapter.hotp(b"12345678901234567890", step);
+            let setup = doc! {"secret": SECRET, "token": &token};
+            let (stored, response) = adapter.validate_setup(&setup).await.unwrap();
+            let stored = stored.unwrap();
+            assert_ne!(stored.get_str("secret"), Ok(SECRET));
+            assert_eq!(stored.get_i64("last"), Ok(step as i64));
+
+            // The enrollment token was used already
+            let login = doc! {"token": &token};
+            assert!(adapter.validate_login(&login, &stored).await.is_err());
+            let next = doc! {"token": adapter.hotp(b"12345678901234567890", step + 1)};
+            let change = adapter
+                .validate_login(&next, &stored)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(change.filter, doc! {"last": step as i64});
+            assert_eq!(change.update, doc! {"$set": {"last": (step + 1) as i64}});
+
+            // Recovery codes are stored hashed and pulled once used
+            let codes = response.unwrap().get_array("recovery").unwrap().clone();
+            assert_eq!(codes.len(), 2);
+            let code = codes[0].as_str().unwrap();
+            let hashed = hash_recovery_code(code);
+            assert!(stored
+                .get_array("recovery")
+                .unwrap()
+                .contains(&Bson::from(&hashed)));
+            let change = adapter.validate_login(&doc! {"token": code}, &stored).await;
+            let change = change.unwrap().unwrap();
+            assert_eq!(change.filter, doc! {"recovery": &hashed});
+            assert_eq!(change.update, doc! {"$pull": {"recovery": &hashed}});
+        });
+    }
+
+    #[test]
+    fn hashes_recovery_codes_with_sha_256() {
+        assert_eq!(
+            hash_recovery_code("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use crate::error::Error;
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+mod anonymous;
+mod mfa;
+mod oauth2;
+
+pub use anonymous::AnonymousAdapter;
+pub use mfa::MfaAdapter;
+pub use oauth2::OAuth2Adapter;
+
+/// A change to the stored data of an additional factor, e.g. a used recovery
+/// code. `update` is a mongo update applied only while `filter` still
+/// matches, keys of both are relative to the stored data.
+pub struct StoredUpdate {
+    pub filter: Document,
+    pub update: Document,
+}
+
+/// Validates the `authData` a client sends for one provider.
+#[async_trait(?Send)]
+pub trait AuthAdapter: Send + Sync {
+    /// Checks that the provider data (`id`, tokens...) belongs to the user.
+    async fn validate_auth_data(&self, auth_data: &Document) -> Result<(), Error>;
+
+    /// Checks that the provider data was issued for this application.
+    async fn validate_app_id(&self, _auth_data: &Document) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Additional factors don't identify a user, they are checked on top of
+    /// the password at login.
+    fn is_additional(&self) -> bool {
+        false
+    }
+
+    /// Turns the data sent when an additional factor is enrolled into the
+    /// data to store, `None` removes the factor, and the data returned to the
+    /// client.
+    async fn validate_setup(
+        &self,
+        auth_data: &Document,
+    ) -> Result<(Option<Document>, Option<Document>), Error> {
+        Ok((Some(auth_data.clone()), None))
+    }
+
+    /// Checks the data sent at login against the stored data of an additional
+    /// factor, returns how the stored data changes when it does.
+    async fn validate_login(
+        &self,
+        _auth_data: &Document,
+        _stored: &Document,
+    ) -> Result<Option<StoredUpdate>, Error> {
+        Ok(None)
+    }
+
+    /// Checks that the data sent to replace or remove an enrolled additional
+    /// factor proves the user holds it, the same way a login does by default.
+    async fn validate_update(&self, auth_data: &Document, stored: &Document) -> Result<(), Error> {
+        self.validate_login(auth_data, stored).await.map(|_| ())
+    }
+
+    /// The stored data as returned to the user itself.
+    fn after_find(&self, stored: &Document) -> Document {
+        stored.clone()
+    }
+}
+
+/// Auth adapters keyed by provider name, `anonymous` is registered by default.
+#[derive(Clone)]
+pub struct AuthAdapters {
+    adapters: HashMap<String, Arc<dyn AuthAdapter>>,
+}
+
+impl Default for AuthAdapters {
+    fn default() -> Self {
+        AuthAdapters::new()
+    }
+}
+
+impl AuthAdapters {
+    pub fn new() -> Self {
+        let mut adapters = AuthAdapters {
+            adapters: HashMap::new(),
+        };
+        adapters.insert("anonymous", AnonymousAdapter);
+        adapters
+    }
+
+    pub fn insert<A: AuthAdapter + 'static>(&mut self, provider: &str, adapter: A) {
+        self.adapters
+            .insert(provider.to_string(), Arc::new(adapter));
+    }
+
+    pub fn remove(&mut self, provider: &str) {
+        self.adapters.remove(provider);
+    }
+
+    pub fn get(&self, provider: &str) -> Option<Arc<dyn AuthAdapter>> {
+        self.adapters.get(provider).cloned()
+    }
+
+    /// Applies `after_find` of each adapter to the `authData` of a user.
+    pub fn hide_auth_data(&self, user: &mut Document) {
+        if let Ok(auth_data) = user.get_document_mut("authData") {
+            *auth_data = auth_data
+                .iter()
+                .map(|(provider, data)| match (self.get(provider), data) {
+                    (Some(adapter), Bson::Document(stored)) => {
+                        (provider.clone(), Bson::Document(adapter.after_find(stored)))
+                    }
+                    _ => (provider.clone(), data.clone()),
+                })
+                .collect();
+        }
+    }
+
+    /// Providers that are additional factors, e.g. `mfa`.
+    pub fn additional(&self) -> Vec<(String, Arc<dyn AuthAdapter>)> {
+        self.adapters
+            .iter()
+            .filter(|(_, adapter)| adapter.is_additional())
+            .map(|(provider, adapter)| (provider.clone(), adapter.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,188 @@
+use super::AuthAdapter;
+use crate::error::Error;
+use actix_web::client::Client;
+use async_trait::async_trait;
+use bson::Document;
+use serde_json::Value;
+
+/// Validates OAuth2 access tokens against a token introspection endpoint (RFC 7662).
+///
+/// The client sends `{"id": "...", "access_token": "..."}`, the token must be
+/// active and, when configured, its user and app id claims must match.
+#[derive(Clone)]
+pub struct OAuth2Adapter {
+    pub introspection_endpoint_url: String,
+    pub authorization_header: Option<String>,
+    pub user_id_field: Option<String>,
+    pub app_id_field: Option<String>,
+    pub app_ids: Vec<String>,
+}
+
+impl OAuth2Adapter {
+    pub fn new(introspection_endpoint_url: &str) -> Self {
+        OAuth2Adapter {
+            introspection_endpoint_url: introspection_endpoint_url.to_string(),
+            authorization_header: None,
+            user_id_field: None,
+            app_id_field: None,
+            app_ids: vec![],
+        }
+    }
+
+    pub fn authorization_header(mut self, value: &str) -> Self {
+        self.authorization_header = Some(value.to_string());
+        self
+    }
+
+    pub fn user_id_field(mut self, field: &str) -> Self {
+        self.user_id_field = Some(field.to_string());
+        self
+    }
+
+    pub fn app_ids(mut self, field: &str, app_ids: Vec<String>) -> Self {
+        self.app_id_field = Some(field.to_string());
+        self.app_ids = app_ids;
+        self
+    }
+
+    async fn introspect(&self, auth_data: &Document) -> Result<Value, Error> {
+        let token = auth_data
+            .get_str("access_token")
+            .map_err(|_| Error::NotFound("OAuth2 access token is missing.".to_string()))?;
+
+        let mut request = Client::default()
+            .post(&self.introspection_endpoint_url)
+            .header("Accept", "application/json");
+        if let Some(header) = &self.authorization_header {
+            request = request.header("Authorization", header.as_str());
+        }
+
+        let mut response = request
+            .send_form(&[("token", token)])
+            .await
+            .map_err(|e| Error::Internal(format!("OAuth2 introspection failed: {}", e)))?;
+        if !response.status().is_success() {
+            let message = format!(
+                "OAuth2 introspection failed with status {}",
+                response.status()
+            );
+            error!("{}", message);
+            return Err(Error::Internal(message));
+        }
+
+        let body = response
+            .json::<Value>()
+            .await
+            .map_err(|e| Error::Internal(format!("OAuth2 introspection failed: {}", e)))?;
+        if body.get("active").and_then(|x| x.as_bool()) != Some(true) {
+            return Err(Error::NotFound(
+                "OAuth2 access token is invalid for this user.".to_string(),
+            ));
+        }
+        Ok(body)
+    }
+
+    fn check_app_id(&self, body: &Value) -> Result<(), Error> {
+        let field = match &self.app_id_field {
+            Some(field) => field,
+            None => return Ok(()),
+        };
+        let app_id = body.get(field).and_then(|x| x.as_str()).unwrap_or("");
+        if self.app_ids.iter().any(|x| x == app_id) {
+            Ok(())
+        } else {
+            Err(Error::NotFound(
+                "OAuth2: the access_token's appID is empty or is not in the list of permitted appIDs."
+                    .to_string(),
+            ))
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl AuthAdapter for OAuth2Adapter {
+    /// Also checks the app id, both come from the same introspection.
+    async fn validate_auth_data(&self, auth_data: &Document) -> Result<(), Error> {
+        let body = self.introspect(auth_data).await?;
+        if let Some(field) = &self.user_id_field {
+            let id = auth_data.get_str("id").unwrap_or("");
+            if body.get(field).and_then(|x| x.as_str()) != Some(id) {
+                return Err(Error::NotFound(
+                    "OAuth2 access token is invalid for this user.".to_string(),
+                ));
+            }
+        }
+        self.check_app_id(&body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{rt::System, test, web, App, HttpResponse};
+    use bson::doc;
+    use futures::future::ready;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Stands in for the introspection endpoint, `good` is the only active
+    /// token.
+    fn introspection_server(calls: Arc<AtomicUsize>) -> test::TestServer {
+        test::start(move || {
+            let calls = calls.clone();
+            App::new().route(
+                "/introspect",
+                web::post().to(move |form: web::Form<HashMap<String, String>>| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let body = match form.get("token").map(|x| x.as_str()) {
+                        Some("good") => json!({"active": true, "sub": "user1", "aud": "app1"}),
+                        _ => json!({"active": false}),
+                    };
+                    ready(HttpResponse::Ok().json(body))
+                }),
+            )
+        })
+    }
+
+    fn adapter(server: &test::TestServer) -> OAuth2Adapter {
+        OAuth2Adapter::new(&server.url("/introspect"))
+            .user_id_field("sub")
+            .app_ids("aud", vec!["app1".to_string()])
+    }
+
+    #[test]
+    fn validates_with_a_single_introspection() {
+        System::new("test").block_on(async {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let server = introspection_server(calls.clone());
+            let auth_data = doc! {"id": "user1", "access_token": "good"};
+            let adapter = adapter(&server);
+            assert!(adapter.validate_auth_data(&auth_data).await.is_ok());
+            assert!(adapter.validate_app_id(&auth_data).await.is_ok());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn rejects_inactive_tokens_and_other_users_or_apps() {
+        System::new("test").block_on(async {
+            let server = introspection_server(Arc::new(AtomicUsize::new(0)));
+            let inactive = doc! {"id": "user1", "access_token": "bad"};
+            let other_user = doc! {"id": "user2", "access_token": "good"};
+            assert!(adapter(&server)
+                .validate_auth_data(&inactive)
+                .await
+                .is_err());
+            assert!(adapter(&server)
+                .validate_auth_data(&other_user)
+                .await
+                .is_err());
+            let other_app = OAuth2Adapter::new(&server.url("/introspect"))
+                .app_ids("aud", vec!["app2".to_string()]);
+            let auth_data = doc! {"id": "user1", "access_token": "good"};
+            assert!(other_app.validate_auth_data(&auth_data).await.is_err());
+        });
+    }
+}
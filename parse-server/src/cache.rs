@@ -1,16 +1,29 @@
+use crate::cloud::Webhooks;
 use crate::schema;
-use crate::user::User;
+use bson::Document;
+use chrono::{DateTime, Duration, Utc};
 use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 
 pub type Schema = HashMap<String, schema::Schema>;
-pub type Sessions = HashMap<String, User>;
+/// User ids and session expiry keyed by session token.
+pub type Sessions = HashMap<String, (String, Option<DateTime<Utc>>)>;
+pub type Roles = HashMap<String, Vec<String>>;
+
+/// Seconds the global config is cached, other servers don't see updates
+/// until then.
+const GLOBAL_CONFIG_TTL: i64 = 10;
 
 pub struct AppCache {
     pub schema: RwLock<Schema>,
     pub schema_loaded: AtomicBool,
     pub sessions: RwLock<Sessions>,
+    pub roles: RwLock<Roles>,
+    pub webhooks: RwLock<Option<Arc<Webhooks>>>,
+    /// The `_GlobalConfig` document, `params` and `masterKeyOnly`, and when
+    /// it was loaded.
+    pub global_config: RwLock<Option<(Document, DateTime<Utc>)>>,
 }
 
 impl AppCache {
@@ -19,6 +32,9 @@ impl AppCache {
             schema: RwLock::new(HashMap::new()),
             schema_loaded: AtomicBool::from(false),
             sessions: RwLock::new(HashMap::new()),
+            roles: RwLock::new(HashMap::new()),
+            webhooks: RwLock::new(None),
+            global_config: RwLock::new(None),
         }
     }
 
@@ -27,4 +43,93 @@ impl AppCache {
         let value = schema.get(class_name);
         value.map(|x| x.clone())
     }
+
+    pub fn get_roles(&self, user_id: &str) -> Option<Vec<String>> {
+        let roles = self.roles.read().expect("RwLock poisoned");
+        roles.get(user_id).cloned()
+    }
+
+    pub fn set_roles(&self, user_id: &str, user_roles: Vec<String>) {
+        let mut roles = self.roles.write().expect("RwLock poisoned");
+        roles.insert(user_id.to_string(), user_roles);
+    }
+
+    /// The user id of a cached session, expired sessions are evicted.
+    pub fn get_session(&self, session_token: &str) -> Option<String> {
+        {
+            let sessions = self.sessions.read().expect("RwLock poisoned");
+            match sessions.get(session_token) {
+                Some((_, Some(expires_at))) if *expires_at < Utc::now() => {}
+                Some((user_id, _)) => return Some(user_id.clone()),
+                None => return None,
+            }
+        }
+        self.remove_session(session_token);
+        None
+    }
+
+    pub fn set_session(
+        &self,
+        session_token: &str,
+        user_id: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) {
+        let mut sessions = self.sessions.write().expect("RwLock poisoned");
+        sessions.insert(session_token.to_string(), (user_id.to_string(), expires_at));
+    }
+
+    pub fn remove_session(&self, session_token: &str) {
+        let mut sessions = self.sessions.write().expect("RwLock poisoned");
+        sessions.remove(session_token);
+    }
+
+    /// Drops the cached sessions of a user, e.g. after a password change.
+    pub fn remove_user_sessions(&self, user_id: &str) {
+        let mut sessions = self.sessions.write().expect("RwLock poisoned");
+        sessions.retain(|_, (x, _)| x != user_id);
+    }
+
+    /// Drops every cached session, `_Session` writes don't name the token.
+    pub fn clear_sessions(&self) {
+        self.sessions.write().expect("RwLock poisoned").clear();
+    }
+
+    /// Drops every cached role list, any role write may change the role graph.
+    pub fn clear_roles(&self) {
+        self.roles.write().expect("RwLock poisoned").clear();
+    }
+
+    pub fn get_webhooks(&self) -> Option<Arc<Webhooks>> {
+        self.webhooks.read().expect("RwLock poisoned").clone()
+    }
+
+    pub fn set_webhooks(&self, webhooks: Arc<Webhooks>) {
+        *self.webhooks.write().expect("RwLock poisoned") = Some(webhooks);
+    }
+
+    /// Webhooks are reloaded from `_Hooks` on next use.
+    pub fn clear_webhooks(&self) {
+        *self.webhooks.write().expect("RwLock poisoned") = None;
+    }
+
+    pub fn get_global_config(&self) -> Option<Document> {
+        let global_config = self.global_config.read().expect("RwLock poisoned");
+        match &*global_config {
+            Some((global_config, loaded_at))
+                if Utc::now() - *loaded_at < Duration::seconds(GLOBAL_CONFIG_TTL) =>
+            {
+                Some(global_config.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_global_config(&self, global_config: Document) {
+        *self.global_config.write().expect("RwLock poisoned") = Some((global_config, Utc::now()));
+    }
+
+    /// The global config is reloaded from `_GlobalConfig` on next use.
+    pub fn clear_global_config(&self) {
+        *self.global_config.write().expect("RwLock poisoned") = None;
+    }
 }
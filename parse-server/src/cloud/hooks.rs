@@ -0,0 +1,488 @@
+use super::trigger::Triggers;
+use super::{
+    AfterFindTrigger, BeforeFind, BeforeFindTrigger, BeforeSaveTrigger, FindTriggerRequest, Query,
+    Trigger, TriggerRequest,
+};
+use crate::error::Error;
+use crate::operation::Context;
+use crate::user::User;
+use crate::util;
+use actix_web::client::Client;
+use bson::{doc, Bson, Document};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+const TRIGGER_NAMES: &[&str] = &[
+    "beforeSave",
+    "afterSave",
+    "beforeDelete",
+    "afterDelete",
+    "beforeFind",
+    "afterFind",
+];
+
+/// Functions and triggers backed by the webhooks in `_Hooks`.
+#[derive(Default)]
+pub struct Webhooks {
+    pub functions: HashMap<String, String>,
+    pub triggers: Triggers,
+}
+
+fn user_body(user: &User) -> Option<Document> {
+    let id = user.id.as_ref()?;
+    Some(doc! {"__type": "Object", "className": "_User", "objectId": id})
+}
+
+/// Fields of every webhook payload.
+pub(super) fn request_body(user: &User, master: bool, context: &Document) -> Document {
+    let mut body = doc! {"master": master, "context": context.clone()};
+    if let Some(user) = user_body(user) {
+        body.insert("user", user);
+    }
+    if let Some(installation_id) = &user.installation_id {
+        body.insert("installationId", installation_id);
+    }
+    body
+}
+
+/// Delivers a Parse webhook payload and resolves to the `success` of the
+/// response. Webhooks POST it to their url, plugins pass it to their module.
+pub(super) type Transport =
+    Arc<dyn Fn(Document) -> LocalBoxFuture<'static, Result<Bson, Error>> + Send + Sync>;
+
+/// The `success` of a webhook response, `source` names the webhook in errors.
+pub(super) fn webhook_result(mut body: Document, source: &str) -> Result<Bson, Error> {
+    match (body.remove("success"), body.remove("error")) {
+        (_, Some(Bson::String(message))) => Err(Error::ScriptFailed(message)),
+        (_, Some(Bson::Document(error))) => {
+            let message = error.get_str("error").or_else(|_| error.get_str("message"));
+            Err(Error::ScriptFailed(message.unwrap_or("").to_string()))
+        }
+        (_, Some(error)) => Err(Error::ScriptFailed(error.to_string())),
+        (Some(success), None) => Ok(success),
+        (None, None) => Err(Error::WebhookError(format!(
+            "Webhook {} returned neither success nor error",
+            source
+        ))),
+    }
+}
+
+/// POSTs a Parse webhook payload and returns the `success` of the response.
+pub async fn call_webhook(url: &str, key: Option<&str>, body: Document) -> Result<Bson, Error> {
+    let mut request = Client::default()
+        .post(url)
+        .header("Content-Type", "application/json");
+    if let Some(key) = key {
+        request = request.header("X-Parse-Webhook-Key", key);
+    }
+    let mut response = request
+        .send_json(&Bson::Document(body).into_relaxed_extjson())
+        .await
+        .map_err(|e| Error::WebhookError(format!("Webhook {} failed: {}", url, e)))?;
+    let body = match response.json::<Value>().await {
+        Ok(Value::Object(body)) => Document::try_from(body).ok(),
+        _ => None,
+    };
+    let body = body.ok_or_else(|| {
+        Error::WebhookError(format!("Webhook {} returned an invalid response", url))
+    })?;
+    webhook_result(body, url)
+}
+
+fn url_transport(url: String, key: Option<String>) -> Transport {
+    Arc::new(move |body| {
+        let (url, key) = (url.clone(), key.clone());
+        async move { call_webhook(&url, key.as_deref(), body).await }.boxed_local()
+    })
+}
+
+pub(super) fn object_body(name: &str, req: &TriggerRequest) -> Document {
+    let mut body = request_body(&req.user, req.master, &req.context);
+    body.insert("triggerName", name);
+    body.insert("object", req.object.clone());
+    if let Some(original) = &req.original {
+        body.insert("original", original.clone());
+    }
+    body
+}
+
+pub(super) fn find_body(name: &str, req: &FindTriggerRequest) -> Document {
+    let query = &req.query;
+    let mut body = request_body(&req.user, req.master, &req.context);
+    body.insert("triggerName", name);
+    body.insert("isGet", req.is_get);
+    let mut query_body = doc! {
+        "where": query.filter.clone(),
+        "include": query.include.join(","),
+        "keys": query.keys.join(","),
+        "count": query.count,
+    };
+    if let Some(limit) = query.limit {
+        query_body.insert("limit", limit);
+    }
+    if let Some(skip) = query.skip {
+        query_body.insert("skip", skip);
+    }
+    body.insert("query", query_body);
+    if name == "afterFind" {
+        body.insert("objects", req.objects.clone());
+    }
+    body
+}
+
+fn before_save_webhook(transport: Transport) -> BeforeSaveTrigger {
+    Arc::new(move |req| {
+        let transport = transport.clone();
+        async move {
+            let body = object_body("beforeSave", &req);
+            // `{"success": true}` keeps the object as it is
+            match transport(body).await? {
+                Bson::Document(object) => Ok(object),
+                _ => Ok(req.object),
+            }
+        }
+        .boxed_local()
+    })
+}
+
+fn object_webhook(name: &'static str, transport: Transport) -> Trigger {
+    Arc::new(move |req| {
+        let transport = transport.clone();
+        async move {
+            transport(object_body(name, &req)).await?;
+            Ok(())
+        }
+        .boxed_local()
+    })
+}
+
+/// The success of a `beforeFind` webhook can hold `objects` to answer with,
+/// or query fields (`where`, `limit`, `skip`) to change.
+fn before_find_webhook(transport: Transport) -> BeforeFindTrigger {
+    Arc::new(move |req| {
+        let transport = transport.clone();
+        async move {
+            let body = find_body("beforeFind", &req);
+            let success = match transport(body).await? {
+                Bson::Document(success) => success,
+                _ => return Ok(BeforeFind::Query(req.query)),
+            };
+            if let Ok(objects) = success.get_array("objects") {
+                let objects = objects.iter().filter_map(|x| x.as_document().cloned());
+                return Ok(BeforeFind::Objects(objects.collect()));
+            }
+            let mut query: Query = req.query;
+            if let Ok(filter) = success.get_document("where") {
+                query.filter = filter.clone();
+            }
+            if let Some(limit) = success.get("limit") {
+                query.limit = limit.as_i32().map(|x| x as i64).or_else(|| limit.as_i64());
+            }
+            if let Some(skip) = success.get("skip") {
+                query.skip = skip.as_i32().map(|x| x as i64).or_else(|| skip.as_i64());
+            }
+            Ok(BeforeFind::Query(query))
+        }
+        .boxed_local()
+    })
+}
+
+/// The success of an `afterFind` webhook is the list of objects to return.
+fn after_find_webhook(transport: Transport) -> AfterFindTrigger {
+    Arc::new(move |req| {
+        let transport = transport.clone();
+        async move {
+            let body = find_body("afterFind", &req);
+            match transport(body).await? {
+                Bson::Array(objects) => Ok(objects
+                    .into_iter()
+                    .filter_map(|x| x.as_document().cloned())
+                    .collect()),
+                _ => Ok(req.objects),
+            }
+        }
+        .boxed_local()
+    })
+}
+
+/// Adds the trigger `trigger_name` of a class, backed by a webhook
+/// transport. Returns false for unknown trigger names.
+pub(super) fn insert_trigger(
+    triggers: &mut Triggers,
+    class_name: String,
+    trigger_name: &str,
+    transport: Transport,
+) -> bool {
+    match trigger_name {
+        "beforeSave" => {
+            let trigger = before_save_webhook(transport);
+            triggers.before_save.insert(class_name, trigger);
+        }
+        "afterSave" => {
+            let trigger = object_webhook("afterSave", transport);
+            triggers.after_save.insert(class_name, trigger);
+        }
+        "beforeDelete" => {
+            let trigger = object_webhook("beforeDelete", transport);
+            triggers.before_delete.insert(class_name, trigger);
+        }
+        "afterDelete" => {
+            let trigger = object_webhook("afterDelete", transport);
+            triggers.after_delete.insert(class_name, trigger);
+        }
+        "beforeFind" => {
+            let trigger = before_find_webhook(transport);
+            triggers.before_find.insert(class_name, trigger);
+        }
+        "afterFind" => {
+            let trigger = after_find_webhook(transport);
+            triggers.after_find.insert(class_name, trigger);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// The webhooks of `_Hooks`, loaded once and cached until hooks change.
+pub async fn webhooks(ctx: &Context) -> Result<Arc<Webhooks>, Error> {
+    if let Some(webhooks) = ctx.cache.get_webhooks() {
+        return Ok(webhooks);
+    }
+    let key = ctx.config.webhook_key.clone();
+    let mut webhooks = Webhooks::default();
+    for hook in ctx.db.find_documents("_Hooks", doc! {}).await? {
+        let url = hook.get_str("url").unwrap_or("").to_string();
+        if let Ok(name) = hook.get_str("functionName") {
+            webhooks.functions.insert(name.to_string(), url);
+            continue;
+        }
+        let class_name = hook.get_str("className").unwrap_or("").to_string();
+        let trigger_name = hook.get_str("triggerName").unwrap_or("");
+        let transport = url_transport(url, key.clone());
+        if !insert_trigger(&mut webhooks.triggers, class_name, trigger_name, transport) {
+            warn!("Ignoring webhook for unknown trigger {}", trigger_name);
+        }
+    }
+    let webhooks = Arc::new(webhooks);
+    ctx.cache.set_webhooks(webhooks.clone());
+    Ok(webhooks)
+}
+
+/// Calls the webhook backing a Cloud Code function.
+pub async fn run_function_webhook(
+    ctx: &Context,
+    url: &str,
+    name: &str,
+    params: Document,
+) -> Result<Bson, Error> {
+    let mut body = request_body(&ctx.user, ctx.user.is_master, &ctx.cloud_context);
+    body.insert("functionName", name);
+    body.insert("params", params);
+    call_webhook(url, ctx.config.webhook_key.as_deref(), body).await
+}
+
+/// A hook definition as the hooks API returns it.
+fn hook_response(mut hook: Document) -> Document {
+    hook.remove("_id");
+    hook
+}
+
+fn hook_url(body: &Document) -> Result<&str, Error> {
+    match body.get_str("url") {
+        Ok(url) if !url.is_empty() => Ok(url),
+        _ => Err(Error::WebhookError("invalid hook declaration".to_string())),
+    }
+}
+
+fn is_delete(body: &Document) -> bool {
+    body.get_str("__op") == Ok("Delete")
+}
+
+/// Lists hooks, `filter` selects functions or triggers.
+pub async fn find_hooks(ctx: &Context, filter: Document) -> Result<Vec<Document>, Error> {
+    let hooks = ctx.db.find_documents("_Hooks", filter).await?;
+    Ok(hooks.into_iter().map(hook_response).collect())
+}
+
+/// `filter` identifies a single hook, `missing` is the error when it does
+/// not exist.
+pub async fn get_hook(ctx: &Context, filter: Document, missing: String) -> Result<Document, Error> {
+    find_hooks(ctx, filter)
+        .await?
+        .pop()
+        .ok_or(Error::WebhookError(missing))
+}
+
+pub async fn create_function_hook(ctx: &Context, body: &Document) -> Result<Document, Error> {
+    let name = body
+        .get_str("functionName")
+        .map_err(|_| Error::WebhookError("invalid hook declaration".to_string()))?;
+    let url = hook_url(body)?;
+    let filter = doc! {"functionName": name};
+    if !find_hooks(ctx, filter).await?.is_empty() {
+        let message = format!("function name: {} already exists", name);
+        return Err(Error::WebhookError(message));
+    }
+    let hook = doc! {"functionName": name, "url": url};
+    let mut stored = hook.clone();
+    stored.insert("_id", util::new_object_id());
+    ctx.db.insert_document("_Hooks", stored).await?;
+    ctx.cache.clear_webhooks();
+    Ok(hook)
+}
+
+pub async fn create_trigger_hook(ctx: &Context, body: &Document) -> Result<Document, Error> {
+    let (class_name, trigger_name) = match (body.get_str("className"), body.get_str("triggerName"))
+    {
+        (Ok(class_name), Ok(trigger_name)) if TRIGGER_NAMES.contains(&trigger_name) => {
+            (class_name, trigger_name)
+        }
+        _ => return Err(Error::WebhookError("invalid hook declaration".to_string())),
+    };
+    let url = hook_url(body)?;
+    let filter = doc! {"className": class_name, "triggerName": trigger_name};
+    if !find_hooks(ctx, filter).await?.is_empty() {
+        let message = format!("class {} already has trigger {}", class_name, trigger_name);
+        return Err(Error::WebhookError(message));
+    }
+    let hook = doc! {"className": class_name, "triggerName": trigger_name, "url": url};
+    let mut stored = hook.clone();
+    stored.insert("_id", util::new_object_id());
+    ctx.db.insert_document("_Hooks", stored).await?;
+    ctx.cache.clear_webhooks();
+    Ok(hook)
+}
+
+/// Changes the url of a hook, or deletes it for `{"__op": "Delete"}`.
+pub async fn update_hook(
+    ctx: &Context,
+    filter: Document,
+    body: &Document,
+    missing: String,
+) -> Result<Document, Error> {
+    if is_delete(body) {
+        return delete_hook(ctx, filter, missing).await;
+    }
+    let url = hook_url(body)?;
+    let matched = ctx
+        .db
+        .update_documents("_Hooks", filter.clone(), doc! {"$set": {"url": url}})
+        .await?;
+    if matched == 0 {
+        return Err(Error::WebhookError(missing));
+    }
+    ctx.cache.clear_webhooks();
+    get_hook(ctx, filter, missing).await
+}
+
+pub async fn delete_hook(
+    ctx: &Context,
+    filter: Document,
+    missing: String,
+) -> Result<Document, Error> {
+    if ctx.db.delete_documents("_Hooks", filter).await? == 0 {
+        return Err(Error::WebhookError(missing));
+    }
+    ctx.cache.clear_webhooks();
+    Ok(doc! {})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{call_webhook, webhook_result};
+    use crate::error::Error;
+    use actix_web::{rt::System, test, web, App, HttpRequest, HttpResponse};
+    use bson::{doc, Bson};
+    use futures::future::ready;
+    use serde_json::{json, Value};
+
+    /// Stands in for a webhook, `/echo` answers with the key header and the
+    /// body it was sent.
+    fn webhook_server() -> test::TestServer {
+        test::start(|| {
+            App::new()
+                .route(
+                    "/echo",
+                    web::post().to(|req: HttpRequest, body: web::Json<Value>| {
+                        let key = req
+                            .headers()
+                            .get("X-Parse-Webhook-Key")
+                            .and_then(|x| x.to_str().ok())
+                            .map(String::from);
+                        let success = json!({"key": key, "body": body.into_inner()});
+                        ready(HttpResponse::Ok().json(json!({ "success": success })))
+                    }),
+                )
+                .route(
+                    "/invalid",
+                    web::post().to(|| ready(HttpResponse::Ok().body("not json"))),
+                )
+        })
+    }
+
+    #[test]
+    fn returns_success() {
+        let result = webhook_result(doc! {"success": {"answer": 42}}, "hook");
+        assert_eq!(result.unwrap(), Bson::Document(doc! {"answer": 42}));
+    }
+
+    #[test]
+    fn reports_errors_as_script_failures() {
+        let message = |body| match webhook_result(body, "hook") {
+            Err(Error::ScriptFailed(message)) => message,
+            _ => panic!("expected a script failure"),
+        };
+        assert_eq!(message(doc! {"error": "no way"}), "no way");
+        assert_eq!(
+            message(doc! {"error": {"code": 141, "error": "nested"}}),
+            "nested"
+        );
+        assert_eq!(
+            message(doc! {"error": {"message": "from message"}}),
+            "from message"
+        );
+        // An error wins over a success in the same response
+        assert_eq!(message(doc! {"success": true, "error": "both"}), "both");
+    }
+
+    #[test]
+    fn rejects_responses_without_result() {
+        match webhook_result(doc! {"other": 1}, "hook") {
+            Err(Error::WebhookError(message)) => assert!(message.contains("hook")),
+            _ => panic!("expected a webhook error"),
+        }
+    }
+
+    #[test]
+    fn posts_the_body_with_the_webhook_key() {
+        System::new("test").block_on(async {
+            let server = webhook_server();
+            let body = doc! {"triggerName": "beforeSave", "object": {"name": "a"}};
+            let result = call_webhook(&server.url("/echo"), Some("secret"), body.clone()).await;
+            let expected = doc! {"key": "secret", "body": body.clone()};
+            assert_eq!(result.unwrap(), Bson::Document(expected));
+
+            let result = call_webhook(&server.url("/echo"), None, body.clone()).await;
+            let expected = doc! {"key": Bson::Null, "body": body};
+            assert_eq!(result.unwrap(), Bson::Document(expected));
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_responses_and_unreachable_hooks() {
+        System::new("test").block_on(async {
+            let server = webhook_server();
+            match call_webhook(&server.url("/invalid"), None, doc! {}).await {
+                Err(Error::WebhookError(message)) => assert!(message.contains("invalid")),
+                _ => panic!("expected a webhook error"),
+            }
+            match call_webhook("http://127.0.0.1:1/hook", None, doc! {}).await {
+                Err(Error::WebhookError(message)) => assert!(message.contains("failed")),
+                _ => panic!("expected a webhook error"),
+            }
+        });
+    }
+}
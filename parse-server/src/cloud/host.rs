@@ -0,0 +1,218 @@
+use crate::error::Error;
+use crate::operation::{execute, Context};
+use crate::rest::classes::{parse_class_request, parse_object_request};
+use crate::user::User;
+use bson::{Bson, Document};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Instant;
+
+/// The error of a host call made after the deadline of the calling code.
+pub const TIMED_OUT: &str = "Script timed out";
+
+/// A read or write that sandboxed Cloud Code asks the server for, with the
+/// REST method and payload of the matching `/parse/classes` request.
+pub struct HostCall {
+    pub method: &'static str,
+    pub class_name: String,
+    pub object_id: Option<String>,
+    pub payload: Value,
+    /// Runs the call with the master key instead of without a user.
+    pub master: bool,
+}
+
+enum Message {
+    Call(HostCall, std::sync::mpsc::Sender<Result<Value, Error>>),
+    Done(Result<Value, Error>),
+}
+
+/// Lets code running on another thread make host calls.
+#[derive(Clone)]
+pub struct HostCalls {
+    sender: mpsc::UnboundedSender<Message>,
+    deadline: Option<Instant>,
+}
+
+impl HostCalls {
+    /// Host calls that always fail, for code run outside of a request.
+    pub fn unavailable() -> HostCalls {
+        let (sender, _) = mpsc::unbounded();
+        HostCalls {
+            sender,
+            deadline: None,
+        }
+    }
+
+    /// The same host calls, failing once `deadline` has passed instead of
+    /// waiting for the server.
+    pub fn with_deadline(&self, deadline: Instant) -> HostCalls {
+        HostCalls {
+            sender: self.sender.clone(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Sends a host call to the server and blocks until it is answered.
+    pub fn call(&self, call: HostCall) -> Result<Value, Error> {
+        let timed_out = || Error::ScriptFailed(TIMED_OUT.to_string());
+        if self.deadline.is_some_and(|x| Instant::now() >= x) {
+            return Err(timed_out());
+        }
+        let (reply, response) = std::sync::mpsc::channel();
+        let unavailable = || Error::ScriptFailed("Host calls are not available".to_string());
+        self.sender
+            .unbounded_send(Message::Call(call, reply))
+            .map_err(|_| unavailable())?;
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return response.recv().map_err(|_| unavailable())?,
+        };
+        match response.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(timed_out()),
+            Err(RecvTimeoutError::Disconnected) => Err(unavailable()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl HostCalls {
+    /// Host calls answered by `answer` on another thread instead of a server.
+    pub fn answered_by<F>(answer: F) -> HostCalls
+    where
+        F: Fn(HostCall) -> Result<Value, Error> + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded();
+        std::thread::spawn(move || {
+            while let Some(Message::Call(call, reply)) =
+                futures::executor::block_on(receiver.next())
+            {
+                let _ = reply.send(answer(call));
+            }
+        });
+        HostCalls {
+            sender,
+            deadline: None,
+        }
+    }
+}
+
+pub fn to_document(value: Value) -> Result<Document, Error> {
+    match value {
+        Value::Object(map) => {
+            Document::try_from(map).map_err(|e| Error::ScriptFailed(e.to_string()))
+        }
+        _ => Err(Error::ScriptFailed("Expected an object".to_string())),
+    }
+}
+
+pub fn to_json(document: Document) -> Value {
+    Bson::Document(document).into_relaxed_extjson()
+}
+
+/// Runs a host call through `execute`. Finds resolve to their results.
+async fn run_host_call(ctx: &Context, call: HostCall) -> Result<Value, Error> {
+    let mut ctx = ctx.for_class(&call.class_name);
+    ctx.user = User {
+        is_master: call.master,
+        ..User::master()
+    };
+    let payload = to_document(call.payload)?;
+    let request = match call.object_id {
+        Some(object_id) => parse_object_request(call.method, object_id, &payload)?,
+        None => parse_class_request(call.method, &payload)?,
+    };
+    let mut result = execute(request, ctx).await?;
+    match (call.method, result.remove("results")) {
+        ("GET", Some(results)) => Ok(results.into_relaxed_extjson()),
+        _ => Ok(to_json(result)),
+    }
+}
+
+/// Runs `invoke` on its own thread, answering its host calls on behalf of
+/// the context until it returns.
+pub async fn run_on_thread<F>(ctx: &Context, invoke: F) -> Result<Value, Error>
+where
+    F: FnOnce(&HostCalls) -> Result<Value, Error> + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::unbounded();
+    std::thread::spawn(move || {
+        let host = HostCalls {
+            sender,
+            deadline: None,
+        };
+        let result = invoke(&host);
+        let _ = host.sender.unbounded_send(Message::Done(result));
+    });
+
+    while let Some(message) = receiver.next().await {
+        match message {
+            Message::Call(call, reply) => {
+                let _ = reply.send(run_host_call(ctx, call).await);
+            }
+            Message::Done(result) => return result,
+        }
+    }
+    Err(Error::ScriptFailed(
+        "Cloud Code stopped unexpectedly".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn call() -> HostCall {
+        HostCall {
+            method: "GET",
+            class_name: "Post".to_string(),
+            object_id: None,
+            payload: Value::Object(Default::default()),
+            master: false,
+        }
+    }
+
+    #[test]
+    fn answers_calls_before_the_deadline() {
+        let host = HostCalls::answered_by(|call| Ok(Value::from(call.class_name)));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let result = host.with_deadline(deadline).call(call());
+        assert_eq!(result.unwrap(), Value::from("Post"));
+    }
+
+    #[test]
+    fn fails_calls_after_the_deadline() {
+        let host = HostCalls::answered_by(|_| panic!("the call was sent"));
+        match host.with_deadline(Instant::now()).call(call()) {
+            Err(Error::ScriptFailed(message)) => assert_eq!(message, TIMED_OUT),
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn stops_waiting_for_slow_calls_at_the_deadline() {
+        let host = HostCalls::answered_by(|_| {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(Value::Null)
+        });
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        match host.with_deadline(deadline).call(call()) {
+            Err(Error::ScriptFailed(message)) => assert_eq!(message, TIMED_OUT),
+            _ => panic!("expected a timeout"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn fails_without_a_server() {
+        match HostCalls::unavailable().call(call()) {
+            Err(Error::ScriptFailed(message)) => assert!(message.contains("not available")),
+            _ => panic!("expected host calls to be unavailable"),
+        }
+    }
+}
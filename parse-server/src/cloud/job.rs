@@ -0,0 +1,195 @@
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::Context;
+use crate::util;
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::sync::Arc;
+
+/// Sets fields of the `_JobStatus` of a job.
+type StatusUpdate =
+    Arc<dyn Fn(Document) -> LocalBoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// Updates the `message` of a running job's `_JobStatus`.
+#[derive(Clone)]
+pub struct JobMessage {
+    update: StatusUpdate,
+    job_id: String,
+}
+
+impl JobMessage {
+    /// The `_JobStatus` id of the job.
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Reports progress, the message replaces the previous one.
+    pub async fn message(&self, message: &str) -> Result<(), Error> {
+        (self.update)(doc! {"message": message}).await
+    }
+}
+
+/// What a background job is called with.
+#[derive(Clone)]
+pub struct JobRequest {
+    pub params: Document,
+    pub job_name: String,
+    pub message: JobMessage,
+}
+
+/// A job resolves to its final message, or to the error it failed with.
+pub type CloudJob =
+    Arc<dyn Fn(JobRequest) -> LocalBoxFuture<'static, Result<Option<String>, Error>> + Send + Sync>;
+
+fn db_status_update(db: Arc<DbAdapter>, job_id: String) -> StatusUpdate {
+    Arc::new(move |mut set| {
+        let (db, job_id) = (db.clone(), job_id.clone());
+        async move {
+            set.insert("_updated_at", Utc::now());
+            db.update_documents("_JobStatus", doc! {"_id": job_id}, doc! {"$set": set})
+                .await?;
+            Ok(())
+        }
+        .boxed_local()
+    })
+}
+
+/// Runs a job to completion and records how it ended.
+async fn run(job: CloudJob, req: JobRequest) {
+    let (update, job_id) = (req.message.update.clone(), req.message.job_id.clone());
+    let job_name = req.job_name.clone();
+    let mut set = match job(req).await {
+        Ok(message) => {
+            info!("Job {} ({}) succeeded", job_name, job_id);
+            let mut set = doc! {"status": "succeeded"};
+            if let Some(message) = message {
+                set.insert("message", message);
+            }
+            set
+        }
+        Err(e) => {
+            error!("Job {} ({}) failed: {}", job_name, job_id, e);
+            doc! {"status": "failed", "message": e.message()}
+        }
+    };
+    set.insert("finishedAt", Utc::now());
+    if let Err(e) = update(set).await {
+        error!("Could not update job status {}: {}", job_id, e);
+    }
+}
+
+/// Starts the job `name` in the background, `source` is recorded in its
+/// `_JobStatus`. Returns the `_JobStatus` id.
+pub async fn start_job(
+    ctx: &Context,
+    name: &str,
+    params: Document,
+    source: &str,
+) -> Result<String, Error> {
+    let job = ctx
+        .config
+        .cloud
+        .jobs
+        .get(name)
+        .cloned()
+        .ok_or_else(|| Error::ScriptFailed("Invalid job.".to_string()))?;
+
+    let now = Utc::now();
+    let job_id = util::new_object_id();
+    let status = doc! {
+        "_id": &job_id,
+        "jobName": name,
+        "source": source,
+        "status": "running",
+        "params": Bson::Document(params.clone()),
+        "startedAt": now,
+        "_created_at": now,
+        "_updated_at": now,
+        "_rperm": [],
+        "_wperm": [],
+        "_acl": {},
+    };
+    ctx.db.insert_document("_JobStatus", status).await?;
+
+    let req = JobRequest {
+        params,
+        job_name: name.to_string(),
+        message: JobMessage {
+            update: db_status_update(ctx.db.clone(), job_id.clone()),
+            job_id: job_id.clone(),
+        },
+    };
+    debug!("Starting job {} ({})", name, job_id);
+    actix_web::rt::spawn(run(job, req));
+    Ok(job_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::System;
+    use futures::future::ready;
+    use std::sync::Mutex;
+
+    /// Runs `job` and returns the `_JobStatus` updates it made.
+    fn run_job<F, R>(job: F) -> Vec<Document>
+    where
+        F: Fn(JobRequest) -> R + Send + Sync + 'static,
+        R: std::future::Future<Output = Result<Option<String>, Error>> + 'static,
+    {
+        let updates = Arc::new(Mutex::new(vec![]));
+        let recorded = updates.clone();
+        let update: StatusUpdate = Arc::new(move |set| {
+            recorded.lock().unwrap().push(set);
+            ready(Ok(())).boxed_local()
+        });
+        let req = JobRequest {
+            params: doc! {"limit": 10},
+            job_name: "cleanup".to_string(),
+            message: JobMessage {
+                update,
+                job_id: "job1".to_string(),
+            },
+        };
+        let job: CloudJob = Arc::new(move |req| job(req).boxed_local());
+        System::new("test").block_on(run(job, req));
+        let updates = updates.lock().unwrap().clone();
+        updates
+    }
+
+    #[test]
+    fn records_messages_then_success() {
+        let updates = run_job(|req| async move {
+            assert_eq!(req.message.job_id(), "job1");
+            assert_eq!(req.params.get_i32("limit"), Ok(10));
+            req.message.message("halfway").await?;
+            Ok(Some("done".to_string()))
+        });
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0], doc! {"message": "halfway"});
+        assert_eq!(updates[1].get_str("status"), Ok("succeeded"));
+        assert_eq!(updates[1].get_str("message"), Ok("done"));
+        assert!(updates[1].get_datetime("finishedAt").is_ok());
+    }
+
+    #[test]
+    fn keeps_the_last_message_without_a_final_one() {
+        let updates = run_job(|req| async move {
+            req.message.message("started").await?;
+            Ok(None)
+        });
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[1].get_str("status"), Ok("succeeded"));
+        assert!(!updates[1].contains_key("message"));
+    }
+
+    #[test]
+    fn records_failures() {
+        let updates = run_job(|_| async { Err(Error::ScriptFailed("no way".to_string())) });
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].get_str("status"), Ok("failed"));
+        assert_eq!(updates[0].get_str("message"), Ok("no way"));
+        assert!(updates[0].get_datetime("finishedAt").is_ok());
+    }
+}
@@ -0,0 +1,307 @@
+use crate::error::Error;
+use crate::operation::Context;
+use crate::user::User;
+use bson::{Bson, Document};
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+mod hooks;
+#[cfg(any(feature = "scripting", feature = "wasm"))]
+mod host;
+mod job;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod schedule;
+#[cfg(feature = "scripting")]
+mod script;
+mod trigger;
+mod validator;
+
+pub use hooks::{
+    create_function_hook, create_trigger_hook, delete_hook, find_hooks, get_hook, update_hook,
+    Webhooks,
+};
+pub use job::{start_job, CloudJob, JobMessage, JobRequest};
+#[cfg(feature = "wasm")]
+pub use plugin::Plugins;
+pub use schedule::{run_scheduler, validate_schedule};
+#[cfg(feature = "scripting")]
+pub use script::Scripts;
+pub use trigger::ClassTriggers;
+pub use trigger::{
+    AfterFindTrigger, BeforeFind, BeforeFindTrigger, BeforeSaveTrigger, FindTriggerRequest, Query,
+    Trigger, TriggerRequest,
+};
+pub use validator::{FieldRule, Validator};
+
+use trigger::Triggers;
+
+/// What a Cloud Code function is called with.
+#[derive(Clone)]
+pub struct FunctionRequest {
+    pub params: Document,
+    /// The caller, `user.id` is set when a session token was sent.
+    pub user: User,
+    pub master: bool,
+}
+
+pub type CloudFunction =
+    Arc<dyn Fn(FunctionRequest) -> LocalBoxFuture<'static, Result<Bson, Error>> + Send + Sync>;
+
+#[derive(Clone)]
+struct Function {
+    handler: CloudFunction,
+    validator: Validator,
+}
+
+/// Cloud Code registered with the server, functions are called through
+/// `/parse/functions/{name}` and jobs started through `/parse/jobs/{name}`.
+#[derive(Clone, Default)]
+pub struct CloudCode {
+    functions: HashMap<String, Function>,
+    jobs: HashMap<String, CloudJob>,
+    pub(crate) triggers: Triggers,
+    #[cfg(feature = "scripting")]
+    pub(crate) scripts: Option<Arc<Scripts>>,
+    #[cfg(feature = "wasm")]
+    pub(crate) plugins: Option<Arc<Plugins>>,
+}
+
+impl CloudCode {
+    pub fn new() -> Self {
+        CloudCode::default()
+    }
+
+    /// Registers the function `name`, the value it resolves to is the `result`
+    /// of the call.
+    pub fn define<F, R>(self, name: &str, handler: F) -> Self
+    where
+        F: Fn(FunctionRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Bson, Error>> + 'static,
+    {
+        self.define_with(name, Validator::new(), handler)
+    }
+
+    /// Registers the function `name`, calls are rejected unless they pass the
+    /// validator.
+    pub fn define_with<F, R>(mut self, name: &str, validator: Validator, handler: F) -> Self
+    where
+        F: Fn(FunctionRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Bson, Error>> + 'static,
+    {
+        let handler: CloudFunction = Arc::new(move |req| handler(req).boxed_local());
+        let function = Function { handler, validator };
+        self.functions.insert(name.to_string(), function);
+        self
+    }
+
+    /// Adds the functions and triggers of Rhai scripts, Rust ones win when
+    /// both define the same.
+    #[cfg(feature = "scripting")]
+    pub fn scripts(mut self, scripts: Scripts) -> Self {
+        self.scripts = Some(Arc::new(scripts));
+        self
+    }
+
+    /// Adds the functions and triggers of WebAssembly plugins, Rust and
+    /// script ones win when they define the same.
+    #[cfg(feature = "wasm")]
+    pub fn plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = Some(Arc::new(plugins));
+        self
+    }
+
+    /// Registers the background job `name`, progress is reported through
+    /// `req.message` and the outcome recorded in `_JobStatus`.
+    pub fn job<F, R>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(JobRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Option<String>, Error>> + 'static,
+    {
+        let handler: CloudJob = Arc::new(move |req| handler(req).boxed_local());
+        self.jobs.insert(name.to_string(), handler);
+        self
+    }
+
+    /// Names of the registered background jobs.
+    pub fn job_names(&self) -> Vec<String> {
+        self.jobs.keys().cloned().collect()
+    }
+
+    /// Runs before objects of the class are created or updated. The trigger
+    /// resolves to the object to save, errors reject the save.
+    pub fn before_save<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(TriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Document, Error>> + 'static,
+    {
+        let handler: BeforeSaveTrigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .before_save
+            .insert(class_name.to_string(), handler);
+        self
+    }
+
+    /// Runs after objects of the class were saved, without delaying the
+    /// response.
+    pub fn after_save<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(TriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Error>> + 'static,
+    {
+        let handler: Trigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .after_save
+            .insert(class_name.to_string(), handler);
+        self
+    }
+
+    /// Runs before objects of the class are deleted, errors reject the delete.
+    pub fn before_delete<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(TriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Error>> + 'static,
+    {
+        let handler: Trigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .before_delete
+            .insert(class_name.to_string(), handler);
+        self
+    }
+
+    /// Runs after objects of the class were deleted, without delaying the
+    /// response.
+    pub fn after_delete<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(TriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<(), Error>> + 'static,
+    {
+        let handler: Trigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .after_delete
+            .insert(class_name.to_string(), handler);
+        self
+    }
+
+    /// Runs before finds, counts and gets on the class. The trigger resolves
+    /// to the query to run, or to the objects to answer with.
+    pub fn before_find<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(FindTriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<BeforeFind, Error>> + 'static,
+    {
+        let handler: BeforeFindTrigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .before_find
+            .insert(class_name.to_string(), handler);
+        self
+    }
+
+    /// Runs on the objects found on the class, the trigger resolves to the
+    /// objects to return.
+    pub fn after_find<F, R>(mut self, class_name: &str, handler: F) -> Self
+    where
+        F: Fn(FindTriggerRequest) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Vec<Document>, Error>> + 'static,
+    {
+        let handler: AfterFindTrigger = Arc::new(move |req| handler(req).boxed_local());
+        self.triggers
+            .after_find
+            .insert(class_name.to_string(), handler);
+        self
+    }
+}
+
+/// Master key requests can skip triggers with `{"skipTriggers": true}` in
+/// their cloud context, e.g. for migrations.
+pub fn skip_triggers(ctx: &Context) -> bool {
+    ctx.user.is_master && ctx.cloud_context.get_bool("skipTriggers").unwrap_or(false)
+}
+
+/// The triggers of the context class, none when they are skipped.
+pub async fn class_triggers(ctx: &Context) -> Result<ClassTriggers, Error> {
+    if skip_triggers(ctx) {
+        return Ok(ClassTriggers::default());
+    }
+    let triggers = ctx.config.cloud.triggers.class(&ctx.class);
+    #[cfg(feature = "scripting")]
+    let triggers = match &ctx.config.cloud.scripts {
+        Some(scripts) => triggers.or(scripts.class_triggers(ctx)),
+        None => triggers,
+    };
+    #[cfg(feature = "wasm")]
+    let triggers = match &ctx.config.cloud.plugins {
+        Some(plugins) => triggers.or(plugins.class_triggers(ctx)),
+        None => triggers,
+    };
+    let webhooks = hooks::webhooks(ctx).await?;
+    Ok(triggers.or(webhooks.triggers.class(&ctx.class)))
+}
+
+/// Runs a fire-and-forget trigger in the background, errors are only logged.
+pub fn spawn_trigger(name: &'static str, trigger: Trigger, req: TriggerRequest) {
+    actix_web::rt::spawn(async move {
+        let class_name = req.class_name.clone();
+        if let Err(e) = trigger(req).await {
+            error!("{} trigger for {} failed: {}", name, class_name, e);
+        }
+    });
+}
+
+/// Errors without a Parse code of their own reject the request as invalid.
+pub fn validation_error(error: Error) -> Error {
+    match error {
+        Error::Internal(message) | Error::OtherCause(message) => Error::ValidationError(message),
+        e => e,
+    }
+}
+
+/// Runs a Cloud Code function, or the script, plugin or webhook defined for
+/// it. Errors without a Parse code of their own are reported as script
+/// failures.
+pub async fn run_function(ctx: &Context, name: &str, params: Document) -> Result<Bson, Error> {
+    let function = match ctx.config.cloud.functions.get(name) {
+        Some(function) => function,
+        None => {
+            #[cfg(feature = "scripting")]
+            if let Some(scripts) = &ctx.config.cloud.scripts {
+                if scripts.has_function(name) {
+                    return scripts
+                        .run_function(ctx, name, params)
+                        .await
+                        .map_err(|e| match e {
+                            Error::OtherCause(message) => Error::ScriptFailed(message),
+                            e => e,
+                        });
+                }
+            }
+            #[cfg(feature = "wasm")]
+            if let Some(plugins) = &ctx.config.cloud.plugins {
+                if plugins.has_function(name) {
+                    return plugins.run_function(ctx, name, params).await;
+                }
+            }
+            return match hooks::webhooks(ctx).await?.functions.get(name) {
+                Some(url) => hooks::run_function_webhook(ctx, url, name, params).await,
+                None => Err(Error::ScriptFailed(format!(
+                    "Invalid function: \"{}\"",
+                    name
+                ))),
+            };
+        }
+    };
+
+    let mut req = FunctionRequest {
+        params,
+        user: ctx.user.clone(),
+        master: ctx.user.is_master,
+    };
+    function.validator.validate(&mut req)?;
+    debug!("Running cloud function {}", name);
+    (function.handler)(req).await.map_err(|e| match e {
+        Error::Internal(message) | Error::OtherCause(message) => Error::ScriptFailed(message),
+        e => e,
+    })
+}
@@ -0,0 +1,648 @@
+use super::hooks::{insert_trigger, request_body, webhook_result, Transport};
+use super::host::{run_on_thread, to_document, to_json, HostCall, HostCalls};
+use super::trigger::Triggers;
+use super::ClassTriggers;
+use crate::error::Error;
+use crate::operation::Context;
+use bson::{Bson, Document};
+use futures::future::FutureExt;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use wasmtime::{
+    AsContext, AsContextMut, Caller, Engine, Extern, Linker, Memory, Module, Store, Trap, TypedFunc,
+};
+
+/// Fuel of a single invocation, about one unit per instruction.
+const DEFAULT_FUEL: u64 = 1_000_000_000;
+/// How often plugin files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Plugin {
+    path: PathBuf,
+    modified: SystemTime,
+    module: Module,
+    functions: Vec<String>,
+    /// `(className, triggerName)` of the triggers the plugin handles.
+    triggers: Vec<(String, String)>,
+}
+
+/// Cloud Code compiled to WebAssembly, loaded from the `*.wasm` files of a
+/// directory and reloaded when they change.
+///
+/// Values cross the boundary as UTF-8 JSON in the module memory, a pointer
+/// and a length packed into an `i64` as `ptr << 32 | len`. A module exports:
+///
+/// - `memory`, and `alloc(len: i32) -> i32` for the host to write into it.
+/// - `registrations() -> i64`, returning
+///   `{"functions": ["name"], "triggers": [{"className": "Post", "triggerName": "beforeSave"}]}`.
+/// - `handle(ptr: i32, len: i32) -> i64`, called with the payload a webhook
+///   would receive and returning a webhook response, `{"success": ...}` or
+///   `{"error": "message"}`.
+///
+/// Modules can import `parse.host_call(ptr: i32, len: i32) -> i64` to run
+/// `{"method": "find", "className": "Post", "query": {"where": {...}}}`,
+/// or `get`, `save` and `delete` with `objectId` and `object`, through the
+/// same permission checks as REST requests. Calls run without a user unless
+/// they set `"useMasterKey": true`, and answer like `handle` does.
+///
+/// Every invocation gets a fresh instance on its own thread, and traps once
+/// its fuel runs out.
+pub struct Plugins {
+    dir: PathBuf,
+    engine: Engine,
+    fuel: u64,
+    plugins: RwLock<Vec<Arc<Plugin>>>,
+}
+
+fn plugin_error(error: wasmtime::Error) -> Error {
+    match error.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => Error::ScriptFailed("Plugin ran out of fuel".to_string()),
+        _ => Error::ScriptFailed(format!("Plugin failed: {:#}", error)),
+    }
+}
+
+fn pack(ptr: i32, len: usize) -> i64 {
+    ((ptr as u32 as i64) << 32) | len as i64
+}
+
+fn read_json(
+    store: impl AsContext,
+    memory: Memory,
+    ptr: usize,
+    len: usize,
+) -> wasmtime::Result<Value> {
+    let data = memory
+        .data(&store)
+        .get(ptr..ptr + len)
+        .ok_or_else(|| wasmtime::Error::msg("pointer out of bounds"))?;
+    Ok(serde_json::from_slice(data)?)
+}
+
+fn read_packed(store: impl AsContext, memory: Memory, packed: i64) -> wasmtime::Result<Value> {
+    let packed = packed as u64;
+    read_json(
+        store,
+        memory,
+        (packed >> 32) as usize,
+        (packed & 0xffff_ffff) as usize,
+    )
+}
+
+fn write_json(
+    mut store: impl AsContextMut,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    value: &Value,
+) -> wasmtime::Result<(i32, usize)> {
+    let bytes = serde_json::to_vec(value)?;
+    let ptr = alloc.call(&mut store, bytes.len() as i32)?;
+    memory.write(&mut store, ptr as usize, &bytes)?;
+    Ok((ptr, bytes.len()))
+}
+
+fn caller_exports(
+    caller: &mut Caller<'_, HostCalls>,
+) -> wasmtime::Result<(Memory, TypedFunc<i32, i32>)> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory);
+    let alloc = caller.get_export("alloc").and_then(Extern::into_func);
+    match (memory, alloc) {
+        (Some(memory), Some(alloc)) => Ok((memory, alloc.typed(&caller)?)),
+        _ => Err(wasmtime::Error::msg("memory and alloc have to be exported")),
+    }
+}
+
+/// The host call a module asks for as JSON.
+fn parse_host_call(request: Value) -> Result<HostCall, Error> {
+    let request = to_document(request)?;
+    let class_name = request
+        .get_str("className")
+        .map_err(|_| Error::BadFormat("className is required".to_string()))?;
+    let object_id = request.get_str("objectId").ok().map(String::from);
+    let document = |key| request.get_document(key).cloned().unwrap_or_default();
+    let (method, payload) = match (request.get_str("method").unwrap_or(""), &object_id) {
+        ("find", None) => ("GET", document("query")),
+        ("get", Some(_)) => ("GET", Document::new()),
+        ("save", None) => ("POST", document("object")),
+        ("save", Some(_)) => ("PUT", document("object")),
+        ("delete", Some(_)) => ("DELETE", Document::new()),
+        (method, _) => {
+            let message = format!("Invalid host call {} on {}", method, class_name);
+            return Err(Error::BadFormat(message));
+        }
+    };
+    Ok(HostCall {
+        method,
+        class_name: class_name.to_string(),
+        object_id,
+        payload: to_json(payload),
+        master: request.get_bool("useMasterKey").unwrap_or(false),
+    })
+}
+
+fn host_call(mut caller: Caller<'_, HostCalls>, ptr: i32, len: i32) -> wasmtime::Result<i64> {
+    let (memory, alloc) = caller_exports(&mut caller)?;
+    let request = read_json(&caller, memory, ptr as usize, len as usize)?;
+    let response = match parse_host_call(request).and_then(|call| caller.data().call(call)) {
+        Ok(success) => json!({ "success": success }),
+        Err(e) => json!({ "error": e.message() }),
+    };
+    let (ptr, len) = write_json(&mut caller, memory, alloc, &response)?;
+    Ok(pack(ptr, len))
+}
+
+/// Calls the export `name` of a fresh instance, with `request` for `handle`.
+fn invoke(
+    engine: &Engine,
+    module: &Module,
+    fuel: u64,
+    host: &HostCalls,
+    name: &str,
+    request: Option<Value>,
+) -> wasmtime::Result<Value> {
+    let mut store = Store::new(engine, host.clone());
+    store.set_fuel(fuel)?;
+    let mut linker = Linker::new(engine);
+    linker.func_wrap("parse", "host_call", host_call)?;
+    let instance = linker.instantiate(&mut store, module)?;
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("memory has to be exported"))?;
+
+    let packed = match request {
+        Some(request) => {
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let (ptr, len) = write_json(&mut store, memory, alloc, &request)?;
+            instance
+                .get_typed_func::<(i32, i32), i64>(&mut store, name)?
+                .call(&mut store, (ptr, len as i32))?
+        }
+        None => instance
+            .get_typed_func::<(), i64>(&mut store, name)?
+            .call(&mut store, ())?,
+    };
+    read_packed(&store, memory, packed)
+}
+
+/// The `*.wasm` files of a directory, with their modification time.
+fn plugin_files(dir: &Path) -> Result<Vec<(PathBuf, SystemTime)>, Error> {
+    let error =
+        |e: std::io::Error| Error::ScriptFailed(format!("Could not read {}: {}", dir.display(), e));
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).map_err(error)? {
+        let path = entry.map_err(error)?.path();
+        if path.extension().is_some_and(|x| x == "wasm") {
+            let modified = std::fs::metadata(&path)
+                .and_then(|x| x.modified())
+                .map_err(error)?;
+            files.push((path, modified));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+impl Plugins {
+    /// Loads the `*.wasm` files of `dir` in name order.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Plugins, Error> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(plugin_error)?;
+        let plugins = Plugins {
+            dir: dir.as_ref().to_path_buf(),
+            engine,
+            fuel: DEFAULT_FUEL,
+            plugins: RwLock::new(vec![]),
+        };
+        let mut loaded = vec![];
+        for (path, modified) in plugin_files(&plugins.dir)? {
+            loaded.push(Arc::new(plugins.load_plugin(path, modified)?));
+        }
+        *plugins.plugins.write().unwrap() = loaded;
+        Ok(plugins)
+    }
+
+    /// Fuel a single invocation may burn, one billion by default.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    fn load_plugin(&self, path: PathBuf, modified: SystemTime) -> Result<Plugin, Error> {
+        let error = |e| match plugin_error(e) {
+            Error::ScriptFailed(message) => {
+                Error::ScriptFailed(format!("Could not load {}: {}", path.display(), message))
+            }
+            e => e,
+        };
+        let bytes = std::fs::read(&path).map_err(|e| error(e.into()))?;
+        let module = Module::from_binary(&self.engine, &bytes).map_err(error)?;
+        let host = HostCalls::unavailable();
+        let registrations = invoke(
+            &self.engine,
+            &module,
+            self.fuel,
+            &host,
+            "registrations",
+            None,
+        )
+        .map_err(error)
+        .and_then(to_document)?;
+
+        let functions = match registrations.get_array("functions") {
+            Ok(functions) => functions
+                .iter()
+                .filter_map(|x| Some(x.as_str()?.to_string()))
+                .collect(),
+            Err(_) => vec![],
+        };
+        let triggers = match registrations.get_array("triggers") {
+            Ok(triggers) => triggers
+                .iter()
+                .filter_map(|x| {
+                    let trigger = x.as_document()?;
+                    let class_name = trigger.get_str("className").ok()?;
+                    let trigger_name = trigger.get_str("triggerName").ok()?;
+                    Some((class_name.to_string(), trigger_name.to_string()))
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+        info!("Loaded plugin {}", path.display());
+        Ok(Plugin {
+            path,
+            modified,
+            module,
+            functions,
+            triggers,
+        })
+    }
+
+    /// Reloads changed and new plugins, and drops deleted ones. A plugin
+    /// that fails to load keeps its previous version.
+    fn reload(&self) -> Result<(), Error> {
+        let current = self.plugins.read().unwrap().clone();
+        let mut plugins = vec![];
+        for (path, modified) in plugin_files(&self.dir)? {
+            let previous = current.iter().find(|x| x.path == path);
+            match previous {
+                Some(plugin) if plugin.modified == modified => plugins.push(plugin.clone()),
+                _ => match self.load_plugin(path, modified) {
+                    Ok(plugin) => plugins.push(Arc::new(plugin)),
+                    Err(e) => {
+                        error!("{}", e);
+                        plugins.extend(previous.cloned());
+                    }
+                },
+            }
+        }
+        for plugin in &current {
+            if !plugins.iter().any(|x| x.path == plugin.path) {
+                info!("Unloaded plugin {}", plugin.path.display());
+            }
+        }
+        *self.plugins.write().unwrap() = plugins;
+        Ok(())
+    }
+
+    /// Reloads plugins as their files change, on a thread of its own.
+    pub fn watch(self: Arc<Self>) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            if let Err(e) = self.reload() {
+                error!("{}", e);
+            }
+        });
+    }
+
+    /// Sends webhook payloads to `handle` of the plugin, with host calls made
+    /// on behalf of the context.
+    fn transport(&self, plugin: Arc<Plugin>, ctx: Arc<Context>) -> Transport {
+        let (engine, fuel) = (self.engine.clone(), self.fuel);
+        Arc::new(move |body| {
+            let (engine, plugin, ctx) = (engine.clone(), plugin.clone(), ctx.clone());
+            async move {
+                let source = plugin.path.display().to_string();
+                let request = to_json(body);
+                let response = run_on_thread(&ctx, move |host| {
+                    invoke(&engine, &plugin.module, fuel, host, "handle", Some(request))
+                        .map_err(plugin_error)
+                })
+                .await?;
+                webhook_result(to_document(response)?, &source)
+            }
+            .boxed_local()
+        })
+    }
+
+    pub fn has_function(&self, name: &str) -> bool {
+        let plugins = self.plugins.read().unwrap();
+        plugins
+            .iter()
+            .any(|x| x.functions.iter().any(|x| x == name))
+    }
+
+    /// Runs the function `name` of the first plugin defining it.
+    pub async fn run_function(
+        &self,
+        ctx: &Context,
+        name: &str,
+        params: Document,
+    ) -> Result<Bson, Error> {
+        let plugin = self
+            .plugins
+            .read()
+            .unwrap()
+            .iter()
+            .find(|x| x.functions.iter().any(|x| x == name))
+            .cloned()
+            .ok_or_else(|| Error::ScriptFailed(format!("Invalid function: \"{}\"", name)))?;
+        let mut body = request_body(&ctx.user, ctx.user.is_master, &ctx.cloud_context);
+        body.insert("functionName", name);
+        body.insert("params", params);
+        let transport = self.transport(plugin, Arc::new(ctx.for_class(&ctx.class)));
+        transport(body).await
+    }
+
+    /// The plugin triggers of the context class, the first plugin handling a
+    /// trigger wins.
+    pub fn class_triggers(&self, ctx: &Context) -> ClassTriggers {
+        let class = ctx.class.as_str();
+        let ctx = Arc::new(ctx.for_class(class));
+        let mut triggers = Triggers::default();
+        for plugin in self.plugins.read().unwrap().iter().rev() {
+            for (class_name, trigger_name) in &plugin.triggers {
+                if class_name == class {
+                    let transport = self.transport(plugin.clone(), ctx.clone());
+                    insert_trigger(&mut triggers, class_name.clone(), trigger_name, transport);
+                }
+            }
+        }
+        triggers.class(class)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::AppCache;
+    use crate::config::Config;
+    use crate::database::DbAdapter;
+    use crate::user::User;
+    use crate::util;
+    use actix_web::rt::System;
+    use bson::doc;
+    use std::fs::{self, File};
+    use std::sync::Mutex;
+
+    /// A module registering `registrations`, whose `handle` answers with
+    /// `response` and `call_host` makes the host call `call`.
+    fn module(registrations: &str, response: &str, call: &str) -> Vec<u8> {
+        let data = |offset: usize, value: &str| {
+            format!(
+                "(data (i32.const {}) \"{}\")",
+                offset,
+                value.replace('"', "\\\"")
+            )
+        };
+        let packed = |offset: usize, value: &str| {
+            format!(
+                "(i64.or (i64.shl (i64.const {}) (i64.const 32)) (i64.const {}))",
+                offset,
+                value.len()
+            )
+        };
+        let source = format!(
+            r#"(module
+                (import "parse" "host_call" (func $host_call (param i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 4096))
+                {}
+                {}
+                {}
+                (func (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "registrations") (result i64) {})
+                (func (export "handle") (param i32 i32) (result i64) {})
+                (func (export "call_host") (param i32 i32) (result i64)
+                    (call $host_call (i32.const 2048) (i32.const {})))
+                (func (export "spin") (param i32 i32) (result i64)
+                    (loop $forever (br $forever))
+                    (i64.const 0)))"#,
+            data(16, registrations),
+            data(1024, response),
+            data(2048, call),
+            packed(16, registrations),
+            packed(1024, response),
+            call.len(),
+        );
+        wat::parse_str(&source).unwrap()
+    }
+
+    fn hello_module() -> Vec<u8> {
+        module(
+            r#"{"functions": ["hello"], "triggers": [{"className": "Post", "triggerName": "beforeSave"}]}"#,
+            r#"{"success": "Hello from wasm"}"#,
+            r#"{"method": "get", "className": "Post", "objectId": "abc", "useMasterKey": true}"#,
+        )
+    }
+
+    fn plugin_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("plugins-{}", util::new_object_id()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a plugin file with a modification time `seconds` from now, so
+    /// changes are seen whatever the precision of the file system.
+    fn write_plugin(path: &Path, bytes: &[u8], seconds: u64) {
+        fs::write(path, bytes).unwrap();
+        let modified = SystemTime::now() + Duration::from_secs(seconds);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    /// A context for `Post`, the database is never reached.
+    async fn context() -> Context {
+        Context {
+            class: "Post".to_string(),
+            user: User::master(),
+            db: Arc::new(DbAdapter::connect("mongodb://localhost/test").await),
+            cache: Arc::new(AppCache::new()),
+            config: Arc::new(Config::default()),
+            cloud_context: doc! {},
+        }
+    }
+
+    #[test]
+    fn packs_pointer_and_length() {
+        assert_eq!(pack(16, 5), (16 << 32) | 5);
+        // Pointers past 2 GiB are negative as `i32`
+        let packed = pack(i32::MIN, 7) as u64;
+        assert_eq!((packed >> 32, packed & 0xffff_ffff), (0x8000_0000, 7));
+
+        let engine = Engine::default();
+        let mut store = Store::new(&engine, ());
+        let memory = Memory::new(&mut store, wasmtime::MemoryType::new(1, None)).unwrap();
+        memory.write(&mut store, 100, br#"{"a": 1}"#).unwrap();
+        let value = read_packed(&store, memory, pack(100, 8)).unwrap();
+        assert_eq!(value, json!({"a": 1}));
+        assert!(read_packed(&store, memory, pack(65_530, 8)).is_err());
+    }
+
+    #[test]
+    fn parses_host_calls() {
+        let call = |request: Value| {
+            parse_host_call(request)
+                .map(|x| (x.method, x.class_name, x.object_id, x.payload, x.master))
+        };
+        let find = json!({"method": "find", "className": "Post", "query": {"limit": 1}});
+        let (method, class_name, object_id, payload, master) = call(find).unwrap();
+        assert_eq!((method, class_name.as_str()), ("GET", "Post"));
+        assert_eq!(
+            (object_id, payload, master),
+            (None, json!({"limit": 1}), false)
+        );
+
+        let save = json!({"method": "save", "className": "Post", "object": {"a": 1}});
+        assert_eq!(call(save).unwrap().0, "POST");
+        let update = json!({
+            "method": "save",
+            "className": "Post",
+            "objectId": "abc",
+            "object": {"a": 1},
+            "useMasterKey": true,
+        });
+        let (method, _, object_id, _, master) = call(update).unwrap();
+        assert_eq!(
+            (method, object_id, master),
+            ("PUT", Some("abc".to_string()), true)
+        );
+        let get = json!({"method": "get", "className": "Post", "objectId": "abc"});
+        assert_eq!(call(get).unwrap().0, "GET");
+        let delete = json!({"method": "delete", "className": "Post", "objectId": "abc"});
+        assert_eq!(call(delete).unwrap().0, "DELETE");
+
+        let invalid = vec![
+            json!({"method": "find"}),
+            json!({"method": "get", "className": "Post"}),
+            json!({"method": "delete", "className": "Post"}),
+            json!({"method": "drop", "className": "Post"}),
+            json!([]),
+        ];
+        for request in invalid {
+            assert!(call(request.clone()).is_err(), "{} was accepted", request);
+        }
+    }
+
+    #[test]
+    fn registers_functions_and_triggers() {
+        let dir = plugin_dir();
+        write_plugin(&dir.join("hello.wasm"), &hello_module(), 0);
+        let plugins = Plugins::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(plugins.has_function("hello"));
+        assert!(!plugins.has_function("bye"));
+
+        System::new("test").block_on(async move {
+            let ctx = context().await;
+            let triggers = plugins.class_triggers(&ctx);
+            assert!(triggers.before_save.is_some());
+            assert!(triggers.after_save.is_none());
+            let result = plugins.run_function(&ctx, "hello", doc! {}).await;
+            assert_eq!(result.unwrap(), Bson::from("Hello from wasm"));
+            match plugins.run_function(&ctx, "bye", doc! {}).await {
+                Err(Error::ScriptFailed(message)) => assert!(message.contains("bye")),
+                _ => panic!("an unknown function was run"),
+            }
+        });
+    }
+
+    #[test]
+    fn answers_host_calls() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let host = HostCalls::answered_by(move |call| {
+            let summary = (call.method, call.class_name, call.object_id, call.master);
+            recorded.lock().unwrap().push(summary);
+            Ok(json!({"objectId": "abc", "title": "Hello"}))
+        });
+        let engine = Engine::new(wasmtime::Config::new().consume_fuel(true)).unwrap();
+        let module = Module::new(&engine, hello_module()).unwrap();
+        let response = invoke(
+            &engine,
+            &module,
+            DEFAULT_FUEL,
+            &host,
+            "call_host",
+            Some(json!({})),
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            json!({"success": {"objectId": "abc", "title": "Hello"}})
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("GET", "Post".to_string(), Some("abc".to_string()), true)]
+        );
+
+        let response = invoke(
+            &engine,
+            &module,
+            DEFAULT_FUEL,
+            &HostCalls::unavailable(),
+            "call_host",
+            Some(json!({})),
+        )
+        .unwrap();
+        assert_eq!(response["error"], "Host calls are not available");
+    }
+
+    #[test]
+    fn stops_modules_out_of_fuel() {
+        let engine = Engine::new(wasmtime::Config::new().consume_fuel(true)).unwrap();
+        let module = Module::new(&engine, hello_module()).unwrap();
+        let host = HostCalls::unavailable();
+        let result = invoke(&engine, &module, 10_000, &host, "spin", Some(json!({})));
+        match result.map_err(plugin_error) {
+            Err(Error::ScriptFailed(message)) => assert!(message.contains("fuel")),
+            _ => panic!("the module kept running"),
+        }
+    }
+
+    #[test]
+    fn reloads_changed_plugins() {
+        let dir = plugin_dir();
+        let path = dir.join("a.wasm");
+        write_plugin(&path, &hello_module(), 0);
+        let plugins = Plugins::load(&dir).unwrap();
+        assert!(plugins.has_function("hello"));
+
+        let bye = module(r#"{"functions": ["bye"]}"#, r#"{"success": null}"#, "{}");
+        write_plugin(&path, &bye, 10);
+        plugins.reload().unwrap();
+        assert!(plugins.has_function("bye"));
+        assert!(!plugins.has_function("hello"));
+
+        // A broken plugin keeps its previous version
+        write_plugin(&path, b"not wasm", 20);
+        plugins.reload().unwrap();
+        assert!(plugins.has_function("bye"));
+
+        write_plugin(&dir.join("b.wasm"), &hello_module(), 0);
+        fs::remove_file(&path).unwrap();
+        plugins.reload().unwrap();
+        assert!(plugins.has_function("hello"));
+        assert!(!plugins.has_function("bye"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
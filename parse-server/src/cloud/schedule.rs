@@ -0,0 +1,275 @@
+use super::job::start_job;
+use crate::error::Error;
+use crate::operation::Context;
+use crate::rest::parse_payload;
+use crate::util;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc};
+
+/// How often the scheduler looks for scheduled jobs that are due.
+const SCHEDULER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// When a `_JobSchedule` runs its job.
+struct Schedule {
+    start_after: DateTime<Utc>,
+    time_of_day: Option<NaiveTime>,
+    /// Days the job runs on, 0 is Sunday. Every day when empty.
+    days_of_week: Vec<u32>,
+    repeat_minutes: Option<i64>,
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        Bson::Double(value) => Some(*value as i64),
+        Bson::String(value) => value.parse().ok(),
+        _ => None,
+    }
+}
+
+/// A UTC time of day, `08:30`, `08:30:00` or `08:30:00.000Z` as the
+/// dashboard sends it.
+fn parse_time_of_day(time: &str) -> Option<NaiveTime> {
+    let time = time.strip_suffix('Z').unwrap_or(time);
+    NaiveTime::parse_from_str(time, "%H:%M:%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()
+}
+
+fn parse_schedule(schedule: &Document) -> Result<Schedule, Error> {
+    let invalid = |key: &str| Error::BadFormat(format!("{} is invalid", key));
+    let start_after = match schedule.get_str("startAfter") {
+        Ok(iso) => util::parse_iso(iso).ok_or_else(|| invalid("startAfter"))?,
+        Err(_) => Utc.timestamp(0, 0),
+    };
+    let time_of_day = match schedule.get_str("timeOfDay") {
+        Ok(time) => Some(parse_time_of_day(time).ok_or_else(|| invalid("timeOfDay"))?),
+        Err(_) => None,
+    };
+    let days_of_week = match schedule.get_array("daysOfWeek") {
+        Ok(days) => days
+            .iter()
+            .map(|day| as_i64(day).filter(|x| (0..7).contains(x)).map(|x| x as u32))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid("daysOfWeek"))?,
+        Err(_) => vec![],
+    };
+    let repeat_minutes = schedule
+        .get("repeatMinutes")
+        .and_then(as_i64)
+        .filter(|x| *x > 0);
+    Ok(Schedule {
+        start_after,
+        time_of_day,
+        days_of_week,
+        repeat_minutes,
+    })
+}
+
+/// The first run of a schedule after its last one. Schedules with only
+/// `startAfter` run once, others run on each of their days at `timeOfDay`
+/// (midnight by default) and every `repeatMinutes` after it until midnight.
+fn next_run(schedule: &Schedule, last_run: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    if schedule.time_of_day.is_none()
+        && schedule.days_of_week.is_empty()
+        && schedule.repeat_minutes.is_none()
+    {
+        return match last_run {
+            Some(_) => None,
+            None => Some(schedule.start_after),
+        };
+    }
+
+    let since = match last_run {
+        Some(last_run) => (last_run + Duration::seconds(1)).max(schedule.start_after),
+        None => schedule.start_after,
+    };
+    let time_of_day = schedule
+        .time_of_day
+        .unwrap_or_else(|| NaiveTime::from_hms(0, 0, 0));
+    // Every day of the week comes up within the next eight days
+    for days in 0..8 {
+        let date = since.naive_utc().date() + Duration::days(days);
+        let weekday = date.weekday().num_days_from_sunday();
+        if !schedule.days_of_week.is_empty() && !schedule.days_of_week.contains(&weekday) {
+            continue;
+        }
+        let first = DateTime::from_utc(date.and_time(time_of_day), Utc);
+        let run = match schedule.repeat_minutes {
+            Some(minutes) if first < since => {
+                let period = minutes * 60;
+                let periods = ((since - first).num_seconds() + period - 1) / period;
+                first + Duration::seconds(periods * period)
+            }
+            _ => first,
+        };
+        if run >= since && run.naive_utc().date() == date {
+            return Some(run);
+        }
+    }
+    None
+}
+
+/// Checks that a `_JobSchedule` runs a registered job and can be parsed.
+pub fn validate_schedule(ctx: &Context, schedule: &Document) -> Result<(), Error> {
+    if let Ok(job_name) = schedule.get_str("jobName") {
+        if !ctx.config.cloud.jobs.contains_key(job_name) {
+            return Err(Error::ScriptFailed(
+                "Cannot Schedule a job that is not deployed".to_string(),
+            ));
+        }
+    }
+    parse_schedule(schedule).map(|_| ())
+}
+
+/// Starts the job of a schedule when it is due. The run is claimed by
+/// moving `lastRun` from the value read, so only one of several servers
+/// starts it.
+async fn run_schedule(ctx: &Context, schedule: &Document) -> Result<(), Error> {
+    let id = schedule.get_str("_id").unwrap_or("");
+    let last_run = schedule.get("lastRun").cloned().unwrap_or(Bson::Null);
+    let next_run = next_run(
+        &parse_schedule(schedule)?,
+        as_i64(&last_run).map(|seconds| Utc.timestamp(seconds, 0)),
+    );
+    let now = Utc::now();
+    match next_run {
+        Some(next_run) if next_run <= now => {}
+        _ => return Ok(()),
+    }
+
+    let claimed = ctx
+        .db
+        .update_documents(
+            "_JobSchedule",
+            doc! {"_id": id, "lastRun": last_run},
+            doc! {"$set": {"lastRun": now.timestamp(), "_updated_at": now}},
+        )
+        .await?;
+    if claimed != 1 {
+        return Ok(());
+    }
+
+    let job_name = schedule
+        .get_str("jobName")
+        .map_err(|_| Error::BadFormat("jobName is required".to_string()))?;
+    let params = match schedule.get("params") {
+        Some(Bson::String(params)) if !params.is_empty() => parse_payload(params)?,
+        Some(Bson::Document(params)) => params.clone(),
+        _ => Document::new(),
+    };
+    start_job(ctx, job_name, params, "schedule").await?;
+    Ok(())
+}
+
+async fn run_schedules(ctx: &Context) -> Result<(), Error> {
+    for schedule in ctx.db.find_documents("_JobSchedule", doc! {}).await? {
+        if let Err(e) = run_schedule(ctx, &schedule).await {
+            let id = schedule.get_str("_id").unwrap_or("");
+            error!("Could not run job schedule {}: {}", id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Runs forever, starting the jobs of `_JobSchedule` rows as they come due.
+pub async fn run_scheduler(ctx: Context) {
+    let mut interval = actix_web::rt::time::interval(SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = run_schedules(&ctx).await {
+            error!("Could not load job schedules: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_run, parse_schedule, parse_time_of_day};
+    use bson::doc;
+    use chrono::{NaiveTime, TimeZone, Utc};
+
+    #[test]
+    fn start_after_only_runs_once() {
+        let schedule = parse_schedule(&doc! {"startAfter": "2020-01-01T10:00:00.000Z"}).unwrap();
+        let start = Utc.ymd(2020, 1, 1).and_hms(10, 0, 0);
+        assert_eq!(next_run(&schedule, None), Some(start));
+        assert_eq!(next_run(&schedule, Some(start)), None);
+    }
+
+    #[test]
+    fn runs_daily_at_time_of_day() {
+        let schedule = parse_schedule(&doc! {
+            "startAfter": "2020-01-01T10:00:00.000Z",
+            "timeOfDay": "08:30",
+        })
+        .unwrap();
+        // 08:30 already passed on the start day
+        assert_eq!(
+            next_run(&schedule, None),
+            Some(Utc.ymd(2020, 1, 2).and_hms(8, 30, 0))
+        );
+        let last_run = Utc.ymd(2020, 1, 2).and_hms(8, 30, 0);
+        assert_eq!(
+            next_run(&schedule, Some(last_run)),
+            Some(Utc.ymd(2020, 1, 3).and_hms(8, 30, 0))
+        );
+    }
+
+    #[test]
+    fn runs_on_days_of_week_only() {
+        // 2020-01-01 is a Wednesday, 1 is Monday
+        let schedule = parse_schedule(&doc! {
+            "startAfter": "2020-01-01T00:00:00.000Z",
+            "daysOfWeek": [1],
+        })
+        .unwrap();
+        assert_eq!(
+            next_run(&schedule, None),
+            Some(Utc.ymd(2020, 1, 6).and_hms(0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn repeats_until_midnight() {
+        let schedule = parse_schedule(&doc! {
+            "startAfter": "2020-01-01T00:00:00.000Z",
+            "timeOfDay": "23:00",
+            "repeatMinutes": 20,
+        })
+        .unwrap();
+        let last_run = Utc.ymd(2020, 1, 1).and_hms(23, 0, 0);
+        assert_eq!(
+            next_run(&schedule, Some(last_run)),
+            Some(Utc.ymd(2020, 1, 1).and_hms(23, 20, 0))
+        );
+        let last_run = Utc.ymd(2020, 1, 1).and_hms(23, 40, 0);
+        assert_eq!(
+            next_run(&schedule, Some(last_run)),
+            Some(Utc.ymd(2020, 1, 2).and_hms(23, 0, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!(parse_schedule(&doc! {"timeOfDay": "25:00"}).is_err());
+        assert!(parse_schedule(&doc! {"daysOfWeek": [7]}).is_err());
+        assert!(parse_schedule(&doc! {"startAfter": "tomorrow"}).is_err());
+    }
+
+    #[test]
+    fn parses_dashboard_times_of_day() {
+        let time = NaiveTime::from_hms(8, 30, 0);
+        assert_eq!(parse_time_of_day("08:30"), Some(time));
+        assert_eq!(parse_time_of_day("08:30:00"), Some(time));
+        assert_eq!(parse_time_of_day("08:30:00.000Z"), Some(time));
+        assert_eq!(parse_time_of_day("08:30:00Z"), Some(time));
+        assert_eq!(
+            parse_time_of_day("23:59:30.500"),
+            Some(NaiveTime::from_hms_milli(23, 59, 30, 500))
+        );
+        assert_eq!(parse_time_of_day("24:00"), None);
+        assert_eq!(parse_time_of_day("08:30:00.000+02:00"), None);
+    }
+}
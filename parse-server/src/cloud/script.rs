@@ -0,0 +1,591 @@
+use super::hooks::{find_body, object_body, request_body};
+use super::host::{run_on_thread, to_document, to_json, HostCall, HostCalls, TIMED_OUT};
+use super::{AfterFindTrigger, BeforeSaveTrigger, ClassTriggers, Trigger};
+use crate::error::Error;
+use crate::operation::Context;
+use bson::{Bson, Document};
+use futures::future::FutureExt;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, Map, Position, AST};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_OPERATIONS: u64 = 1_000_000;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A script function, `ast` is the index of the file defining it.
+#[derive(Clone)]
+struct Handler {
+    ast: usize,
+    function: FnPtr,
+}
+
+#[derive(Default)]
+struct Registry {
+    functions: HashMap<String, Handler>,
+    before_save: HashMap<String, Handler>,
+    after_save: HashMap<String, Handler>,
+    before_delete: HashMap<String, Handler>,
+    after_delete: HashMap<String, Handler>,
+    after_find: HashMap<String, Handler>,
+}
+
+/// Cloud Code written in Rhai. The `*.rhai` files of a directory register
+/// their functions and triggers when loaded:
+///
+/// ```text
+/// define("hello", |req| "Hello " + req.params.name);
+/// before_save("Post", |req| { req.object.title.trim(); req.object });
+/// after_find("Post", |req| req.objects.filter(|x| !x.hidden));
+/// ```
+///
+/// Scripts read and write objects with `find(class, query)`,
+/// `get(class, objectId)`, `save(class, object)` and
+/// `destroy(class, objectId)`, which take a last `#{useMasterKey: true}`
+/// argument to bypass ACLs. Every invocation runs on its own thread, and is
+/// stopped when it runs too many operations or for too long.
+pub struct Scripts {
+    asts: Vec<Arc<AST>>,
+    registry: Registry,
+    max_operations: u64,
+    timeout: Duration,
+}
+
+fn load_error(path: &Path, error: impl std::fmt::Display) -> Error {
+    Error::ScriptFailed(format!("Could not load {}: {}", path.display(), error))
+}
+
+/// `throw` rejects the request with the value thrown, hitting a limit is a
+/// script failure.
+fn script_error(error: EvalAltResult) -> Error {
+    match error {
+        EvalAltResult::ErrorInFunctionCall(_, _, error, _) => script_error(*error),
+        EvalAltResult::ErrorRuntime(value, _) => Error::OtherCause(value.to_string()),
+        EvalAltResult::ErrorTerminated(..) => Error::ScriptFailed(TIMED_OUT.to_string()),
+        EvalAltResult::ErrorTooManyOperations(..) => {
+            Error::ScriptFailed("Script exceeded its operation limit".to_string())
+        }
+        error => Error::ScriptFailed(error.to_string()),
+    }
+}
+
+fn use_master_key(options: &Map) -> bool {
+    options
+        .get("useMasterKey")
+        .and_then(|x| x.as_bool().ok())
+        .unwrap_or(false)
+}
+
+/// Failed host calls throw their message, running out of time stops the
+/// script as the operation limit does.
+fn host_call(host: &HostCalls, call: HostCall) -> Result<Dynamic, Box<EvalAltResult>> {
+    match host.call(call) {
+        Ok(value) => to_dynamic(value),
+        Err(Error::ScriptFailed(message)) if message == TIMED_OUT => {
+            Err(EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into())
+        }
+        Err(e) => Err(e.message().into()),
+    }
+}
+
+fn register_host_calls(engine: &mut Engine, host: &HostCalls) {
+    let find = |host: &HostCalls, class: &str, query: Map| {
+        let master = use_master_key(&query);
+        let payload = from_dynamic(&Dynamic::from_map(query))?;
+        host_call(
+            host,
+            HostCall {
+                method: "GET",
+                class_name: class.to_string(),
+                object_id: None,
+                payload,
+                master,
+            },
+        )
+    };
+    let get = |host: &HostCalls, class: &str, id: &str, options: Map| {
+        let call = HostCall {
+            method: "GET",
+            class_name: class.to_string(),
+            object_id: Some(id.to_string()),
+            payload: Value::Object(Default::default()),
+            master: use_master_key(&options),
+        };
+        host_call(host, call)
+    };
+    let save = |host: &HostCalls, class: &str, object: Map, options: Map| {
+        let object_id = object
+            .get("objectId")
+            .and_then(|x| x.clone().into_string().ok());
+        let call = HostCall {
+            method: if object_id.is_some() { "PUT" } else { "POST" },
+            class_name: class.to_string(),
+            object_id,
+            payload: from_dynamic(&Dynamic::from_map(object))?,
+            master: use_master_key(&options),
+        };
+        host_call(host, call)
+    };
+    let destroy = |host: &HostCalls, class: &str, id: &str, options: Map| {
+        let call = HostCall {
+            method: "DELETE",
+            class_name: class.to_string(),
+            object_id: Some(id.to_string()),
+            payload: Value::Object(Default::default()),
+            master: use_master_key(&options),
+        };
+        host_call(host, call)
+    };
+
+    let calls = host.clone();
+    engine.register_fn("find", move |class: &str, query: Map| {
+        find(&calls, class, query)
+    });
+    let calls = host.clone();
+    engine.register_fn("get", move |class: &str, id: &str| {
+        get(&calls, class, id, Map::new())
+    });
+    let calls = host.clone();
+    engine.register_fn("get", move |class: &str, id: &str, options: Map| {
+        get(&calls, class, id, options)
+    });
+    let calls = host.clone();
+    engine.register_fn("save", move |class: &str, object: Map| {
+        save(&calls, class, object, Map::new())
+    });
+    let calls = host.clone();
+    engine.register_fn("save", move |class: &str, object: Map, options: Map| {
+        save(&calls, class, object, options)
+    });
+    let calls = host.clone();
+    engine.register_fn("destroy", move |class: &str, id: &str| {
+        destroy(&calls, class, id, Map::new())
+    });
+    let calls = host.clone();
+    engine.register_fn("destroy", move |class: &str, id: &str, options: Map| {
+        destroy(&calls, class, id, options)
+    });
+}
+
+impl Scripts {
+    /// Compiles and runs the `*.rhai` files of `dir` in name order.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Scripts, Error> {
+        let dir = dir.as_ref();
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map_err(|e| load_error(dir, e))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|x| x == "rhai"))
+            .collect();
+        paths.sort();
+
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let mut asts = vec![];
+        for (index, path) in paths.into_iter().enumerate() {
+            let mut engine = Engine::new();
+            let functions = [
+                "before_save",
+                "after_save",
+                "before_delete",
+                "after_delete",
+                "after_find",
+            ];
+            for name in functions.iter().copied() {
+                let registry = registry.clone();
+                engine.register_fn(name, move |class_name: &str, function: FnPtr| {
+                    let mut registry = registry.lock().unwrap();
+                    let triggers = match name {
+                        "before_save" => &mut registry.before_save,
+                        "after_save" => &mut registry.after_save,
+                        "before_delete" => &mut registry.before_delete,
+                        "after_delete" => &mut registry.after_delete,
+                        _ => &mut registry.after_find,
+                    };
+                    let handler = Handler {
+                        ast: index,
+                        function,
+                    };
+                    triggers.insert(class_name.to_string(), handler);
+                });
+            }
+            let functions = registry.clone();
+            engine.register_fn("define", move |name: &str, function: FnPtr| {
+                let handler = Handler {
+                    ast: index,
+                    function,
+                };
+                let mut registry = functions.lock().unwrap();
+                registry.functions.insert(name.to_string(), handler);
+            });
+
+            let ast = engine
+                .compile_file(path.clone())
+                .map_err(|e| load_error(&path, e))?;
+            engine.run_ast(&ast).map_err(|e| load_error(&path, e))?;
+            info!("Loaded cloud script {}", path.display());
+            asts.push(Arc::new(ast));
+        }
+
+        let registry = std::mem::take(&mut *registry.lock().unwrap());
+        Ok(Scripts {
+            asts,
+            registry,
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Operations a single invocation may run, one million by default.
+    pub fn max_operations(mut self, max_operations: u64) -> Self {
+        self.max_operations = max_operations;
+        self
+    }
+
+    /// How long a single invocation may run, ten seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn has_function(&self, name: &str) -> bool {
+        self.registry.functions.contains_key(name)
+    }
+
+    /// Calls a script function on its own thread, answering its host calls
+    /// until it returns.
+    async fn call(&self, ctx: &Context, handler: &Handler, req: Document) -> Result<Value, Error> {
+        let ast = self.asts[handler.ast].clone();
+        let function = handler.function.clone();
+        let (max_operations, timeout) = (self.max_operations, self.timeout);
+        let req = to_json(req);
+
+        run_on_thread(ctx, move |host| {
+            let mut engine = Engine::new();
+            engine.set_max_operations(max_operations);
+            let deadline = Instant::now() + timeout;
+            engine.on_progress(move |_| match Instant::now() > deadline {
+                true => Some(Dynamic::UNIT),
+                false => None,
+            });
+            engine.on_print(|text| info!("{}", text));
+            engine.on_debug(|text, _, _| debug!("{}", text));
+            register_host_calls(&mut engine, &host.with_deadline(deadline));
+
+            to_dynamic(req)
+                .and_then(|req| function.call::<Dynamic>(&engine, &ast, (req,)))
+                .and_then(|result| from_dynamic::<Value>(&result))
+                .map_err(|e| script_error(*e))
+        })
+        .await
+    }
+
+    /// Runs the script function `name`, which has to exist.
+    pub async fn run_function(
+        &self,
+        ctx: &Context,
+        name: &str,
+        params: Document,
+    ) -> Result<Bson, Error> {
+        let handler = &self.registry.functions[name];
+        let mut req = request_body(&ctx.user, ctx.user.is_master, &ctx.cloud_context);
+        req.insert("functionName", name);
+        req.insert("params", params);
+        let result = self.call(ctx, handler, req).await?;
+        Bson::try_from(result).map_err(|e| Error::ScriptFailed(e.to_string()))
+    }
+
+    /// The script triggers of the context class, bound to the server of the
+    /// context for their host calls.
+    pub fn class_triggers(self: &Arc<Self>, ctx: &Context) -> ClassTriggers {
+        let class = ctx.class.as_str();
+        let registry = &self.registry;
+        let ctx = Arc::new(ctx.for_class(class));
+        ClassTriggers {
+            before_save: registry
+                .before_save
+                .get(class)
+                .map(|x| self.before_save_trigger(&ctx, x)),
+            after_save: registry
+                .after_save
+                .get(class)
+                .map(|x| self.object_trigger("afterSave", &ctx, x)),
+            before_delete: registry
+                .before_delete
+                .get(class)
+                .map(|x| self.object_trigger("beforeDelete", &ctx, x)),
+            after_delete: registry
+                .after_delete
+                .get(class)
+                .map(|x| self.object_trigger("afterDelete", &ctx, x)),
+            before_find: None,
+            after_find: registry
+                .after_find
+                .get(class)
+                .map(|x| self.after_find_trigger(&ctx, x)),
+        }
+    }
+
+    /// A `beforeSave` script returns the object to save, or nothing to keep
+    /// it as it is.
+    fn before_save_trigger(
+        self: &Arc<Self>,
+        ctx: &Arc<Context>,
+        handler: &Handler,
+    ) -> BeforeSaveTrigger {
+        let (scripts, ctx, handler) = (self.clone(), ctx.clone(), handler.clone());
+        Arc::new(move |req| {
+            let (scripts, ctx, handler) = (scripts.clone(), ctx.clone(), handler.clone());
+            async move {
+                let body = object_body("beforeSave", &req);
+                match scripts.call(&ctx, &handler, body).await? {
+                    Value::Null => Ok(req.object),
+                    object => to_document(object),
+                }
+            }
+            .boxed_local()
+        })
+    }
+
+    fn object_trigger(
+        self: &Arc<Self>,
+        name: &'static str,
+        ctx: &Arc<Context>,
+        handler: &Handler,
+    ) -> Trigger {
+        let (scripts, ctx, handler) = (self.clone(), ctx.clone(), handler.clone());
+        Arc::new(move |req| {
+            let (scripts, ctx, handler) = (scripts.clone(), ctx.clone(), handler.clone());
+            async move {
+                scripts
+                    .call(&ctx, &handler, object_body(name, &req))
+                    .await?;
+                Ok(())
+            }
+            .boxed_local()
+        })
+    }
+
+    /// An `afterFind` script returns the objects to answer with, or nothing
+    /// to keep them as they are.
+    fn after_find_trigger(
+        self: &Arc<Self>,
+        ctx: &Arc<Context>,
+        handler: &Handler,
+    ) -> AfterFindTrigger {
+        let (scripts, ctx, handler) = (self.clone(), ctx.clone(), handler.clone());
+        Arc::new(move |req| {
+            let (scripts, ctx, handler) = (scripts.clone(), ctx.clone(), handler.clone());
+            async move {
+                match scripts
+                    .call(&ctx, &handler, find_body("afterFind", &req))
+                    .await?
+                {
+                    Value::Array(objects) => objects.into_iter().map(to_document).collect(),
+                    _ => Ok(req.objects),
+                }
+            }
+            .boxed_local()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::AppCache;
+    use crate::cloud::{FindTriggerRequest, TriggerRequest};
+    use crate::config::Config;
+    use crate::database::DbAdapter;
+    use crate::user::User;
+    use crate::util;
+    use actix_web::rt::System;
+    use bson::doc;
+    use std::fs;
+
+    /// Loads `scripts` as files of a new directory.
+    fn load(scripts: &[&str]) -> Scripts {
+        let dir = std::env::temp_dir().join(format!("scripts-{}", util::new_object_id()));
+        fs::create_dir(&dir).unwrap();
+        for (index, script) in scripts.iter().enumerate() {
+            fs::write(dir.join(format!("{}.rhai", index)), script).unwrap();
+        }
+        let scripts = Scripts::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        scripts.unwrap()
+    }
+
+    /// A context for `Post`, the database is never reached.
+    async fn context() -> Context {
+        Context {
+            class: "Post".to_string(),
+            user: User::master(),
+            db: Arc::new(DbAdapter::connect("mongodb://localhost/test").await),
+            cache: Arc::new(AppCache::new()),
+            config: Arc::new(Config::default()),
+            cloud_context: doc! {},
+        }
+    }
+
+    fn run_function(scripts: Scripts, name: &'static str, params: Document) -> Result<Bson, Error> {
+        System::new("test")
+            .block_on(async move { scripts.run_function(&context().await, name, params).await })
+    }
+
+    #[test]
+    fn registers_functions_and_triggers() {
+        let scripts = load(&[
+            r#"define("hello", |req| "Hello " + req.params.name);"#,
+            r#"before_save("Post", |req| req.object); after_find("Comment", |req| ());"#,
+        ]);
+        assert!(scripts.has_function("hello"));
+        assert!(!scripts.has_function("other"));
+        assert!(scripts.registry.before_save.contains_key("Post"));
+        assert!(scripts.registry.after_find.contains_key("Comment"));
+        assert!(scripts.registry.after_save.is_empty());
+
+        let result = run_function(scripts, "hello", doc! {"name": "Ada"});
+        assert_eq!(result.unwrap(), Bson::from("Hello Ada"));
+    }
+
+    #[test]
+    fn reports_load_errors_with_the_file() {
+        let dir = std::env::temp_dir().join(format!("scripts-{}", util::new_object_id()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("broken.rhai"), "define(").unwrap();
+        let result = Scripts::load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(Error::ScriptFailed(message)) => assert!(message.contains("broken.rhai")),
+            _ => panic!("the broken script was loaded"),
+        }
+    }
+
+    #[test]
+    fn maps_throws_and_limits_to_errors() {
+        let script = r#"
+            define("reject", |req| throw "no way");
+            define("spin", |req| { loop {} });
+        "#;
+        match run_function(load(&[script]), "reject", doc! {}) {
+            Err(Error::OtherCause(message)) => assert_eq!(message, "no way"),
+            _ => panic!("expected the thrown value"),
+        }
+
+        let scripts = load(&[script]).max_operations(1_000);
+        match run_function(scripts, "spin", doc! {}) {
+            Err(Error::ScriptFailed(message)) => assert!(message.contains("operation limit")),
+            _ => panic!("expected the operation limit"),
+        }
+
+        let scripts = load(&[script]).timeout(Duration::from_millis(50));
+        match run_function(scripts, "spin", doc! {}) {
+            Err(Error::ScriptFailed(message)) => assert_eq!(message, TIMED_OUT),
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn runs_before_save_and_after_find_triggers() {
+        let scripts = Arc::new(load(&[r#"
+            before_save("Post", |req| { req.object.title = req.object.title.to_upper(); req.object });
+            after_find("Post", |req| req.objects.filter(|x| !x.hidden));
+        "#]));
+        System::new("test").block_on(async move {
+            let ctx = context().await;
+            let triggers = scripts.class_triggers(&ctx);
+            assert!(triggers.after_save.is_none());
+
+            let before_save = triggers.before_save.unwrap();
+            let req = TriggerRequest {
+                class_name: "Post".to_string(),
+                object: doc! {"title": "hello"},
+                original: None,
+                user: User::master(),
+                master: true,
+                context: doc! {},
+            };
+            let object = before_save(req).await.unwrap();
+            assert_eq!(object.get_str("title"), Ok("HELLO"));
+
+            let after_find = triggers.after_find.unwrap();
+            let req = FindTriggerRequest {
+                class_name: "Post".to_string(),
+                query: Default::default(),
+                is_get: false,
+                objects: vec![
+                    doc! {"name": "a", "hidden": true},
+                    doc! {"name": "b", "hidden": false},
+                ],
+                user: User::master(),
+                master: true,
+                context: doc! {},
+            };
+            let objects = after_find(req).await.unwrap();
+            assert_eq!(objects.len(), 1);
+            assert_eq!(objects[0].get_str("name"), Ok("b"));
+        });
+    }
+
+    #[test]
+    fn makes_host_calls_for_object_functions() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let host = HostCalls::answered_by(move |call| {
+            let id = call.object_id.clone().unwrap_or_default();
+            let payload = call.payload.clone();
+            recorded
+                .lock()
+                .unwrap()
+                .push((call.method, call.class_name, id, payload, call.master));
+            Ok(serde_json::json!({"objectId": "new"}))
+        });
+        let mut engine = Engine::new();
+        register_host_calls(&mut engine, &host);
+        let script = r#"
+            let saved = save("Post", #{title: "a"});
+            save("Post", #{objectId: saved.objectId, title: "b"}, #{useMasterKey: true});
+            get("Post", "abc");
+            destroy("Post", "abc", #{useMasterKey: true});
+            saved.objectId
+        "#;
+        assert_eq!(engine.eval::<String>(script).unwrap(), "new");
+
+        let calls = calls.lock().unwrap();
+        let summary: Vec<(&str, &str, bool)> = calls
+            .iter()
+            .map(|(method, _, id, _, master)| (*method, id.as_str(), *master))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("POST", "", false),
+                ("PUT", "new", true),
+                ("GET", "abc", false),
+                ("DELETE", "abc", true),
+            ]
+        );
+        assert_eq!(calls[0].1, "Post");
+        assert_eq!(calls[1].3["title"], "b");
+    }
+
+    #[test]
+    fn stops_scripts_whose_host_calls_outlive_the_deadline() {
+        let host = HostCalls::answered_by(|_| {
+            std::thread::sleep(Duration::from_secs(5));
+            Ok(Value::Null)
+        });
+        let mut engine = Engine::new();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        register_host_calls(&mut engine, &host.with_deadline(deadline));
+        // A script can't catch running out of time
+        let script = r#"try { get("Post", "abc") } catch { "caught" }"#;
+        let error = engine.eval::<Dynamic>(script).unwrap_err();
+        match script_error(*error) {
+            Error::ScriptFailed(message) => assert_eq!(message, TIMED_OUT),
+            _ => panic!("expected a timeout"),
+        }
+    }
+}
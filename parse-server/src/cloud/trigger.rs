@@ -0,0 +1,130 @@
+use crate::error::Error;
+use crate::user::User;
+use bson::Document;
+use futures::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What a save or delete trigger is called with.
+#[derive(Clone)]
+pub struct TriggerRequest {
+    pub class_name: String,
+    /// The object as it will be saved, with `__op` operations such as
+    /// `Increment` applied to the stored object, or as it was before a delete.
+    pub object: Document,
+    /// The stored object before an update or delete.
+    pub original: Option<Document>,
+    pub user: User,
+    pub master: bool,
+    /// Data the client passes to its triggers, see `X-Parse-Cloud-Context`.
+    pub context: Document,
+}
+
+/// A find, count or get as `beforeFind` can rewrite it.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub filter: Document,
+    pub limit: Option<i64>,
+    pub skip: Option<i64>,
+    pub include: Vec<String>,
+    /// Fields to return, all fields when empty.
+    pub keys: Vec<String>,
+    pub read_preference: Option<String>,
+    pub count: bool,
+}
+
+/// What `beforeFind` and `afterFind` triggers are called with.
+#[derive(Clone)]
+pub struct FindTriggerRequest {
+    pub class_name: String,
+    pub query: Query,
+    /// The objects found, empty for `beforeFind`.
+    pub objects: Vec<Document>,
+    /// Whether the query is a get by objectId.
+    pub is_get: bool,
+    pub user: User,
+    pub master: bool,
+    pub context: Document,
+}
+
+/// What a `beforeFind` trigger resolves to.
+pub enum BeforeFind {
+    /// Runs this query instead.
+    Query(Query),
+    /// Answers with these objects without querying the database.
+    Objects(Vec<Document>),
+}
+
+pub type BeforeFindTrigger = Arc<
+    dyn Fn(FindTriggerRequest) -> LocalBoxFuture<'static, Result<BeforeFind, Error>> + Send + Sync,
+>;
+
+/// An `afterFind` trigger resolves to the objects to return.
+pub type AfterFindTrigger = Arc<
+    dyn Fn(FindTriggerRequest) -> LocalBoxFuture<'static, Result<Vec<Document>, Error>>
+        + Send
+        + Sync,
+>;
+
+/// A `beforeSave` trigger resolves to the object to save.
+pub type BeforeSaveTrigger =
+    Arc<dyn Fn(TriggerRequest) -> LocalBoxFuture<'static, Result<Document, Error>> + Send + Sync>;
+
+pub type Trigger =
+    Arc<dyn Fn(TriggerRequest) -> LocalBoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// Triggers keyed by class name.
+#[derive(Clone, Default)]
+pub struct Triggers {
+    pub before_save: HashMap<String, BeforeSaveTrigger>,
+    pub after_save: HashMap<String, Trigger>,
+    pub before_delete: HashMap<String, Trigger>,
+    pub after_delete: HashMap<String, Trigger>,
+    pub before_find: HashMap<String, BeforeFindTrigger>,
+    pub after_find: HashMap<String, AfterFindTrigger>,
+}
+
+impl Triggers {
+    pub fn class(&self, class_name: &str) -> ClassTriggers {
+        ClassTriggers {
+            before_save: self.before_save.get(class_name).cloned(),
+            after_save: self.after_save.get(class_name).cloned(),
+            before_delete: self.before_delete.get(class_name).cloned(),
+            after_delete: self.after_delete.get(class_name).cloned(),
+            before_find: self.before_find.get(class_name).cloned(),
+            after_find: self.after_find.get(class_name).cloned(),
+        }
+    }
+}
+
+/// The triggers of one class, Rust triggers win over scripts and webhooks.
+#[derive(Default)]
+pub struct ClassTriggers {
+    pub before_save: Option<BeforeSaveTrigger>,
+    pub after_save: Option<Trigger>,
+    pub before_delete: Option<Trigger>,
+    pub after_delete: Option<Trigger>,
+    pub before_find: Option<BeforeFindTrigger>,
+    pub after_find: Option<AfterFindTrigger>,
+}
+
+impl ClassTriggers {
+    /// Fills the triggers this does not have from `other`.
+    pub fn or(self, other: ClassTriggers) -> ClassTriggers {
+        ClassTriggers {
+            before_save: self.before_save.or(other.before_save),
+            after_save: self.after_save.or(other.after_save),
+            before_delete: self.before_delete.or(other.before_delete),
+            after_delete: self.after_delete.or(other.after_delete),
+            before_find: self.before_find.or(other.before_find),
+            after_find: self.after_find.or(other.after_find),
+        }
+    }
+
+    pub fn has_write_triggers(&self) -> bool {
+        self.before_save.is_some()
+            || self.after_save.is_some()
+            || self.before_delete.is_some()
+            || self.after_delete.is_some()
+    }
+}
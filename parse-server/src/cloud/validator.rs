@@ -0,0 +1,132 @@
+use super::FunctionRequest;
+use crate::error::Error;
+use bson::Bson;
+
+/// Rules for one param of a Cloud Code call.
+#[derive(Clone, Debug, Default)]
+pub struct FieldRule {
+    pub required: bool,
+    /// Type name as in the schema, e.g. `string`, `number` or `array`.
+    pub field_type: Option<String>,
+    /// Value used when the param is missing.
+    pub default: Option<Bson>,
+    /// Values the param is limited to.
+    pub options: Vec<Bson>,
+}
+
+impl FieldRule {
+    pub fn new() -> Self {
+        FieldRule::default()
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn field_type(mut self, field_type: &str) -> Self {
+        self.field_type = Some(field_type.to_string());
+        self
+    }
+
+    pub fn default_value<V: Into<Bson>>(mut self, value: V) -> Self {
+        self.default = Some(value.into());
+        self
+    }
+
+    pub fn options(mut self, options: Vec<Bson>) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Checks run before a Cloud Code function, failures are validation errors.
+#[derive(Clone, Debug, Default)]
+pub struct Validator {
+    pub require_user: bool,
+    pub require_master: bool,
+    pub fields: Vec<(String, FieldRule)>,
+}
+
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_) => "number",
+        Bson::String(_) => "string",
+        Bson::Boolean(_) => "boolean",
+        Bson::Array(_) => "array",
+        Bson::Document(_) => "object",
+        Bson::Null => "null",
+        _ => "unknown",
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    pub fn require_user(mut self) -> Self {
+        self.require_user = true;
+        self
+    }
+
+    pub fn require_master(mut self) -> Self {
+        self.require_master = true;
+        self
+    }
+
+    pub fn field(mut self, name: &str, rule: FieldRule) -> Self {
+        self.fields.push((name.to_string(), rule));
+        self
+    }
+
+    /// Validates a call, filling in default params. The master key skips the
+    /// user requirement.
+    pub fn validate(&self, req: &mut FunctionRequest) -> Result<(), Error> {
+        if self.require_master && !req.master {
+            return Err(Error::ValidationError(
+                "Validation failed. Master key is required to complete this request.".to_string(),
+            ));
+        }
+        if self.require_user && req.user.id.is_none() && !req.master {
+            return Err(Error::ValidationError(
+                "Validation failed. Please login to continue.".to_string(),
+            ));
+        }
+
+        for (name, rule) in &self.fields {
+            let value = match req.params.get(name) {
+                Some(value) if *value != Bson::Null => value,
+                _ => {
+                    if let Some(default) = &rule.default {
+                        req.params.insert(name.as_str(), default.clone());
+                    } else if rule.required {
+                        let message =
+                            format!("Validation failed. Please specify data for {}.", name);
+                        return Err(Error::ValidationError(message));
+                    }
+                    continue;
+                }
+            };
+            if let Some(field_type) = &rule.field_type {
+                if type_name(value) != field_type {
+                    let message = format!(
+                        "Validation failed. Invalid type for {}. Expected: {}",
+                        name, field_type
+                    );
+                    return Err(Error::ValidationError(message));
+                }
+            }
+            if !rule.options.is_empty() && !rule.options.contains(value) {
+                let options: Vec<String> = rule.options.iter().map(|x| x.to_string()).collect();
+                let message = format!(
+                    "Validation failed. Invalid option for {}. Expected: {}",
+                    name,
+                    options.join(", ")
+                );
+                return Err(Error::ValidationError(message));
+            }
+        }
+        Ok(())
+    }
+}
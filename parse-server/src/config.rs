@@ -1,16 +1,252 @@
-const CONFIG: Config = Config {
-    allow_client_class_creation: false,
-};
+use crate::auth::AuthAdapters;
+use crate::cloud::CloudCode;
+use crate::email::EmailAdapter;
+use crate::files::FilesAdapter;
+use crate::push::PushAdapters;
+use regex::Regex;
+use std::env;
+use std::sync::Arc;
 
-#[derive(Debug)]
+/// Locks an account after too many failed logins.
+#[derive(Clone)]
+pub struct AccountLockout {
+    /// Failed logins before the account is locked.
+    pub threshold: i64,
+    /// Minutes the account stays locked.
+    pub duration: i64,
+    pub unlock_on_password_reset: bool,
+}
+
+impl AccountLockout {
+    pub fn new(threshold: i64, duration: i64) -> Self {
+        AccountLockout {
+            threshold,
+            duration,
+            unlock_on_password_reset: false,
+        }
+    }
+}
+
+/// Rejects repeated `X-Parse-Request-Id` headers on some paths, so retried
+/// writes are not applied twice.
+#[derive(Clone)]
+pub struct IdempotencyOptions {
+    /// Request paths below the mount path, such as `classes/Order` or
+    /// `functions/.*`, matched from their start.
+    pub paths: Vec<Regex>,
+    /// Seconds a request id is remembered.
+    pub ttl: i64,
+}
+
+impl IdempotencyOptions {
+    pub fn new(paths: &[&str]) -> Result<Self, regex::Error> {
+        // Grouped so alternations such as `a|b` are anchored as a whole
+        let paths = paths
+            .iter()
+            .map(|path| Regex::new(&format!("^(?:{})", path)))
+            .collect::<Result<_, _>>()?;
+        Ok(IdempotencyOptions { paths, ttl: 300 })
+    }
+
+    /// Whether requests to `path`, relative to the mount path, are checked.
+    pub fn matches(&self, path: &str) -> bool {
+        self.paths.iter().any(|x| x.is_match(path))
+    }
+}
+
+pub type PasswordValidator = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Rules new passwords have to follow.
+#[derive(Clone, Default)]
+pub struct PasswordPolicy {
+    pub validator_pattern: Option<Regex>,
+    pub validator_callback: Option<PasswordValidator>,
+    /// Message of the error returned when a validator rejects a password.
+    pub validation_error: Option<String>,
+    pub do_not_allow_username: bool,
+    /// Days after which the password has to be reset.
+    pub max_password_age: Option<i64>,
+    /// Number of previous passwords that can not be reused.
+    pub max_password_history: Option<usize>,
+}
+
+/// Server settings, shared by every request through `Context`.
 pub struct Config {
+    pub app_id: String,
+    pub master_key: String,
+    pub read_only_master_key: Option<String>,
+    pub database_uri: String,
+    pub server_url: String,
+    /// Base url of the links in emails, `server_url` when not set.
+    pub public_server_url: Option<String>,
+    pub app_name: String,
     pub allow_client_class_creation: bool,
+    /// Session lifetime in seconds.
+    pub session_length: i64,
+    pub auth: AuthAdapters,
+    /// Allows signups through `authData.anonymous`.
+    pub enable_anonymous_users: bool,
+    pub verify_user_emails: bool,
+    pub prevent_login_with_unverified_email: bool,
+    /// Seconds an email verification link stays valid, forever when `None`.
+    pub email_verify_token_validity_duration: Option<i64>,
+    /// Seconds a password reset link stays valid, forever when `None`.
+    pub reset_token_validity_duration: Option<i64>,
+    pub email: Option<Arc<dyn EmailAdapter>>,
+    pub push: PushAdapters,
+    /// Where uploaded files are kept, GridFS of the database when not set.
+    pub files: Option<Arc<dyn FilesAdapter>>,
+    /// Largest accepted upload in bytes.
+    pub max_upload_size: usize,
+    pub cloud: CloudCode,
+    /// Sent as `X-Parse-Webhook-Key` with every webhook call.
+    pub webhook_key: Option<String>,
+    pub account_lockout: Option<AccountLockout>,
+    pub password_policy: Option<PasswordPolicy>,
+    pub idempotency: Option<IdempotencyOptions>,
 }
 
-pub fn config() -> Config {
-    CONFIG
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            app_id: String::new(),
+            master_key: String::new(),
+            read_only_master_key: None,
+            database_uri: "mongodb://192.168.254.111:27017/rust-parse".to_string(),
+            server_url: "http://localhost:5000/parse".to_string(),
+            public_server_url: None,
+            app_name: "Parse".to_string(),
+            allow_client_class_creation: false,
+            session_length: 31536000,
+            auth: AuthAdapters::new(),
+            enable_anonymous_users: true,
+            verify_user_emails: false,
+            prevent_login_with_unverified_email: false,
+            email_verify_token_validity_duration: None,
+            reset_token_validity_duration: None,
+            email: None,
+            push: PushAdapters::new(),
+            files: None,
+            max_upload_size: 20 * 1024 * 1024,
+            cloud: CloudCode::new(),
+            webhook_key: None,
+            account_lockout: None,
+            password_policy: None,
+            idempotency: None,
+        }
+    }
 }
 
-// pub fn init() {
+/// Parses sizes such as `20mb`, `512kb` or `1024`.
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim().to_ascii_lowercase();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size.as_str(), ""),
+    };
+    let unit = match unit.trim() {
+        "" | "b" => 1,
+        "kb" => 1024,
+        "mb" => 1024 * 1024,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok().map(|x| x * unit)
+}
 
-// }
+impl Config {
+    /// Reads the settings from the `PARSE_SERVER_*` environment variables used
+    /// by Parse Server, falling back to the defaults.
+    pub fn from_env() -> Self {
+        let mut config = Config::default();
+        if let Ok(value) = env::var("PARSE_SERVER_APPLICATION_ID") {
+            config.app_id = value;
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_MASTER_KEY") {
+            config.master_key = value;
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_READ_ONLY_MASTER_KEY") {
+            config.read_only_master_key = Some(value);
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_DATABASE_URI") {
+            config.database_uri = value;
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_URL") {
+            config.server_url = value;
+        }
+        if let Ok(value) = env::var("PARSE_PUBLIC_SERVER_URL") {
+            config.public_server_url = Some(value);
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_APP_NAME") {
+            config.app_name = value;
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_ENABLE_ANON_USERS") {
+            config.enable_anonymous_users = value != "false";
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_MAX_UPLOAD_SIZE") {
+            match parse_size(&value) {
+                Some(size) => config.max_upload_size = size,
+                None => error!("Invalid max upload size: {}", value),
+            }
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_WEBHOOK_KEY") {
+            config.webhook_key = Some(value);
+        }
+        #[cfg(feature = "scripting")]
+        if let Ok(value) = env::var("PARSE_SERVER_CLOUD") {
+            match crate::cloud::Scripts::load(&value) {
+                Ok(scripts) => config.cloud = std::mem::take(&mut config.cloud).scripts(scripts),
+                Err(e) => error!("{}", e),
+            }
+        }
+        #[cfg(feature = "wasm")]
+        if let Ok(value) = env::var("PARSE_SERVER_WASM_PLUGINS") {
+            match crate::cloud::Plugins::load(&value) {
+                Ok(plugins) => config.cloud = std::mem::take(&mut config.cloud).plugins(plugins),
+                Err(e) => error!("{}", e),
+            }
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_VERIFY_USER_EMAILS") {
+            config.verify_user_emails = value == "true";
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_PREVENT_LOGIN_WITH_UNVERIFIED_EMAIL") {
+            config.prevent_login_with_unverified_email = value == "true";
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_EXPERIMENTAL_IDEMPOTENCY_PATHS") {
+            let paths: Vec<&str> = value.split(',').map(str::trim).collect();
+            match IdempotencyOptions::new(&paths) {
+                Ok(idempotency) => config.idempotency = Some(idempotency),
+                Err(e) => error!("Invalid idempotency path: {}", e),
+            }
+        }
+        if let Ok(value) = env::var("PARSE_SERVER_EXPERIMENTAL_IDEMPOTENCY_TTL") {
+            match (&mut config.idempotency, value.parse()) {
+                (Some(idempotency), Ok(ttl)) => idempotency.ttl = ttl,
+                _ => error!("Invalid idempotency ttl: {}", value),
+            }
+        }
+        config
+    }
+
+    pub fn public_server_url(&self) -> &str {
+        self.public_server_url.as_ref().unwrap_or(&self.server_url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyOptions;
+
+    #[test]
+    fn idempotency_paths_match_from_the_start() {
+        let options = IdempotencyOptions::new(&["classes/Order|functions/.*"]).unwrap();
+        assert!(options.matches("classes/Order"));
+        assert!(options.matches("functions/charge"));
+        assert!(!options.matches("batch/functions/charge"));
+        assert!(!options.matches("users/classes/Order"));
+
+        let options = IdempotencyOptions::new(&["^users"]).unwrap();
+        assert!(options.matches("users/me"));
+        assert!(!options.matches("classes/users"));
+    }
+}
@@ -9,6 +9,7 @@ lazy_static! {
         set.insert("_GlobalConfig");
         set.insert("_JobSchedule");
         set.insert("_Idempotency");
+        set.insert("_Audience");
         set
     };
     pub static ref SYSTEM_CLASSES: HashSet<&'static str> = {
@@ -1,14 +1,23 @@
+use crate::config::Config;
 use crate::error::Error;
-use crate::operation::{Context, FindRequest, Join};
-use crate::schema::{Field, FieldType, Permissions, Schema};
+use crate::files;
+use crate::operation::{self, Context, FindRequest, GetRequest, Join, Request};
+use crate::schema::{parse_type, public_permission, Field, FieldType, Permissions, Schema};
 use bson::{doc, Document};
 use chrono::DateTime;
 use chrono::SecondsFormat;
 use chrono::Utc;
 use futures::future::join_all;
 use futures::stream::StreamExt;
+use mongodb::error::{ErrorKind, WriteFailure};
 use mongodb::Database;
-use mongodb::{options::ClientOptions, Client};
+use mongodb::{
+    options::{
+        ClientOptions, CollectionOptions, FindOneAndUpdateOptions, ReadPreference,
+        ReadPreferenceOptions, ReturnDocument, SelectionCriteria, UpdateOptions,
+    },
+    Client,
+};
 use std::collections::HashMap;
 
 #[derive(Clone)]
@@ -22,17 +31,251 @@ impl From<mongodb::error::Error> for Error {
     }
 }
 
+/// Changes to a relation's `_Join` collection carried by an update.
+struct RelationUpdate {
+    key: String,
+    add: bool,
+    ids: Vec<String>,
+}
+
 impl DbAdapter {
-    pub async fn connect() -> DbAdapter {
-        let client_options = ClientOptions::parse("mongodb://192.168.254.111:27017/rust-parse")
-            .await
-            .unwrap();
+    pub async fn connect(uri: &str) -> DbAdapter {
+        let client_options = ClientOptions::parse(uri).await.unwrap();
         let client = Client::with_options(client_options).unwrap();
+        // The database name is the path of the connection string
+        let name = uri
+            .rsplit('/')
+            .next()
+            .and_then(|x| x.split('?').next())
+            .filter(|x| !x.is_empty() && !x.contains(':'))
+            .unwrap_or("parse");
         DbAdapter {
-            db: client.database("rust-parse"),
+            db: client.database(name),
+        }
+    }
+
+    /// Adds fields to a class in `_SCHEMA`, creating the class when needed.
+    pub async fn update_schema(
+        &self,
+        class_name: &str,
+        fields: &[(String, String)],
+    ) -> Result<(), Error> {
+        let mut set = doc! {};
+        for (name, type_name) in fields {
+            set.insert(name, type_name);
+        }
+        let update = doc! {
+            "$set": set,
+            "$setOnInsert": {"objectId": "string", "createdAt": "date", "updatedAt": "date"},
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.db
+            .collection("_SCHEMA")
+            .update_one(doc! {"_id": class_name}, update, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Finds raw stored documents, `filter` is a mongo filter.
+    pub async fn find_documents(
+        &self,
+        class_name: &str,
+        filter: Document,
+    ) -> Result<Vec<Document>, Error> {
+        let mut cursor = self.db.collection(class_name).find(filter, None).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?);
+        }
+        Ok(documents)
+    }
+
+    pub async fn insert_document(&self, class_name: &str, document: Document) -> Result<(), Error> {
+        self.db
+            .collection(class_name)
+            .insert_one(document, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Inserts a raw document into a collection with a unique index, returns
+    /// false when it would duplicate an existing one.
+    pub async fn insert_unique_document(
+        &self,
+        class_name: &str,
+        document: Document,
+    ) -> Result<bool, Error> {
+        match self
+            .db
+            .collection(class_name)
+            .insert_one(document, None)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::WriteError(WriteFailure::WriteError(e)) if e.code == 11000 => Ok(false),
+                _ => Err(e.into()),
+            },
         }
     }
 
+    /// Creates indexes of a collection, `indexes` are `createIndexes` specs.
+    pub async fn create_indexes(
+        &self,
+        class_name: &str,
+        indexes: Vec<Document>,
+    ) -> Result<(), Error> {
+        self.db
+            .run_command(doc! {"createIndexes": class_name, "indexes": indexes}, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Applies a mongo update to the matching documents, returns the match count.
+    pub async fn update_documents(
+        &self,
+        class_name: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<i64, Error> {
+        let result = self
+            .db
+            .collection(class_name)
+            .update_many(filter, update, None)
+            .await?;
+        Ok(result.matched_count)
+    }
+
+    /// Applies a mongo update to the first matching document, returns the
+    /// document as updated.
+    pub async fn update_document(
+        &self,
+        class_name: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<Option<Document>, Error> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let document = self
+            .db
+            .collection(class_name)
+            .find_one_and_update(filter, update, options)
+            .await?;
+        Ok(document)
+    }
+
+    /// Applies a mongo update to the matching document, inserting it when
+    /// there is none.
+    pub async fn upsert_document(
+        &self,
+        class_name: &str,
+        filter: Document,
+        update: Document,
+    ) -> Result<(), Error> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.db
+            .collection(class_name)
+            .update_one(filter, update, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the matching documents, returns the deleted count.
+    pub async fn delete_documents(&self, class_name: &str, filter: Document) -> Result<i64, Error> {
+        let result = self
+            .db
+            .collection(class_name)
+            .delete_many(filter, None)
+            .await?;
+        Ok(result.deleted_count)
+    }
+
+    /// Stores a new object from REST params.
+    pub async fn create_object(
+        &self,
+        schema: &Schema,
+        object_id: &str,
+        params: &Document,
+    ) -> Result<(), Error> {
+        let (update, relations) = transform_update(params, schema);
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.db
+            .collection(&schema.name)
+            .update_one(doc! {"_id": object_id}, update, options)
+            .await?;
+        self.update_relations(schema, object_id, relations).await
+    }
+
+    /// Applies REST params to an object, `filter` holds extra REST constraints
+    /// (ACL, pointer permissions) the object has to match.
+    pub async fn update_object(
+        &self,
+        schema: &Schema,
+        object_id: &str,
+        filter: Option<&Document>,
+        params: &Document,
+    ) -> Result<(), Error> {
+        let (update, relations) = transform_update(params, schema);
+        let filter = object_filter(schema, object_id, filter);
+        let collection = self.db.collection(&schema.name);
+        let matched = if update.is_empty() {
+            collection.count_documents(filter, None).await?
+        } else {
+            collection
+                .update_one(filter, update, None)
+                .await?
+                .matched_count
+        };
+        if matched == 0 {
+            return Err(Error::NotFound("Object not found.".to_string()));
+        }
+        self.update_relations(schema, object_id, relations).await
+    }
+
+    pub async fn delete_object(
+        &self,
+        schema: &Schema,
+        object_id: &str,
+        filter: Option<&Document>,
+    ) -> Result<(), Error> {
+        let filter = object_filter(schema, object_id, filter);
+        let result = self
+            .db
+            .collection(&schema.name)
+            .delete_one(filter, None)
+            .await?;
+        if result.deleted_count == 0 {
+            return Err(Error::NotFound("Object not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn update_relations(
+        &self,
+        schema: &Schema,
+        object_id: &str,
+        relations: Vec<RelationUpdate>,
+    ) -> Result<(), Error> {
+        for relation in relations {
+            let collection = self
+                .db
+                .collection(&format!("_Join:{}:{}", relation.key, schema.name));
+            for id in relation.ids {
+                let join = doc! {"owningId": object_id, "relatedId": id};
+                if relation.add {
+                    let options = UpdateOptions::builder().upsert(true).build();
+                    collection
+                        .update_one(join.clone(), doc! {"$set": join}, options)
+                        .await?;
+                } else {
+                    collection.delete_many(join, None).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn get_schema(&self) -> Result<HashMap<String, Schema>, Error> {
         let collection = self.db.collection("_SCHEMA");
         let mut cursor = collection.find(None, None).await?;
@@ -72,10 +315,310 @@ impl DbAdapter {
             .filter_map(|x| x.ok())
             .collect())
     }
+
+    /// Ids of `owning_class` objects whose `key` relation contains any of `related_ids`.
+    pub async fn find_relation_owners(
+        &self,
+        key: &str,
+        owning_class: &str,
+        related_ids: &[String],
+    ) -> Result<Vec<String>, Error> {
+        let collection = self
+            .db
+            .collection(&format!("_Join:{}:{}", key, owning_class));
+        let filter = doc! {"relatedId": {"$in": related_ids.to_vec()}};
+        let mut cursor = collection.find(filter, None).await?;
+        let mut ids = Vec::new();
+        while let Some(join) = cursor.next().await {
+            if let Ok(id) = join?.get_str("owningId") {
+                ids.push(id.to_string());
+            }
+        }
+        Ok(ids)
+    }
+
+    pub async fn find_role_names(&self, ids: &[String]) -> Result<Vec<String>, Error> {
+        let collection = self.db.collection("_Role");
+        let filter = doc! {"_id": {"$in": ids.to_vec()}};
+        let mut cursor = collection.find(filter, None).await?;
+        let mut names = Vec::new();
+        while let Some(role) = cursor.next().await {
+            if let Ok(name) = role?.get_str("name") {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    pub async fn count_objects(&self, req: &FindRequest, ctx: &Context) -> Result<i64, Error> {
+        let schema = match ctx.cache.get_schema(&ctx.class) {
+            Some(schema) => schema,
+            None => return Ok(0),
+        };
+        let filter = req.filter.as_ref().map(|x| transform_filter(x, &schema));
+        // Count options have no read preference, the collection's default
+        // applies to the aggregate the count runs
+        let options = CollectionOptions::builder()
+            .selection_criteria(selection_criteria(req.read_preference.as_deref()))
+            .build();
+        let count = self
+            .db
+            .collection_with_options(&ctx.class, options)
+            .count_documents(filter, None)
+            .await?;
+        Ok(count)
+    }
+}
+
+/// Maps a Parse read preference such as `SECONDARY_PREFERRED` to mongo's.
+fn selection_criteria(read_preference: Option<&str>) -> Option<SelectionCriteria> {
+    let options = ReadPreferenceOptions::default();
+    let read_preference = match read_preference?.to_uppercase().as_str() {
+        "PRIMARY" => ReadPreference::Primary,
+        "PRIMARY_PREFERRED" => ReadPreference::PrimaryPreferred { options },
+        "SECONDARY" => ReadPreference::Secondary { options },
+        "SECONDARY_PREFERRED" => ReadPreference::SecondaryPreferred { options },
+        "NEAREST" => ReadPreference::Nearest { options },
+        _ => return None,
+    };
+    Some(SelectionCriteria::ReadPreference(read_preference))
+}
+
+/// Converts a REST value (pointers, dates) to its storage representation.
+fn transform_value(value: &bson::Bson) -> bson::Bson {
+    match value {
+        bson::Bson::Document(doc) => match doc.get_str("__type") {
+            Ok("Pointer") => bson::Bson::String(format!(
+                "{}${}",
+                doc.get_str("className").unwrap_or(""),
+                doc.get_str("objectId").unwrap_or("")
+            )),
+            Ok("File") => bson::Bson::String(doc.get_str("name").unwrap_or("").to_string()),
+            Ok("Date") => match DateTime::parse_from_rfc3339(doc.get_str("iso").unwrap_or("")) {
+                Ok(date) => bson::Bson::DateTime(date.with_timezone(&Utc)),
+                Err(_) => value.clone(),
+            },
+            _ => bson::Bson::Document(
+                doc.iter()
+                    .map(|(key, value)| (key.clone(), transform_value(value)))
+                    .collect(),
+            ),
+        },
+        bson::Bson::Array(values) => {
+            bson::Bson::Array(values.iter().map(transform_value).collect())
+        }
+        value => value.clone(),
+    }
+}
+
+fn object_filter(schema: &Schema, object_id: &str, filter: Option<&Document>) -> Document {
+    match filter {
+        Some(filter) => doc! {"$and": [{"_id": object_id}, transform_filter(filter, schema)]},
+        None => doc! {"_id": object_id},
+    }
+}
+
+/// Storage key of a REST field, pointers are stored as `_p_<field>` strings.
+fn storage_key(key: &str, value: &bson::Bson, schema: &Schema) -> String {
+    match key {
+        "objectId" => "_id".to_string(),
+        "createdAt" => "_created_at".to_string(),
+        "updatedAt" => "_updated_at".to_string(),
+        key => {
+            let is_pointer = match schema.fields.get(key) {
+                Some(field) => matches!(field.field_type, FieldType::Pointer),
+                None => is_pointer_constraint(value),
+            };
+            if is_pointer {
+                format!("_p_{}", key)
+            } else {
+                key.to_string()
+            }
+        }
+    }
+}
+
+/// Converts a REST ACL to the `_acl` map and the `_rperm`/`_wperm` arrays used in queries.
+fn transform_acl(acl: &Document, set: &mut Document) {
+    let mut stored = doc! {};
+    let mut rperm = vec![];
+    let mut wperm = vec![];
+    for (key, value) in acl {
+        let permissions = match value.as_document() {
+            Some(permissions) => permissions,
+            None => continue,
+        };
+        let mut entry = doc! {};
+        if permissions.get_bool("read").unwrap_or(false) {
+            entry.insert("r", true);
+            rperm.push(bson::Bson::String(key.clone()));
+        }
+        if permissions.get_bool("write").unwrap_or(false) {
+            entry.insert("w", true);
+            wperm.push(bson::Bson::String(key.clone()));
+        }
+        stored.insert(key, entry);
+    }
+    set.insert("_acl", stored);
+    set.insert("_rperm", rperm);
+    set.insert("_wperm", wperm);
+}
+
+/// Converts REST params, including `__op` operations, to a mongo update document
+/// and the relation changes it carries.
+fn transform_update(params: &Document, schema: &Schema) -> (Document, Vec<RelationUpdate>) {
+    let mut set = doc! {};
+    let mut unset = doc! {};
+    let mut inc = doc! {};
+    let mut push = doc! {};
+    let mut add_to_set = doc! {};
+    let mut pull_all = doc! {};
+    let mut relations = vec![];
+
+    for (key, value) in params {
+        let op = value
+            .as_document()
+            .and_then(|x| x.get_str("__op").ok().map(|op| (op, x)));
+        if let Some((op, value)) = op {
+            let target = storage_key(key, &bson::Bson::Null, schema);
+            let objects = transform_value(value.get("objects").unwrap_or(&bson::Bson::Null));
+            match op {
+                "Delete" => {
+                    unset.insert(target, "");
+                }
+                "Increment" => {
+                    let amount = value.get("amount").cloned().unwrap_or(bson::Bson::Int32(1));
+                    inc.insert(target, amount);
+                }
+                "Add" => {
+                    push.insert(target, doc! {"$each": objects});
+                }
+                "AddUnique" => {
+                    add_to_set.insert(target, doc! {"$each": objects});
+                }
+                "Remove" => {
+                    pull_all.insert(target, objects);
+                }
+                "AddRelation" | "RemoveRelation" => {
+                    let ids = value
+                        .get_array("objects")
+                        .map(|objects| {
+                            objects
+                                .iter()
+                                .filter_map(|x| x.as_document())
+                                .filter_map(|x| x.get_str("objectId").ok())
+                                .map(|x| x.to_string())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    relations.push(RelationUpdate {
+                        key: key.clone(),
+                        add: op == "AddRelation",
+                        ids,
+                    });
+                }
+                _ => warn!("Unsupported operation {} on {}", op, key),
+            }
+            continue;
+        }
+
+        match (key.as_str(), value) {
+            ("ACL", bson::Bson::Document(acl)) => transform_acl(acl, &mut set),
+            ("authData", bson::Bson::Document(auth_data)) => {
+                for (provider, data) in auth_data {
+                    let target = format!("_auth_data_{}", provider);
+                    match data {
+                        bson::Bson::Null => {
+                            unset.insert(target, "");
+                        }
+                        data => {
+                            set.insert(target, data.clone());
+                        }
+                    }
+                }
+            }
+            (key, value) => {
+                set.insert(storage_key(key, value, schema), transform_value(value));
+            }
+        }
+    }
+
+    let mut update = doc! {};
+    for (operator, fields) in [
+        ("$set", set),
+        ("$unset", unset),
+        ("$inc", inc),
+        ("$push", push),
+        ("$addToSet", add_to_set),
+        ("$pullAll", pull_all),
+    ] {
+        if !fields.is_empty() {
+            update.insert(operator, fields);
+        }
+    }
+    (update, relations)
+}
+
+fn is_pointer_constraint(value: &bson::Bson) -> bool {
+    match value {
+        bson::Bson::Document(doc) => {
+            doc.get_str("__type") == Ok("Pointer")
+                || doc.values().any(|value| match value {
+                    bson::Bson::Array(values) => values.iter().any(is_pointer_constraint),
+                    value => is_pointer_constraint(value),
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Converts a REST `where` clause to a mongo filter.
+fn transform_filter(filter: &Document, schema: &Schema) -> Document {
+    let mut result = Document::new();
+    for (key, value) in filter {
+        match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let values = value
+                    .as_array()
+                    .map(|values| {
+                        values
+                            .iter()
+                            .filter_map(|x| x.as_document())
+                            .map(|x| bson::Bson::Document(transform_filter(x, schema)))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                result.insert(key, bson::Bson::Array(values));
+            }
+            "objectId" => {
+                result.insert("_id", transform_value(value));
+            }
+            "createdAt" => {
+                result.insert("_created_at", transform_value(value));
+            }
+            "updatedAt" => {
+                result.insert("_updated_at", transform_value(value));
+            }
+            name => {
+                let is_pointer = match schema.fields.get(name) {
+                    Some(field) => matches!(field.field_type, FieldType::Pointer),
+                    None => is_pointer_constraint(value),
+                };
+                if is_pointer {
+                    result.insert(format!("_p_{}", name), transform_value(value));
+                } else {
+                    result.insert(name, transform_value(value));
+                }
+            }
+        }
+    }
+    result
 }
 
 fn bson_to_bool_map(value: Option<&bson::Bson>) -> HashMap<String, bool> {
     match value {
+        // Operations missing from the schema default to public access
+        None => public_permission(),
         Some(value) => {
             let empty = doc! {};
             let doc = value.as_document().unwrap_or(&empty);
@@ -84,13 +627,38 @@ fn bson_to_bool_map(value: Option<&bson::Bson>) -> HashMap<String, bool> {
                 map
             })
         }
-        None => HashMap::new(),
     }
 }
 
+fn bson_to_string_vec(value: Option<&bson::Bson>) -> Vec<String> {
+    value
+        .and_then(|x| x.as_array())
+        .map(|array| {
+            array
+                .iter()
+                .filter_map(|x| x.as_str())
+                .map(|x| x.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_protected_fields(value: Option<&bson::Bson>) -> HashMap<String, Vec<String>> {
+    let empty = doc! {};
+    let doc = value.and_then(|x| x.as_document()).unwrap_or(&empty);
+    doc.iter().fold(HashMap::new(), |mut map, (key, val)| {
+        map.insert(key.clone(), bson_to_string_vec(Some(val)));
+        map
+    })
+}
+
 fn parse_permissions(data: &bson::Document) -> Permissions {
     let empty = doc! {};
     let metadata = data.get_document("_metadata").unwrap_or(&empty);
+    // Parse Server keeps CLPs under `class_permissions`, older schemas inline them
+    let metadata = metadata
+        .get_document("class_permissions")
+        .unwrap_or(metadata);
     Permissions {
         add_field: bson_to_bool_map(metadata.get("addField")),
         count: bson_to_bool_map(metadata.get("count")),
@@ -98,8 +666,10 @@ fn parse_permissions(data: &bson::Document) -> Permissions {
         delete: bson_to_bool_map(metadata.get("delete")),
         find: bson_to_bool_map(metadata.get("find")),
         get: bson_to_bool_map(metadata.get("get")),
-        protected_fields: bson_to_bool_map(metadata.get("protectedFields")),
+        protected_fields: parse_protected_fields(metadata.get("protectedFields")),
         update: bson_to_bool_map(metadata.get("update")),
+        read_user_fields: bson_to_string_vec(metadata.get("readUserFields")),
+        write_user_fields: bson_to_string_vec(metadata.get("writeUserFields")),
     }
 }
 
@@ -112,31 +682,7 @@ fn parse_field(key: &String, field: &bson::Bson, fields_options: &bson::Document
         Some(v) => Some(v.clone()),
         None => None,
     };
-    let (field_type, target_type) = match field.as_str().unwrap_or("") {
-        "number" => (FieldType::Number, None),
-        "string" => (FieldType::String, None),
-        "boolean" => (FieldType::Boolean, None),
-        "date" => (FieldType::Date, None),
-        "map" => (FieldType::Object, None),
-        "object" => (FieldType::Object, None),
-        "array" => (FieldType::Array, None),
-        "geopoint" => (FieldType::GeoPoint, None),
-        "file" => (FieldType::File, None),
-        "bytes" => (FieldType::Bytes, None),
-        "polygon" => (FieldType::Polygon, None),
-        field_type => {
-            if &field_type[..1] == "*" {
-                (FieldType::Pointer, Some(String::from(&field_type[1..])))
-            } else if &field_type[..9] == "relation<" {
-                (
-                    FieldType::Relation,
-                    Some(String::from(&field_type[9..field_type.len() - 1])),
-                )
-            } else {
-                (FieldType::Unknown, None)
-            }
-        }
-    };
+    let (field_type, target_type) = parse_type(field.as_str().unwrap_or(""));
     Field {
         name: key.clone(),
         field_type: field_type,
@@ -175,7 +721,7 @@ fn parse_indexes(data: &bson::Document) -> HashMap<String, bool> {
         })
 }
 
-fn parse_document(data: &bson::Document, schema: &Schema) -> bson::Document {
+fn parse_document(data: &bson::Document, schema: &Schema, config: &Config) -> bson::Document {
     let mut document = bson::Document::new();
     for (_, field) in &schema.fields {
         match field.field_type {
@@ -202,15 +748,16 @@ fn parse_document(data: &bson::Document, schema: &Schema) -> bson::Document {
                 );
             }
             FieldType::File => {
-                let value = data.get_str(&field.name).unwrap_or("");
-                document.insert(
-                    &field.name,
-                    doc! {
-                        "__type": "File",
-                        "name": value,
-                        "url": format!("http://localhost:5000/parse/files/{}", value)
-                    },
-                );
+                if let Ok(value) = data.get_str(&field.name) {
+                    document.insert(
+                        &field.name,
+                        doc! {
+                            "__type": "File",
+                            "name": value,
+                            "url": files::file_url(config, value)
+                        },
+                    );
+                }
             }
             _ => {
                 let mut insert_if_value = |value: Option<&bson::Bson>| {
@@ -251,6 +798,33 @@ fn parse_document(data: &bson::Document, schema: &Schema) -> bson::Document {
             }
         };
     }
+
+    if let Ok(acl) = data.get_document("_acl") {
+        let acl: Document = acl
+            .iter()
+            .filter_map(|(key, value)| value.as_document().map(|x| (key, x)))
+            .map(|(key, permissions)| {
+                let mut entry = doc! {};
+                if permissions.get_bool("r").unwrap_or(false) {
+                    entry.insert("read", true);
+                }
+                if permissions.get_bool("w").unwrap_or(false) {
+                    entry.insert("write", true);
+                }
+                (key.clone(), bson::Bson::Document(entry))
+            })
+            .collect();
+        document.insert("ACL", acl);
+    }
+
+    let auth_data: Document = data
+        .iter()
+        .filter(|(key, _)| key.starts_with("_auth_data_"))
+        .map(|(key, value)| (key["_auth_data_".len()..].to_string(), value.clone()))
+        .collect();
+    if !auth_data.is_empty() {
+        document.insert("authData", auth_data);
+    }
     document
 }
 
@@ -271,18 +845,18 @@ async fn query_objects(
         .limit(req.limit)
         .sort(req.sort.as_ref().map(|x| x.clone()))
         .skip(req.skip)
+        .selection_criteria(selection_criteria(req.read_preference.as_deref()))
         .build();
 
-    let mut cursor = collection
-        .find(req.filter.as_ref().map(|x| x.clone()), find_options)
-        .await?;
+    let filter = req.filter.as_ref().map(|x| transform_filter(x, &schema));
+    let mut cursor = collection.find(filter, find_options).await?;
 
     let mut results = Vec::new();
 
     while let Some(result) = cursor.next().await {
         match result {
             Ok(doc) => {
-                let parsed = parse_document(&doc, &schema);
+                let parsed = parse_document(&doc, &schema, &ctx.config);
                 results.push(parsed);
             }
             Err(err) => {
@@ -325,10 +899,12 @@ async fn join_pointers(
             options: FindRequest {
                 include: vec![],
                 filter: None,
-                join: vec![],
                 limit: None,
                 skip: None,
                 sort: None,
+                count: false,
+                keys: vec![],
+                read_preference: None,
             },
         })
         .chain(vec![])
@@ -345,15 +921,13 @@ async fn join_pointers(
         .iter()
         .map(|join| join_pointer(db, join, &parent, &schema, req, ctx));
 
-    match join_all(futures)
-        .await
-        .into_iter()
-        .filter_map(|x| x.err())
-        .nth(0)
-    {
-        Some(err) => Err(err),
-        None => Ok(parent),
+    let mut joined = parent.clone();
+    for result in join_all(futures).await {
+        if let Some((key, document)) = result? {
+            joined.insert(key, document);
+        }
     }
+    Ok(joined)
 }
 
 async fn join_pointer(
@@ -363,7 +937,7 @@ async fn join_pointer(
     source_schema: &Schema,
     req: &FindRequest,
     ctx: &Context,
-) -> Result<Document, Error> {
+) -> Result<Option<(String, Document)>, Error> {
     debug!("Fetching pointer for {}", join.pointer_key);
     let empty = doc! {};
     let id = parent
@@ -372,23 +946,41 @@ async fn join_pointer(
         .get_str("objectId")
         .unwrap_or("");
     if id == "" {
-        return Ok(doc! {});
+        return Ok(None);
     }
-    let req = FindRequest {
+    // The pointed to class has its own permissions, pointers the user can't
+    // read are left as they are
+    let ctx = &ctx.for_class(&join.pointer_type);
+    let mut get = Request::Get(GetRequest {
+        objectId: id.to_string(),
+        include: vec![],
         filter: None,
+        keys: vec![],
+        read_preference: None,
+    });
+    if let Err(e) = operation::enforce_read_permissions(&mut get, ctx) {
+        debug!("Not joining pointer {}: {}", join.pointer_key, e);
+        return Ok(None);
+    }
+    let mut filter = doc! {"objectId": id};
+    if let Request::Get(GetRequest {
+        filter: Some(constraint),
+        ..
+    }) = get
+    {
+        filter = doc! {"$and": [filter, constraint]};
+    }
+    let req = FindRequest {
+        filter: Some(filter),
         limit: Some(1),
         skip: None,
         sort: None,
-        join: vec![],
         include: vec![],
+        count: false,
+        keys: vec![],
+        read_preference: None,
     };
-    let ctx = &Context {
-        class: join.pointer_type.clone(),
-        user: ctx.user.clone(),
-        db: ctx.db.clone(),
-        cache: ctx.cache.clone(),
-    };
-    match query_objects(db, &req, &ctx).await {
+    match query_objects(db, &req, ctx).await {
         Ok(mut results) => {
             debug!(
                 "Joined pointer: {} = {} #{}, {}",
@@ -397,12 +989,11 @@ async fn join_pointer(
                 id,
                 results.len()
             );
-            results.pop().map(|x| (join.pointer_key.clone(), x));
-            Ok(doc! {})
+            Ok(results.pop().map(|x| (join.pointer_key.clone(), x)))
         }
         Err(e) => {
-            error!("Could not join pointer: {}", e.to_string());
-            Ok(doc! {})
+            error!("Could not join pointer: {}", e);
+            Ok(None)
         }
     }
 }
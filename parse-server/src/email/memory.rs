@@ -0,0 +1,37 @@
+use super::{EmailAdapter, Mail};
+use crate::error::Error;
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+
+/// Keeps sent mails in memory instead of delivering them. Clones share the
+/// same outbox, so a test can keep one and pass the other to the server.
+#[derive(Clone, Default)]
+pub struct InMemoryEmailAdapter {
+    outbox: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl InMemoryEmailAdapter {
+    pub fn new() -> Self {
+        InMemoryEmailAdapter::default()
+    }
+
+    pub fn sent(&self) -> Vec<Mail> {
+        self.outbox.lock().expect("Mutex poisoned").clone()
+    }
+
+    pub fn clear(&self) {
+        self.outbox.lock().expect("Mutex poisoned").clear();
+    }
+}
+
+#[async_trait(?Send)]
+impl EmailAdapter for InMemoryEmailAdapter {
+    async fn send_mail(&self, mail: &Mail) -> Result<(), Error> {
+        debug!("Storing mail to {}: {}", mail.to, mail.subject);
+        self.outbox
+            .lock()
+            .expect("Mutex poisoned")
+            .push(mail.clone());
+        Ok(())
+    }
+}
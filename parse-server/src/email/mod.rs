@@ -0,0 +1,22 @@
+use crate::error::Error;
+use async_trait::async_trait;
+
+mod memory;
+mod smtp;
+
+pub use memory::InMemoryEmailAdapter;
+pub use smtp::SmtpAdapter;
+
+/// A plain text email.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Delivers the verification and password reset emails.
+#[async_trait(?Send)]
+pub trait EmailAdapter: Send + Sync {
+    async fn send_mail(&self, mail: &Mail) -> Result<(), Error>;
+}
@@ -0,0 +1,112 @@
+use super::{EmailAdapter, Mail};
+use crate::error::Error;
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Sends mail through an SMTP relay over a plain connection, optionally with
+/// `AUTH PLAIN`. TLS is left to a local relay or sink.
+#[derive(Clone)]
+pub struct SmtpAdapter {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn smtp_error<E: ToString>(e: E) -> Error {
+    Error::Internal(format!("SMTP error: {}", e.to_string()))
+}
+
+/// Reads a possibly multi-line reply and checks its status code.
+async fn read_reply(stream: &mut BufReader<TcpStream>, expected: u16) -> Result<(), Error> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.map_err(smtp_error)? == 0 {
+            return Err(smtp_error("connection closed"));
+        }
+        let code = line.get(0..3).and_then(|x| x.parse::<u16>().ok());
+        if code != Some(expected) {
+            return Err(smtp_error(line.trim_end()));
+        }
+        // "250-" continues the reply, "250 " ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn command(
+    stream: &mut BufReader<TcpStream>,
+    command: &str,
+    expected: u16,
+) -> Result<(), Error> {
+    trace!("SMTP OUT: {}", command);
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .await
+        .map_err(smtp_error)?;
+    read_reply(stream, expected).await
+}
+
+/// Headers and dot-stuffed body, terminated by the lone `.` line.
+fn message(from: &str, mail: &Mail) -> String {
+    let mut data = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        from, mail.to, mail.subject
+    );
+    for line in mail.text.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    data
+}
+
+impl SmtpAdapter {
+    pub fn new(host: &str, port: u16, from: &str) -> Self {
+        SmtpAdapter {
+            host: host.to_string(),
+            port,
+            from: from.to_string(),
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn credentials(mut self, username: &str, password: &str) -> Self {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl EmailAdapter for SmtpAdapter {
+    async fn send_mail(&self, mail: &Mail) -> Result<(), Error> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(smtp_error)?;
+        let mut stream = BufReader::new(stream);
+
+        read_reply(&mut stream, 220).await?;
+        command(&mut stream, "EHLO localhost", 250).await?;
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            command(&mut stream, &format!("AUTH PLAIN {}", token), 235).await?;
+        }
+        command(&mut stream, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        command(&mut stream, &format!("RCPT TO:<{}>", mail.to), 250).await?;
+        command(&mut stream, "DATA", 354).await?;
+        command(&mut stream, &message(&self.from, mail), 250).await?;
+        command(&mut stream, "QUIT", 221).await?;
+
+        debug!("Sent mail to {}: {}", mail.to, mail.subject);
+        Ok(())
+    }
+}
@@ -1,45 +1,135 @@
 use actix_web::HttpResponse;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum Error {
+    OtherCause(String),
     Internal(String),
     NotFound(String),
     BadFormat(String),
     Forbidden(String),
+    InvalidKeyName(String),
+    InvalidFileName(String),
+    InvalidEmailAddress(String),
+    InvalidInstallationId(String),
+    MissingRequiredField(String),
+    ChangedImmutableField(String),
+    PushMisconfigured(String),
+    FileTooLarge(String),
+    FileSaveError(String),
+    FileDeleteError(String),
+    ScriptFailed(String),
+    ValidationError(String),
+    WebhookError(String),
+    DuplicateRequest(String),
+    UsernameMissing(String),
+    PasswordMissing(String),
+    UsernameTaken(String),
+    EmailTaken(String),
+    EmailMissing(String),
+    EmailNotFound(String),
+    AccountAlreadyLinked(String),
+    InvalidSessionToken(String),
+    MfaError(String),
+    MfaTokenRequired(String),
+    UnsupportedService(String),
 }
 
 #[inline]
 fn format_json(code: i32, message: &String) -> String {
-    format!(r#"{{"code":{},"message": "{}"}}"#, code, message)
+    serde_json::json!({"code": code, "message": message}).to_string()
 }
 
 impl Error {
-    pub fn to_json(&self) -> String {
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::OtherCause(_) => -1,
+            Error::Internal(_) => 100,
+            Error::NotFound(_) => 101,
+            Error::BadFormat(_) => 102,
+            Error::InvalidKeyName(_) => 105,
+            Error::InvalidFileName(_) => 122,
+            Error::Forbidden(_) => 119,
+            Error::InvalidEmailAddress(_) => 125,
+            Error::InvalidInstallationId(_) => 132,
+            Error::MissingRequiredField(_) => 135,
+            Error::ChangedImmutableField(_) => 136,
+            Error::PushMisconfigured(_) => 115,
+            Error::FileTooLarge(_) => 129,
+            Error::FileSaveError(_) => 130,
+            Error::FileDeleteError(_) => 153,
+            Error::ScriptFailed(_) => 141,
+            Error::ValidationError(_) => 142,
+            Error::WebhookError(_) => 143,
+            Error::DuplicateRequest(_) => 159,
+            Error::UsernameMissing(_) => 200,
+            Error::PasswordMissing(_) => 201,
+            Error::UsernameTaken(_) => 202,
+            Error::EmailTaken(_) => 203,
+            Error::EmailMissing(_) => 204,
+            Error::EmailNotFound(_) => 205,
+            Error::AccountAlreadyLinked(_) => 208,
+            Error::InvalidSessionToken(_) => 209,
+            Error::MfaError(_) => 210,
+            Error::MfaTokenRequired(_) => 211,
+            Error::UnsupportedService(_) => 252,
+        }
+    }
+
+    pub fn message(&self) -> &String {
         match self {
-            Error::Internal(message) => format_json(100, message),
-            Error::NotFound(message) => format_json(101, message),
-            Error::BadFormat(message) => format_json(102, message),
-            Error::Forbidden(message) => format_json(119, message),
+            Error::OtherCause(message)
+            | Error::Internal(message)
+            | Error::NotFound(message)
+            | Error::BadFormat(message)
+            | Error::Forbidden(message)
+            | Error::InvalidKeyName(message)
+            | Error::InvalidFileName(message)
+            | Error::InvalidEmailAddress(message)
+            | Error::InvalidInstallationId(message)
+            | Error::MissingRequiredField(message)
+            | Error::ChangedImmutableField(message)
+            | Error::PushMisconfigured(message)
+            | Error::FileTooLarge(message)
+            | Error::FileSaveError(message)
+            | Error::FileDeleteError(message)
+            | Error::ScriptFailed(message)
+            | Error::ValidationError(message)
+            | Error::WebhookError(message)
+            | Error::DuplicateRequest(message)
+            | Error::UsernameMissing(message)
+            | Error::PasswordMissing(message)
+            | Error::UsernameTaken(message)
+            | Error::EmailTaken(message)
+            | Error::EmailMissing(message)
+            | Error::EmailNotFound(message)
+            | Error::AccountAlreadyLinked(message)
+            | Error::InvalidSessionToken(message)
+            | Error::MfaError(message)
+            | Error::MfaTokenRequired(message)
+            | Error::UnsupportedService(message) => message,
         }
     }
 
+    pub fn to_json(&self) -> String {
+        format_json(self.code(), self.message())
+    }
+
     pub fn to_http_response(&self) -> HttpResponse {
         match self {
-            Error::Internal(message) => HttpResponse::BadRequest().body(self.to_json()),
-            Error::NotFound(message) => HttpResponse::NotFound().body(self.to_json()),
-            Error::BadFormat(message) => HttpResponse::BadRequest().body(self.to_json()),
-            Error::Forbidden(message) => HttpResponse::BadRequest().body(self.to_json()),
+            Error::NotFound(_) => HttpResponse::NotFound().body(self.to_json()),
+            _ => HttpResponse::BadRequest().body(self.to_json()),
         }
     }
 }
 
-impl ToString for Error {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            Error::Internal(message) => format!("Internal Error: {}", message),
-            Error::NotFound(message) => format!("Not Found: {}", message),
-            Error::BadFormat(message) => format!("Bad Format: {}", message),
-            Error::Forbidden(message) => format!("Permission Denied: {}", message),
+            Error::Internal(message) => write!(f, "Internal Error: {}", message),
+            Error::NotFound(message) => write!(f, "Not Found: {}", message),
+            Error::BadFormat(message) => write!(f, "Bad Format: {}", message),
+            Error::Forbidden(message) => write!(f, "Permission Denied: {}", message),
+            error => write!(f, "Error {}: {}", error.code(), error.message()),
         }
     }
 }
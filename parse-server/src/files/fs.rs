@@ -0,0 +1,174 @@
+use super::{FileInfo, FileStream, FilesAdapter};
+use crate::error::Error;
+use actix_web::error::BlockingError;
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+/// Bytes read from disk at a time for downloads.
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Keeps files in a directory of the local file system. Content types are
+/// not stored, downloads guess them from the file extension.
+#[derive(Clone)]
+pub struct FileSystemAdapter {
+    pub dir: PathBuf,
+}
+
+fn file_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound("File not found.".to_string()),
+        _ => Error::Internal(e.to_string()),
+    }
+}
+
+/// Runs file system calls on the thread pool, not on the server's threads.
+async fn blocking<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => file_error(e),
+        BlockingError::Canceled => Error::Internal(e.to_string()),
+    })
+}
+
+impl FileSystemAdapter {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileSystemAdapter { dir: dir.into() }
+    }
+
+    /// The path of a file, names must stay inside the directory.
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.dir.join(name)),
+            _ => Err(Error::InvalidFileName(
+                "Filename contains invalid characters.".to_string(),
+            )),
+        }
+    }
+
+    async fn write_file(&self, path: &Path, mut data: FileStream) -> Result<(), Error> {
+        let (dir, path) = (self.dir.clone(), path.to_path_buf());
+        let mut file = blocking(move || {
+            std::fs::create_dir_all(dir)?;
+            File::create(path)
+        })
+        .await?;
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk?;
+            file = blocking(move || file.write_all(&chunk).map(|_| file)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesAdapter for FileSystemAdapter {
+    async fn create_file(&self, name: &str, data: FileStream, _: &str) -> Result<(), Error> {
+        let path = self.path(name)?;
+        let result = self.write_file(&path, data).await;
+        if result.is_err() {
+            let _ = blocking(move || std::fs::remove_file(path)).await;
+        }
+        result
+    }
+
+    async fn delete_file(&self, name: &str) -> Result<(), Error> {
+        let path = self.path(name)?;
+        blocking(move || std::fs::remove_file(path)).await
+    }
+
+    async fn file_info(&self, name: &str) -> Result<FileInfo, Error> {
+        let path = self.path(name)?;
+        let metadata = blocking(move || std::fs::metadata(path)).await?;
+        Ok(FileInfo {
+            length: metadata.len(),
+            content_type: None,
+        })
+    }
+
+    async fn file_data(&self, name: &str, range: Range<u64>) -> Result<FileStream, Error> {
+        let (path, start) = (self.path(name)?, range.start);
+        let file = blocking(move || {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(start))?;
+            Ok(file)
+        })
+        .await?;
+        // Reads a chunk at a time, the file is dropped after an error
+        let chunks = stream::unfold(Some((file, range.end - range.start)), |state| async {
+            let (mut file, remaining) = state.filter(|(_, remaining)| *remaining > 0)?;
+            let size = remaining.min(READ_CHUNK_SIZE);
+            let read = blocking(move || {
+                let mut chunk = vec![0; size as usize];
+                file.read_exact(&mut chunk)?;
+                Ok((file, chunk))
+            })
+            .await;
+            Some(match read {
+                Ok((file, chunk)) => (Ok(Bytes::from(chunk)), Some((file, remaining - size))),
+                Err(e) => (Err(e), None),
+            })
+        });
+        Ok(chunks.boxed_local())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+    use actix_web::rt::System;
+    use futures::TryStreamExt;
+
+    fn upload(data: Vec<u8>) -> FileStream {
+        let chunks: Vec<Result<Bytes, Error>> = data
+            .chunks(1000)
+            .map(|x| Ok(Bytes::copy_from_slice(x)))
+            .collect();
+        stream::iter(chunks).boxed_local()
+    }
+
+    #[test]
+    fn streams_ranges_of_stored_files() {
+        let dir = std::env::temp_dir().join(format!("files-{}", util::new_object_id()));
+        let adapter = FileSystemAdapter::new(&dir);
+        // Longer than a read chunk, so downloads take several reads
+        let data: Vec<u8> = (0..(READ_CHUNK_SIZE + 5000)).map(|x| x as u8).collect();
+        System::new("test").block_on(async move {
+            let created = adapter.create_file("a.bin", upload(data.clone()), "").await;
+            assert!(created.is_ok());
+            let info = adapter.file_info("a.bin").await.unwrap();
+            assert_eq!(info.length, data.len() as u64);
+
+            let read = |range: Range<u64>| async {
+                let chunks = adapter.file_data("a.bin", range).await?;
+                let chunks: Vec<Bytes> = chunks.try_collect().await?;
+                Ok::<_, Error>(chunks)
+            };
+            let chunks = read(0..info.length).await.unwrap();
+            assert_eq!(chunks.len(), 2);
+            assert_eq!(chunks.concat(), data);
+            let chunks = read(10..20).await.unwrap();
+            assert_eq!(chunks.concat(), &data[10..20]);
+            // Past the end of the file
+            assert!(read(info.length - 1..info.length + 1).await.is_err());
+
+            adapter.delete_file("a.bin").await.unwrap();
+            match adapter.file_info("a.bin").await {
+                Err(Error::NotFound(_)) => {}
+                _ => panic!("the file was not deleted"),
+            }
+            assert!(adapter.file_data("a.bin", 0..1).await.is_err());
+            assert!(adapter.file_info("../a.bin").await.is_err());
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use super::{FileInfo, FileStream, FilesAdapter};
+use crate::error::Error;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use bson::oid::ObjectId;
+use bson::spec::BinarySubtype;
+use bson::{doc, Binary, Bson, Document};
+use chrono::Utc;
+use futures::future::ready;
+use futures::stream::{self, StreamExt};
+use mongodb::options::{ClientOptions, FindOneOptions, FindOptions};
+use mongodb::{Client, Database};
+use std::ops::Range;
+
+/// Size of the `fs.chunks` documents, the GridFS default.
+const CHUNK_SIZE: usize = 255 * 1024;
+
+/// Keeps files in the `fs.files` and `fs.chunks` collections of a mongo
+/// database, readable by other GridFS clients.
+#[derive(Clone)]
+pub struct GridFsAdapter {
+    db: Database,
+}
+
+impl GridFsAdapter {
+    /// Uses the database of a connection string, like `DbAdapter` does.
+    pub async fn connect(uri: &str) -> Result<GridFsAdapter, Error> {
+        let client = Client::with_options(ClientOptions::parse(uri).await?)?;
+        let name = uri
+            .rsplit('/')
+            .next()
+            .and_then(|x| x.split('?').next())
+            .filter(|x| !x.is_empty() && !x.contains(':'))
+            .unwrap_or("parse");
+        Ok(GridFsAdapter {
+            db: client.database(name),
+        })
+    }
+
+    /// Creates the indexes GridFS readers expect.
+    pub async fn create_indexes(&self) -> Result<(), Error> {
+        self.db
+            .run_command(
+                doc! {"createIndexes": "fs.files", "indexes": [
+                    {"key": {"filename": 1, "uploadDate": 1}, "name": "filename_1_uploadDate_1"},
+                ]},
+                None,
+            )
+            .await?;
+        self.db
+            .run_command(
+                doc! {"createIndexes": "fs.chunks", "indexes": [
+                    {"key": {"files_id": 1, "n": 1}, "name": "files_id_1_n_1", "unique": true},
+                ]},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_chunk(&self, file_id: &ObjectId, n: i32, data: Vec<u8>) -> Result<(), Error> {
+        let chunk = doc! {
+            "files_id": file_id.clone(),
+            "n": n,
+            "data": Binary {subtype: BinarySubtype::Generic, bytes: data},
+        };
+        self.db
+            .collection("fs.chunks")
+            .insert_one(chunk, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn write_chunks(&self, file_id: &ObjectId, mut data: FileStream) -> Result<u64, Error> {
+        let mut length = 0;
+        let mut n = 0;
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+        while let Some(bytes) = data.next().await {
+            let mut bytes = &bytes?[..];
+            length += bytes.len() as u64;
+            while !bytes.is_empty() {
+                let take = bytes.len().min(CHUNK_SIZE - buffer.len());
+                buffer.extend_from_slice(&bytes[..take]);
+                bytes = &bytes[take..];
+                if buffer.len() == CHUNK_SIZE {
+                    let chunk = std::mem::replace(&mut buffer, Vec::with_capacity(CHUNK_SIZE));
+                    self.insert_chunk(file_id, n, chunk).await?;
+                    n += 1;
+                }
+            }
+        }
+        if !buffer.is_empty() {
+            self.insert_chunk(file_id, n, buffer).await?;
+        }
+        Ok(length)
+    }
+
+    /// The latest `fs.files` entry of a name.
+    async fn find_file(&self, name: &str) -> Result<Document, Error> {
+        let options = FindOneOptions::builder()
+            .sort(doc! {"uploadDate": -1})
+            .build();
+        self.db
+            .collection("fs.files")
+            .find_one(doc! {"filename": name}, options)
+            .await?
+            .ok_or_else(|| Error::NotFound("File not found.".to_string()))
+    }
+}
+
+fn as_u64(value: Option<&Bson>) -> u64 {
+    match value {
+        Some(Bson::Int32(value)) => *value as u64,
+        Some(Bson::Int64(value)) => *value as u64,
+        Some(Bson::Double(value)) => *value as u64,
+        _ => 0,
+    }
+}
+
+#[async_trait(?Send)]
+impl FilesAdapter for GridFsAdapter {
+    async fn create_file(
+        &self,
+        name: &str,
+        data: FileStream,
+        content_type: &str,
+    ) -> Result<(), Error> {
+        let file_id = ObjectId::new();
+        let length = match self.write_chunks(&file_id, data).await {
+            Ok(length) => length,
+            Err(e) => {
+                let filter = doc! {"files_id": file_id};
+                let _ = self
+                    .db
+                    .collection("fs.chunks")
+                    .delete_many(filter, None)
+                    .await;
+                return Err(e);
+            }
+        };
+        let file = doc! {
+            "_id": file_id,
+            "length": length as i64,
+            "chunkSize": CHUNK_SIZE as i32,
+            "uploadDate": Utc::now(),
+            "filename": name,
+            "contentType": content_type,
+        };
+        self.db
+            .collection("fs.files")
+            .insert_one(file, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_file(&self, name: &str) -> Result<(), Error> {
+        let mut cursor = self
+            .db
+            .collection("fs.files")
+            .find(doc! {"filename": name}, None)
+            .await?;
+        let mut ids = vec![];
+        while let Some(file) = cursor.next().await {
+            if let Some(id) = file?.get("_id") {
+                ids.push(id.clone());
+            }
+        }
+        if ids.is_empty() {
+            return Err(Error::NotFound("File not found.".to_string()));
+        }
+        self.db
+            .collection("fs.chunks")
+            .delete_many(doc! {"files_id": {"$in": ids.clone()}}, None)
+            .await?;
+        self.db
+            .collection("fs.files")
+            .delete_many(doc! {"_id": {"$in": ids}}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn file_info(&self, name: &str) -> Result<FileInfo, Error> {
+        let file = self.find_file(name).await?;
+        Ok(FileInfo {
+            length: as_u64(file.get("length")),
+            content_type: file.get_str("contentType").ok().map(String::from),
+        })
+    }
+
+    async fn file_data(&self, name: &str, range: Range<u64>) -> Result<FileStream, Error> {
+        let file = self.find_file(name).await?;
+        let chunk_size = as_u64(file.get("chunkSize")).max(1);
+        let first = range.start / chunk_size;
+        let last = range.end.saturating_sub(1) / chunk_size;
+        let filter = doc! {
+            "files_id": file.get("_id").cloned().unwrap_or(Bson::Null),
+            "n": {"$gte": first as i64, "$lte": last as i64},
+        };
+        let options = FindOptions::builder().sort(doc! {"n": 1}).build();
+        let mut cursor = self
+            .db
+            .collection("fs.chunks")
+            .find(filter, options)
+            .await?;
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        while let Some(chunk) = cursor.next().await {
+            let chunk = chunk?;
+            let bytes = chunk
+                .get_binary_generic("data")
+                .map_err(|_| Error::Internal(format!("Chunk of {} is invalid", name)))?;
+            data.extend_from_slice(bytes);
+        }
+        let offset = (range.start - first * chunk_size) as usize;
+        let end = offset + (range.end - range.start) as usize;
+        let data = data
+            .get(offset..end)
+            .map(Bytes::copy_from_slice)
+            .ok_or_else(|| Error::Internal(format!("Chunks of {} are missing", name)))?;
+        Ok(stream::once(ready(Ok(data))).boxed_local())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::config::Config;
+use crate::error::Error;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::ops::Range;
+
+mod fs;
+mod gridfs;
+mod s3;
+
+pub use fs::FileSystemAdapter;
+pub use gridfs::GridFsAdapter;
+pub use s3::S3Adapter;
+
+/// The content of a file, uploads are read as they arrive and downloads as
+/// they are sent.
+pub type FileStream = LocalBoxStream<'static, Result<Bytes, Error>>;
+
+/// Characters kept as they are in urls, the rest are percent-encoded.
+const URL_SAFE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A stored file.
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub length: u64,
+    /// The type given on upload, when the adapter keeps it.
+    pub content_type: Option<String>,
+}
+
+/// Stores the files uploaded through `/parse/files`.
+#[async_trait(?Send)]
+pub trait FilesAdapter: Send + Sync {
+    /// Stores `data` under `name`, which is already unique.
+    async fn create_file(
+        &self,
+        name: &str,
+        data: FileStream,
+        content_type: &str,
+    ) -> Result<(), Error>;
+
+    async fn delete_file(&self, name: &str) -> Result<(), Error>;
+
+    /// Fails with `NotFound` when there is no such file.
+    async fn file_info(&self, name: &str) -> Result<FileInfo, Error>;
+
+    /// The bytes of `range`, which lies within the file.
+    async fn file_data(&self, name: &str, range: Range<u64>) -> Result<FileStream, Error>;
+
+    /// The url of the file in `{"__type": "File"}` values, the download route
+    /// of the server by default.
+    fn file_location(&self, config: &Config, name: &str) -> String {
+        server_location(config, name)
+    }
+}
+
+/// The `/parse/files/{appId}/{name}` url of a file.
+pub fn server_location(config: &Config, name: &str) -> String {
+    format!(
+        "{}/files/{}/{}",
+        config.public_server_url(),
+        encode_url_part(&config.app_id),
+        encode_url_part(name)
+    )
+}
+
+pub fn encode_url_part(part: &str) -> String {
+    utf8_percent_encode(part, URL_SAFE).to_string()
+}
+
+/// The url clients get for a stored file.
+pub fn file_url(config: &Config, name: &str) -> String {
+    match &config.files {
+        Some(files) => files.file_location(config, name),
+        None => server_location(config, name),
+    }
+}
+
+/// Whether a file name only has the characters Parse allows.
+pub fn is_valid_file_name(name: &str) -> bool {
+    lazy_static! {
+        static ref FILE_NAME: regex::Regex =
+            regex::Regex::new(r"^[_a-zA-Z0-9][a-zA-Z0-9@. ~_-]*$").unwrap();
+    }
+    name.len() <= 128 && FILE_NAME.is_match(name)
+}
+
+/// Content type of a file from its extension.
+pub fn guess_content_type(name: &str) -> &'static str {
+    let extension = match name.rsplit_once('.') {
+        Some((_, extension)) => extension.to_ascii_lowercase(),
+        None => String::new(),
+    };
+    match extension.as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
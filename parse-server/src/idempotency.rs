@@ -0,0 +1,56 @@
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::util;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use bson::doc;
+use chrono::{Duration, Utc};
+
+const CLASS_NAME: &str = "_Idempotency";
+
+/// Makes request ids unique and lets mongo drop them once they expire.
+pub async fn create_indexes(db: &DbAdapter) -> Result<(), Error> {
+    let indexes = vec![
+        doc! {"key": {"reqId": 1}, "name": "reqId_1", "unique": true},
+        doc! {"key": {"expire": 1}, "name": "expire_1", "expireAfterSeconds": 0},
+    ];
+    db.create_indexes(CLASS_NAME, indexes).await
+}
+
+/// Stores the `X-Parse-Request-Id` of writes to the configured paths in
+/// `_Idempotency`, failing when the same id was sent before.
+pub async fn enforce(req: &HttpRequest, config: &Config, db: &DbAdapter) -> Result<(), Error> {
+    let options = match &config.idempotency {
+        Some(options) => options,
+        None => return Ok(()),
+    };
+    if req.method() != Method::POST && req.method() != Method::PUT {
+        return Ok(());
+    }
+    let request_id = match req.headers().get("X-Parse-Request-Id") {
+        Some(header) => header.to_str().unwrap_or(""),
+        None => return Ok(()),
+    };
+    let path = req.path().trim_start_matches('/');
+    let path = path.strip_prefix("parse/").unwrap_or(path);
+    if !options.matches(path) {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let document = doc! {
+        "_id": util::new_object_id(),
+        "reqId": request_id,
+        "expire": now + Duration::seconds(options.ttl),
+        "_created_at": now,
+        "_updated_at": now,
+        "_rperm": [],
+        "_wperm": [],
+        "_acl": {},
+    };
+    if !db.insert_unique_document(CLASS_NAME, document).await? {
+        return Err(Error::DuplicateRequest("Duplicate request".to_string()));
+    }
+    Ok(())
+}
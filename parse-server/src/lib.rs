@@ -7,41 +7,208 @@ pub use actix_web;
 // pub use bson;
 
 use actix_web::{middleware, web::Data, App, HttpServer};
+use std::sync::Arc;
 
+mod auth;
+mod config;
 mod database;
+mod email;
 mod error;
+mod files;
+mod idempotency;
 mod schema;
 mod user;
 // mod api;
 mod cache;
+mod cloud;
 mod constants;
 mod operation;
+mod password;
+mod push;
 mod read;
 mod util;
 mod write;
-// mod config;
 // mod handlers;
 mod rest;
 
+pub use auth::{AnonymousAdapter, AuthAdapter, AuthAdapters, MfaAdapter, OAuth2Adapter};
+#[cfg(feature = "wasm")]
+pub use cloud::Plugins;
+#[cfg(feature = "scripting")]
+pub use cloud::Scripts;
+pub use cloud::{
+    AfterFindTrigger, BeforeFind, BeforeFindTrigger, BeforeSaveTrigger, CloudCode, CloudFunction,
+    CloudJob, FieldRule, FindTriggerRequest, FunctionRequest, JobMessage, JobRequest, Query,
+    Trigger, TriggerRequest, Validator,
+};
+pub use config::{AccountLockout, Config, IdempotencyOptions, PasswordPolicy};
+pub use email::{EmailAdapter, InMemoryEmailAdapter, Mail, SmtpAdapter};
+pub use error::Error;
+pub use files::{FileInfo, FileStream, FileSystemAdapter, FilesAdapter, GridFsAdapter, S3Adapter};
+pub use push::{
+    FilePushAdapter, InMemoryPushAdapter, PushAdapter, PushAdapters, PushResult, SentPush,
+};
+pub use user::User;
+
 // use schema::SchemaRepository;
 // use document::DocumentRepository;
 
+pub struct ParseServer {
+    config: Config,
+}
+
+impl ParseServer {
+    pub fn new(config: Config) -> Self {
+        ParseServer { config }
+    }
+
+    /// Registers the adapter validating `authData` of the given provider.
+    pub fn auth_adapter<A: AuthAdapter + 'static>(mut self, provider: &str, adapter: A) -> Self {
+        self.config.auth.insert(provider, adapter);
+        self
+    }
+
+    pub fn email_adapter<E: EmailAdapter + 'static>(mut self, adapter: E) -> Self {
+        self.config.email = Some(Arc::new(adapter));
+        self
+    }
+
+    /// Registers the adapter delivering pushes for its device types.
+    pub fn push_adapter<P: PushAdapter + 'static>(mut self, adapter: P) -> Self {
+        self.config.push.insert(adapter);
+        self
+    }
+
+    /// Stores uploaded files with the adapter instead of GridFS.
+    pub fn files_adapter<F: FilesAdapter + 'static>(mut self, adapter: F) -> Self {
+        self.config.files = Some(Arc::new(adapter));
+        self
+    }
+
+    pub fn cloud_code(mut self, cloud: CloudCode) -> Self {
+        self.config.cloud = cloud;
+        self
+    }
+
+    pub async fn run(mut self) -> std::io::Result<()> {
+        if !self.config.enable_anonymous_users {
+            self.config.auth.remove("anonymous");
+        }
+        if self.config.files.is_none() {
+            match files::GridFsAdapter::connect(&self.config.database_uri).await {
+                Ok(files) => {
+                    if let Err(e) = files.create_indexes().await {
+                        error!("Could not create GridFS indexes: {}", e);
+                    }
+                    self.config.files = Some(Arc::new(files));
+                }
+                Err(e) => error!("Could not connect GridFS: {}", e),
+            }
+        }
+        let db = Data::new(database::DbAdapter::connect(&self.config.database_uri).await);
+        let app_cache = Data::new(cache::AppCache::new());
+        let config = Data::new(self.config);
+
+        if config.idempotency.is_some() {
+            if let Err(e) = idempotency::create_indexes(&db).await {
+                error!("Could not create _Idempotency indexes: {}", e);
+            }
+        }
+        if !config.push.is_empty() {
+            let ctx = operation::Context {
+                class: "_Installation".to_string(),
+                user: user::User::master(),
+                db: db.clone().into_inner(),
+                cache: app_cache.clone().into_inner(),
+                config: config.clone().into_inner(),
+                cloud_context: bson::Document::new(),
+            };
+            actix_web::rt::spawn(push::run_scheduler(ctx));
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(plugins) = &config.cloud.plugins {
+            plugins.clone().watch();
+        }
+        if !config.cloud.job_names().is_empty() {
+            let ctx = operation::Context {
+                class: "_JobSchedule".to_string(),
+                user: user::User::master(),
+                db: db.clone().into_inner(),
+                cache: app_cache.clone().into_inner(),
+                config: config.clone().into_inner(),
+                cloud_context: bson::Document::new(),
+            };
+            actix_web::rt::spawn(cloud::run_scheduler(ctx));
+        }
+
+        HttpServer::new(move || {
+            App::new()
+                .wrap(middleware::DefaultHeaders::new().header("X-Version", "0.2"))
+                .wrap(middleware::Compress::default())
+                .wrap(middleware::Logger::default())
+                // .data(web::JsonConfig::default().limit(4096))
+                .app_data(db.clone())
+                .app_data(app_cache.clone())
+                .app_data(config.clone())
+                .service(rest::classes::query_documents)
+                .service(rest::classes::get_document)
+                .service(rest::functions::call_function)
+                .service(rest::files::upload_file)
+                .service(rest::files::download_file)
+                .service(rest::files::delete_file)
+                .service(rest::global_config::get_config)
+                .service(rest::global_config::update_config)
+                .service(rest::hooks::find_functions)
+                .service(rest::hooks::get_function)
+                .service(rest::hooks::create_function)
+                .service(rest::hooks::update_function)
+                .service(rest::hooks::delete_function)
+                .service(rest::hooks::find_triggers)
+                .service(rest::hooks::get_trigger)
+                .service(rest::hooks::create_trigger)
+                .service(rest::hooks::update_trigger)
+                .service(rest::hooks::delete_trigger)
+                .service(rest::jobs::start_job)
+                .service(rest::cloud_code::find_jobs)
+                .service(rest::cloud_code::jobs_data)
+                .service(rest::cloud_code::create_job_schedule)
+                .service(rest::cloud_code::update_job_schedule)
+                .service(rest::cloud_code::delete_job_schedule)
+                .service(rest::installations::create_installation)
+                .service(rest::installations::find_installations)
+                .service(rest::installations::get_installation)
+                .service(rest::installations::installation)
+                .service(rest::installations::update_installation)
+                .service(rest::installations::delete_installation)
+                .service(rest::push::send_push)
+                .service(rest::audiences::create_audience)
+                .service(rest::audiences::find_audiences)
+                .service(rest::audiences::get_audience)
+                .service(rest::audiences::update_audience)
+                .service(rest::audiences::delete_audience)
+                .service(rest::users::sign_up)
+                .service(rest::users::get_me)
+                .service(rest::users::post_me)
+                .service(rest::users::get_user)
+                .service(rest::users::user_document)
+                .service(rest::users::update_user)
+                .service(rest::users::delete_user)
+                .service(rest::users::get_login)
+                .service(rest::users::post_login)
+                .service(rest::users::log_out)
+                .service(rest::users::verification_email_request)
+                .service(rest::users::request_password_reset)
+                .service(rest::pages::verify_email)
+                .service(rest::pages::password_reset_form)
+                .service(rest::pages::reset_password)
+        })
+        .bind("127.0.0.1:5000")?
+        .workers(8)
+        .run()
+        .await
+    }
+}
+
 pub async fn run() -> std::io::Result<()> {
-    let db = Data::new(database::DbAdapter::connect().await);
-    let app_cache = Data::new(cache::AppCache::new());
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::DefaultHeaders::new().header("X-Version", "0.2"))
-            .wrap(middleware::Compress::default())
-            .wrap(middleware::Logger::default())
-            // .data(web::JsonConfig::default().limit(4096))
-            .app_data(db.clone())
-            .app_data(app_cache.clone())
-            .service(rest::classes::query_documents)
-    })
-    .bind("127.0.0.1:5000")?
-    .workers(8)
-    .run()
-    .await
+    ParseServer::new(Config::from_env()).run().await
 }
@@ -1,11 +1,13 @@
 use crate::cache::AppCache;
+use crate::config::Config;
 use crate::constants::{MASTER_ONLY_ACCESS, SYSTEM_CLASSES};
 use crate::database::DbAdapter;
 use crate::error::Error;
-use crate::read::read;
-use crate::user::User;
+use crate::read::{read, run_before_find_trigger};
+use crate::schema::{is_special_param, Schema};
+use crate::user::{self, User};
 use crate::write::write;
-use bson::Document;
+use bson::{doc, Bson, Document};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
@@ -14,6 +16,23 @@ pub struct Context {
     pub user: User,
     pub db: Arc<DbAdapter>,
     pub cache: Arc<AppCache>,
+    pub config: Arc<Config>,
+    /// Data the client passes to triggers through `X-Parse-Cloud-Context`.
+    pub cloud_context: Document,
+}
+
+impl Context {
+    /// Context for another class on behalf of the same user.
+    pub fn for_class(&self, class: &str) -> Context {
+        Context {
+            class: class.to_string(),
+            user: self.user.clone(),
+            db: self.db.clone(),
+            cache: self.cache.clone(),
+            config: self.config.clone(),
+            cloud_context: self.cloud_context.clone(),
+        }
+    }
 }
 
 pub enum Request {
@@ -26,7 +45,11 @@ pub enum Request {
 
 pub struct GetRequest {
     pub objectId: String,
-    pub join: Vec<Join>,
+    pub include: Vec<String>,
+    pub filter: Option<Document>,
+    /// Fields to return, all fields when empty.
+    pub keys: Vec<String>,
+    pub read_preference: Option<String>,
 }
 
 pub struct FindRequest {
@@ -35,7 +58,11 @@ pub struct FindRequest {
     pub limit: Option<i64>,
     pub skip: Option<i64>,
     pub sort: Option<Document>,
-    pub join: Vec<Join>,
+    pub count: bool,
+    /// Fields to return, all fields when empty.
+    pub keys: Vec<String>,
+    /// Mongo read preference, e.g. `SECONDARY_PREFERRED`.
+    pub read_preference: Option<String>,
 }
 
 pub struct CreateRequest {
@@ -44,11 +71,13 @@ pub struct CreateRequest {
 
 pub struct UpdateRequest {
     pub objectId: String,
+    pub filter: Option<Document>,
     pub params: Document,
 }
 
 pub struct DeleteRequest {
     pub objectId: String,
+    pub filter: Option<Document>,
 }
 
 pub struct Join {
@@ -63,15 +92,65 @@ pub struct Relation {
     pub filters: Option<Document>,
 }
 
+impl Request {
+    /// Name of the class level permission guarding this request.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Request::Get(_) => "get",
+            Request::Find(req) if req.count => "count",
+            Request::Find(_) => "find",
+            Request::Create(_) => "create",
+            Request::Update(_) => "update",
+            Request::Delete(_) => "delete",
+        }
+    }
+
+    pub fn is_read(&self) -> bool {
+        matches!(self, Request::Get(_) | Request::Find(_))
+    }
+
+    fn filter_mut(&mut self) -> Option<&mut Option<Document>> {
+        match self {
+            Request::Get(req) => Some(&mut req.filter),
+            Request::Find(req) => Some(&mut req.filter),
+            Request::Update(req) => Some(&mut req.filter),
+            Request::Delete(req) => Some(&mut req.filter),
+            Request::Create(_) => None,
+        }
+    }
+
+    fn params(&self) -> Option<&Document> {
+        match self {
+            Request::Create(req) => Some(&req.params),
+            Request::Update(req) => Some(&req.params),
+            _ => None,
+        }
+    }
+}
+
 // TODO: fix error handling
-async fn fetch_schema(ctx: &Context) -> Result<(), Error> {
+pub async fn fetch_schema(ctx: &Context) -> Result<(), Error> {
     if ctx.cache.schema_loaded.load(Ordering::Relaxed) {
         info!("Using cached schema!");
         return Ok(());
     }
 
+    reload_schema(ctx).await
+}
+
+/// Loads the schema from the database into the cache, system classes always
+/// exist with their default fields.
+pub async fn reload_schema(ctx: &Context) -> Result<(), Error> {
     info!("Loading schema...");
-    let schema = ctx.db.get_schema().await?;
+    let mut schema = ctx.db.get_schema().await?;
+    for class in SYSTEM_CLASSES.iter() {
+        schema
+            .entry(class.to_string())
+            .or_insert_with(|| Schema::new(class.to_string()));
+    }
+    for class in schema.values_mut() {
+        class.add_default_fields();
+    }
     info!("Schema loaded! count: {}", schema.len());
 
     *ctx.cache.schema.write().expect("RwLock is poisoned") = schema;
@@ -82,9 +161,7 @@ async fn fetch_schema(ctx: &Context) -> Result<(), Error> {
 
 // TODO: fix error handling
 fn validate_class_creation(ctx: &Context) -> Result<(), Error> {
-    let allow_client_class_creation = false;
-
-    if !allow_client_class_creation
+    if !ctx.config.allow_client_class_creation
         && !ctx.user.is_master
         && !SYSTEM_CLASSES.contains(ctx.class.as_str())
         && ctx.cache.get_schema(&ctx.class).is_none()
@@ -104,7 +181,7 @@ fn enforce_role_security(req: &Request, ctx: &Context) -> Result<(), Error> {
     if ctx.class == "_Installation" && !ctx.user.is_master {
         match req {
             Request::Delete(_) | Request::Find(_) => {
-                let message = format!("Clients aren't allowed to perform the {} operation on the installation collection.", "ctx.request");
+                let message = format!("Clients aren't allowed to perform the {} operation on the installation collection.", req.operation());
                 error!("{}", message);
                 return Err(Error::Forbidden(message));
             }
@@ -115,7 +192,8 @@ fn enforce_role_security(req: &Request, ctx: &Context) -> Result<(), Error> {
     if !ctx.user.is_master && MASTER_ONLY_ACCESS.contains(ctx.class.as_str()) {
         let message = format!(
             "Clients aren't allowed to perform the {} operation on the {} collection.",
-            "ctx.operation", ctx.class
+            req.operation(),
+            ctx.class
         );
         error!("{}", message);
         return Err(Error::Forbidden(message));
@@ -126,7 +204,7 @@ fn enforce_role_security(req: &Request, ctx: &Context) -> Result<(), Error> {
             if ctx.user.is_read_only {
                 let message = format!(
                     "read-only masterKey isn't allowed to perform the {} operation.",
-                    "req.operation"
+                    req.operation()
                 );
                 error!("{}", message);
                 return Err(Error::Forbidden(message));
@@ -138,17 +216,361 @@ fn enforce_role_security(req: &Request, ctx: &Context) -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn execute(req: Request, ctx: Context) -> Result<(), Error> {
+/// Keys the user (and its roles) is matched against in a permission map.
+fn permission_keys(ctx: &Context) -> Vec<&str> {
+    let mut keys = vec!["*"];
+    if let Some(id) = &ctx.user.id {
+        keys.push(id.as_str());
+    }
+    keys.extend(ctx.user.user_roles.iter().map(|role| role.as_str()));
+    keys
+}
+
+fn permission_denied(operation: &str, ctx: &Context) -> Error {
+    let message = format!(
+        "Permission denied for action {} on class {}.",
+        operation, ctx.class
+    );
+    error!("{}", message);
+    Error::Forbidden(message)
+}
+
+fn test_permissions(operation: &str, ctx: &Context, schema: &Schema) -> Result<bool, Error> {
+    let permissions = match schema.permissions.for_operation(operation) {
+        Some(permissions) => permissions,
+        None => return Ok(true),
+    };
+
+    // Only rejects anonymous callers, users still need a matching key
+    if permissions.get("requiresAuthentication") == Some(&true) && ctx.user.id.is_none() {
+        let message = format!(
+            "Permission denied for action {} on class {}, user needs to be authenticated.",
+            operation, ctx.class
+        );
+        error!("{}", message);
+        return Err(Error::Forbidden(message));
+    }
+
+    Ok(permission_keys(ctx)
+        .iter()
+        .any(|key| permissions.get(*key) == Some(&true)))
+}
+
+fn add_constraint(req: &mut Request, constraint: Document) {
+    if let Some(filter) = req.filter_mut() {
+        *filter = match filter.take() {
+            Some(existing) => Some(doc! {"$and": [existing, constraint]}),
+            None => Some(constraint),
+        };
+    }
+}
+
+/// Constrains the request to objects whose pointer fields reference the user.
+fn add_pointer_permissions(req: &mut Request, user_id: &str, fields: &[String]) {
+    let constraints: Vec<Bson> = fields
+        .iter()
+        .map(|field| {
+            let mut constraint = doc! {};
+            constraint.insert(
+                field.as_str(),
+                doc! {"__type": "Pointer", "className": "_User", "objectId": user_id},
+            );
+            Bson::Document(constraint)
+        })
+        .collect();
+    let constraint = if constraints.len() == 1 {
+        constraints[0].as_document().unwrap().clone()
+    } else {
+        doc! {"$or": constraints}
+    };
+    add_constraint(req, constraint);
+}
+
+/// Restricts reads to objects readable by the user or one of its roles, and
+/// updates or deletes to writable ones.
+fn enforce_acl(req: &mut Request, ctx: &Context) {
+    if ctx.user.is_master {
+        return;
+    }
+    let key = match req {
+        Request::Get(_) | Request::Find(_) => "_rperm",
+        Request::Update(_) | Request::Delete(_) => "_wperm",
+        Request::Create(_) => return,
+    };
+    // Objects without an ACL are public
+    let mut allowed = vec![Bson::Null];
+    allowed.extend(permission_keys(ctx).into_iter().map(Bson::from));
+    let mut constraint = doc! {};
+    constraint.insert(key, doc! {"$in": allowed});
+    add_constraint(req, constraint);
+}
+
+async fn load_user_roles(ctx: &mut Context) -> Result<(), Error> {
+    if ctx.user.is_master || !ctx.user.user_roles.is_empty() {
+        return Ok(());
+    }
+    if let Some(user_id) = ctx.user.id.clone() {
+        ctx.user.user_roles = user::load_roles(&user_id, &ctx.db, &ctx.cache).await?;
+    }
+    Ok(())
+}
+
+fn enforce_class_permissions(req: &mut Request, ctx: &Context) -> Result<(), Error> {
+    if ctx.user.is_master {
+        return Ok(());
+    }
+
+    let schema = match ctx.cache.get_schema(&ctx.class) {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    let operation = req.operation();
+
+    // Creating or updating an object with unknown keys requires addField
+    let adds_fields = req
+        .params()
+        .map(|params| {
+            params.keys().any(|key| {
+                !key.starts_with('_') && !is_special_param(key) && !schema.fields.contains_key(key)
+            })
+        })
+        .unwrap_or(false);
+    if adds_fields && !test_permissions("addField", ctx, &schema)? {
+        return Err(permission_denied("addField", ctx));
+    }
+
+    if test_permissions(operation, ctx, &schema)? {
+        return Ok(());
+    }
+
+    let pointer_fields = schema.permissions.pointer_fields(operation);
+    match &ctx.user.id {
+        Some(user_id) if !pointer_fields.is_empty() => {
+            add_pointer_permissions(req, user_id, pointer_fields);
+            Ok(())
+        }
+        _ => Err(permission_denied(operation, ctx)),
+    }
+}
+
+/// Rejects `where` keys that are not fields, such as `$where` or internal
+/// fields like `_perishable_token` that could be guessed through queries.
+fn validate_query(filter: &Document) -> Result<(), Error> {
+    for (key, value) in filter {
+        match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let queries = value.as_array().ok_or_else(|| {
+                    Error::BadFormat(format!("Bad {} format - use an array value.", key))
+                })?;
+                for query in queries {
+                    match query {
+                        Bson::Document(query) => validate_query(query)?,
+                        _ => {
+                            return Err(Error::BadFormat(format!(
+                                "Bad {} format - use an array of objects.",
+                                key
+                            )))
+                        }
+                    }
+                }
+            }
+            key => {
+                let mut chars = key.chars();
+                let valid = matches!(chars.next(), Some(x) if x.is_ascii_alphabetic())
+                    && chars.all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '.');
+                if !valid {
+                    return Err(Error::InvalidKeyName(format!("Invalid key name: {}", key)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Restricts a read done on behalf of another request, e.g. of an included
+/// pointer, the same way as a request to that class.
+pub fn enforce_read_permissions(req: &mut Request, ctx: &Context) -> Result<(), Error> {
+    enforce_role_security(req, ctx)?;
+    enforce_class_permissions(req, ctx)?;
+    enforce_acl(req, ctx);
+    Ok(())
+}
+
+/// Restricts a write the server rewrote, e.g. an installation create merged
+/// into an existing installation, the same way as a request to that object.
+pub fn enforce_write_permissions(req: &mut Request, ctx: &Context) -> Result<(), Error> {
+    enforce_class_permissions(req, ctx)?;
+    enforce_acl(req, ctx);
+    Ok(())
+}
+
+pub async fn execute(mut req: Request, mut ctx: Context) -> Result<Document, Error> {
     info!("Executing read for {}", ctx.class);
+    if let Request::Find(FindRequest {
+        filter: Some(filter),
+        ..
+    }) = &req
+    {
+        validate_query(filter)?;
+    }
     fetch_schema(&ctx).await?;
     validate_class_creation(&ctx)?;
     enforce_role_security(&req, &ctx)?;
+    load_user_roles(&mut ctx).await?;
+    let objects = run_before_find_trigger(&mut req, &ctx).await?;
+    enforce_class_permissions(&mut req, &ctx)?;
+    enforce_acl(&mut req, &ctx);
 
     match req {
-        Request::Get(_) => read(req, ctx).await,
-        Request::Find(_) => read(req, ctx).await,
-        Request::Create(_) => read(req, ctx).await,
+        Request::Get(_) => read(req, ctx, objects).await,
+        Request::Find(_) => read(req, ctx, objects).await,
+        Request::Create(_) => write(req, ctx).await,
         Request::Update(_) => write(req, ctx).await,
         Request::Delete(_) => write(req, ctx).await,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Permissions;
+    use actix_web::rt::System;
+    use std::collections::HashMap;
+
+    /// A context for `class` with the given permissions, the database is
+    /// never reached.
+    fn context(user_id: Option<&str>, class: &str, permissions: Permissions) -> Context {
+        let db = System::new("test").block_on(DbAdapter::connect("mongodb://localhost/test"));
+        let cache = AppCache::new();
+        let mut schema = Schema::new(class.to_string());
+        schema.permissions = permissions;
+        schema.add_default_fields();
+        cache
+            .schema
+            .write()
+            .unwrap()
+            .insert(class.to_string(), schema);
+        Context {
+            class: class.to_string(),
+            user: User {
+                id: user_id.map(String::from),
+                is_master: false,
+                ..User::master()
+            },
+            db: Arc::new(db),
+            cache: Arc::new(cache),
+            config: Arc::new(Config::default()),
+            cloud_context: doc! {},
+        }
+    }
+
+    fn allow(keys: &[&str]) -> HashMap<String, bool> {
+        keys.iter().map(|key| (key.to_string(), true)).collect()
+    }
+
+    fn create(params: Document) -> Request {
+        Request::Create(CreateRequest { params })
+    }
+
+    #[test]
+    fn requires_authentication_still_needs_a_matching_key() {
+        let mut find = allow(&["requiresAuthentication", "user1"]);
+        find.insert("*".to_string(), false);
+        let permissions = Permissions {
+            find,
+            ..Permissions::new()
+        };
+        let anonymous = context(None, "Post", permissions.clone());
+        let listed = context(Some("user1"), "Post", permissions.clone());
+        let other = context(Some("user2"), "Post", permissions);
+        let can_find = |ctx: &Context| {
+            let schema = ctx.cache.get_schema("Post").unwrap();
+            test_permissions("find", ctx, &schema).ok()
+        };
+        assert_eq!(can_find(&anonymous), None);
+        assert_eq!(can_find(&listed), Some(true));
+        assert_eq!(can_find(&other), Some(false));
+    }
+
+    #[test]
+    fn locked_add_field_allows_acl_password_and_auth_data() {
+        let permissions = Permissions {
+            add_field: allow(&[]),
+            ..Permissions::new()
+        };
+        let ctx = context(None, "_User", permissions);
+        let mut req = create(doc! {
+            "username": "alice",
+            "password": "secret",
+            "authData": {"anonymous": {"id": "1"}},
+            "ACL": {"*": {"read": true}},
+        });
+        assert!(enforce_class_permissions(&mut req, &ctx).is_ok());
+
+        let mut req = create(doc! {"username": "alice", "nickname": "al"});
+        match enforce_class_permissions(&mut req, &ctx) {
+            Err(Error::Forbidden(_)) => {}
+            _ => panic!("a new field was added"),
+        }
+    }
+
+    #[test]
+    fn merged_installation_needs_update_permission_and_write_access() {
+        let merged = || {
+            Request::Update(UpdateRequest {
+                objectId: "abc".to_string(),
+                filter: None,
+                params: doc! {"deviceToken": "token"},
+            })
+        };
+        let locked = Permissions {
+            update: allow(&[]),
+            ..Permissions::new()
+        };
+        let ctx = context(None, "_Installation", locked);
+        match enforce_write_permissions(&mut merged(), &ctx) {
+            Err(Error::Forbidden(_)) => {}
+            _ => panic!("the update permission was ignored"),
+        }
+
+        let ctx = context(Some("user1"), "_Installation", Permissions::new());
+        let mut req = merged();
+        assert!(enforce_write_permissions(&mut req, &ctx).is_ok());
+        match req {
+            Request::Update(UpdateRequest {
+                filter: Some(filter),
+                ..
+            }) => assert_eq!(filter, doc! {"_wperm": {"$in": [Bson::Null, "*", "user1"]}}),
+            _ => panic!("the ACL wasn't enforced"),
+        }
+    }
+
+    #[test]
+    fn accepts_field_queries() {
+        let query = doc! {
+            "name": "a",
+            "score": {"$gt": 1},
+            "object.key": 1,
+            "$or": [{"name": "b"}, {"$and": [{"score": 2}]}],
+        };
+        assert!(validate_query(&query).is_ok());
+    }
+
+    #[test]
+    fn rejects_internal_and_operator_keys() {
+        let queries = vec![
+            doc! {"_perishable_token": {"$regex": "^a"}},
+            doc! {"_hashed_password": {"$exists": true}},
+            doc! {"_auth_data_mfa.secret": {"$regex": "^A"}},
+            doc! {"$where": "true"},
+            doc! {"$or": [{"name": "a"}, {"_email_verify_token": "x"}]},
+        ];
+        for query in queries {
+            match validate_query(&query) {
+                Err(Error::InvalidKeyName(_)) => {}
+                _ => panic!("{} was accepted", query),
+            }
+        }
+    }
+}
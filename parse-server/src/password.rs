@@ -0,0 +1,92 @@
+use crate::config::Config;
+use crate::error::Error;
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    bcrypt::hash(password, 10).map_err(|e| Error::Internal(e.to_string()))
+}
+
+fn stored_history(user: &Document) -> Vec<String> {
+    match user.get_array("_password_history") {
+        Ok(history) => history
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| x.to_string())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Checks a new password against the password policy and returns the fields
+/// storing it: the hash, the change date and the password history.
+///
+/// `stored` is the current `_User` document when the password is changed.
+pub fn new_password_fields(
+    config: &Config,
+    stored: Option<&Document>,
+    username: Option<&str>,
+    password: &str,
+) -> Result<Document, Error> {
+    let mut fields = doc! {
+        "_hashed_password": hash_password(password)?,
+        "_password_changed_at": Utc::now(),
+    };
+    let policy = match &config.password_policy {
+        Some(policy) => policy,
+        None => return Ok(fields),
+    };
+
+    let pattern_failed = policy
+        .validator_pattern
+        .as_ref()
+        .map(|x| !x.is_match(password))
+        .unwrap_or(false);
+    let callback_failed = policy
+        .validator_callback
+        .as_ref()
+        .map(|x| !x(password))
+        .unwrap_or(false);
+    if pattern_failed || callback_failed {
+        let message = policy.validation_error.clone().unwrap_or_else(|| {
+            "Password does not meet the Password Policy requirements.".to_string()
+        });
+        return Err(Error::ValidationError(message));
+    }
+
+    let username = username.or_else(|| stored.and_then(|x| x.get_str("username").ok()));
+    if policy.do_not_allow_username {
+        if let Some(username) = username.filter(|x| !x.is_empty()) {
+            if password.contains(username) {
+                return Err(Error::ValidationError(
+                    "Password cannot contain your username.".to_string(),
+                ));
+            }
+        }
+    }
+
+    let max_history = policy.max_password_history.unwrap_or(0);
+    if let (Some(stored), true) = (stored, max_history > 0) {
+        // The current password counts as the most recent one
+        let mut history = stored_history(stored);
+        if let Ok(current) = stored.get_str("_hashed_password") {
+            history.push(current.to_string());
+        }
+        let skip = history.len().saturating_sub(max_history);
+        let history: Vec<String> = history.into_iter().skip(skip).collect();
+        if history
+            .iter()
+            .any(|x| bcrypt::verify(password, x).unwrap_or(false))
+        {
+            return Err(Error::ValidationError(format!(
+                "New password should not be the same as last {} passwords.",
+                max_history
+            )));
+        }
+        // The new password is the current one, only older ones go to history
+        let skip = history.len().saturating_sub(max_history - 1);
+        let history: Vec<Bson> = history.into_iter().skip(skip).map(Bson::String).collect();
+        fields.insert("_password_history", history);
+    }
+    Ok(fields)
+}
@@ -0,0 +1,688 @@
+use super::PushAdapters;
+use crate::error::Error;
+use crate::operation::{fetch_schema, Context, FindRequest};
+use crate::rest::parse_payload;
+use crate::util;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::HashMap;
+
+/// How often the scheduler looks for scheduled pushes that are due.
+const SCHEDULER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+fn to_json(document: &Document) -> String {
+    Bson::Document(document.clone())
+        .into_relaxed_extjson()
+        .to_string()
+}
+
+fn from_json(json: Result<&str, bson::document::ValueAccessError>) -> Result<Document, Error> {
+    let json = json.map_err(|_| Error::Internal("Missing stored query".to_string()))?;
+    parse_payload(json)
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(*value as i64),
+        Bson::Int64(value) => Some(*value),
+        Bson::Double(value) => Some(*value as i64),
+        _ => None,
+    }
+}
+
+/// A push date from an ISO string, a `Date` value or epoch seconds.
+fn parse_date(key: &str, value: &Bson) -> Result<DateTime<Utc>, Error> {
+    let date = match value {
+        Bson::String(iso) => util::parse_iso(iso),
+        Bson::Document(date) if date.get_str("__type") == Ok("Date") => {
+            date.get_str("iso").ok().and_then(util::parse_iso)
+        }
+        value => as_i64(value).map(|seconds| Utc.timestamp(seconds, 0)),
+    };
+    date.ok_or_else(|| Error::PushMisconfigured(format!("{} contains an invalid date.", key)))
+}
+
+/// The `_Installation` query of a saved `_Audience`.
+async fn audience_filter(ctx: &Context, audience_id: &str) -> Result<Document, Error> {
+    let audience = ctx
+        .db
+        .find_documents("_Audience", doc! {"_id": audience_id})
+        .await?
+        .pop()
+        .ok_or_else(|| Error::NotFound("Audience not found.".to_string()))?;
+    from_json(audience.get_str("query"))
+}
+
+/// Counts a push towards the usage of its audience.
+async fn record_audience_use(ctx: &Context, audience_id: &str) -> Result<(), Error> {
+    let now = Utc::now();
+    let update = doc! {
+        "$set": {"lastUsed": now, "_updated_at": now},
+        "$inc": {"timesUsed": 1},
+    };
+    ctx.db
+        .update_documents("_Audience", doc! {"_id": audience_id}, update)
+        .await?;
+    Ok(())
+}
+
+/// The audience whose query a push is sent to, `where` and `channels` take
+/// precedence over it.
+fn audience_id(body: &Document) -> Option<&str> {
+    if body.get_document("where").is_ok() || body.contains_key("channels") {
+        return None;
+    }
+    body.get_str("audience_id").ok()
+}
+
+/// The `_Installation` query of a push, from `where` and/or `channels`, or
+/// from the audience when neither is given.
+async fn installation_filter(ctx: &Context, body: &Document) -> Result<Document, Error> {
+    let filter = match (body.get_document("where"), audience_id(body)) {
+        (Ok(filter), _) => Some(filter.clone()),
+        (Err(_), Some(audience_id)) => Some(audience_filter(ctx, audience_id).await?),
+        _ => None,
+    };
+    let channels = body.get_array("channels").ok().cloned();
+    match (filter, channels) {
+        (Some(mut filter), Some(channels)) => {
+            filter.insert("channels", doc! {"$in": channels});
+            Ok(filter)
+        }
+        (Some(filter), None) => Ok(filter),
+        (None, Some(channels)) => Ok(doc! {"channels": {"$in": channels}}),
+        (None, None) => Err(Error::PushMisconfigured(
+            "Sending a push requires either \"channels\" or a \"where\" query.".to_string(),
+        )),
+    }
+}
+
+/// Adds `expiry` or `expiration_interval` (seconds) to a new `_PushStatus`.
+fn set_expiration(body: &Document, status: &mut Document) -> Result<(), Error> {
+    match (body.get("expiration_time"), body.get("expiration_interval")) {
+        (Some(_), Some(_)) => Err(Error::PushMisconfigured(
+            "Both expiration_time and expiration_interval cannot be set.".to_string(),
+        )),
+        (Some(time), None) => {
+            let time = parse_date("expiration_time", time)?;
+            status.insert("expiry", time.timestamp());
+            Ok(())
+        }
+        (None, Some(interval)) => match as_i64(interval) {
+            Some(interval) if interval > 0 => {
+                status.insert("expiration_interval", interval);
+                Ok(())
+            }
+            _ => Err(Error::PushMisconfigured(
+                "expiration_interval must be a number greater than 0.".to_string(),
+            )),
+        },
+        (None, None) => Ok(()),
+    }
+}
+
+/// When the push stops being worth delivering, if ever.
+fn expiration_time(status: &Document, push_time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    if let Some(expiry) = status.get("expiry").and_then(as_i64) {
+        return Some(Utc.timestamp(expiry, 0));
+    }
+    let interval = status.get("expiration_interval").and_then(as_i64)?;
+    Some(push_time + Duration::seconds(interval))
+}
+
+/// Locales with a localized alert or title, e.g. `fr` for `alert-fr`.
+fn push_locales(data: &Document) -> Vec<String> {
+    let mut locales: Vec<String> = data
+        .keys()
+        .filter_map(|key| {
+            key.strip_prefix("alert-")
+                .or_else(|| key.strip_prefix("title-"))
+        })
+        .map(|x| x.to_string())
+        .collect();
+    locales.sort();
+    locales.dedup();
+    locales
+}
+
+/// The most specific push locale matching the installation, so `fr-CA` is
+/// preferred over `fr` for a `fr-CA` device.
+fn installation_locale<'a>(installation: &Document, locales: &'a [String]) -> Option<&'a str> {
+    let identifier = installation.get_str("localeIdentifier").ok()?;
+    locales
+        .iter()
+        .filter(|locale| {
+            identifier == locale.as_str()
+                || identifier.starts_with(&format!("{}-", locale))
+                || identifier.starts_with(&format!("{}_", locale))
+        })
+        .max_by_key(|locale| locale.len())
+        .map(|x| x.as_str())
+}
+
+/// The push data with the alert and title of `locale` and without the
+/// other localized keys.
+fn localized_data(data: &Document, locale: Option<&str>) -> Document {
+    let mut localized: Document = data
+        .iter()
+        .filter(|(key, _)| !key.starts_with("alert-") && !key.starts_with("title-"))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    if let Some(locale) = locale {
+        for key in &["alert", "title"] {
+            if let Some(value) = data.get(&format!("{}-{}", key, locale)) {
+                localized.insert(*key, value.clone());
+            }
+        }
+    }
+    localized
+}
+
+/// The amount of a badge increment, `"Increment"` or an `Increment` op.
+fn badge_increment(data: &Document) -> Option<i64> {
+    match data.get("badge") {
+        Some(Bson::String(badge)) if badge.eq_ignore_ascii_case("increment") => Some(1),
+        Some(Bson::Document(op)) if op.get_str("__op") == Ok("Increment") => {
+            Some(op.get("amount").and_then(as_i64).unwrap_or(1))
+        }
+        _ => None,
+    }
+}
+
+/// Stores the push badge on the iOS installations, increments are applied
+/// atomically. Returns the resulting badge per installation id.
+async fn update_badges(
+    ctx: &Context,
+    data: &Document,
+    installations: &[Document],
+) -> Result<HashMap<String, Bson>, Error> {
+    let update = match (badge_increment(data), data.get("badge")) {
+        (Some(amount), _) => doc! {"$inc": {"badge": amount}},
+        (None, Some(badge)) if as_i64(badge).is_some() => doc! {"$set": {"badge": badge.clone()}},
+        _ => return Ok(HashMap::new()),
+    };
+    let ids: Vec<String> = installations
+        .iter()
+        .filter(|x| x.get_str("deviceType") == Ok("ios"))
+        .filter_map(|x| x.get_str("objectId").ok())
+        .map(|x| x.to_string())
+        .collect();
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let filter = doc! {"_id": {"$in": ids}};
+    ctx.db
+        .update_documents("_Installation", filter.clone(), update)
+        .await?;
+    Ok(ctx
+        .db
+        .find_documents("_Installation", filter)
+        .await?
+        .into_iter()
+        .filter_map(|x| Some((x.get_str("_id").ok()?.to_string(), x.get("badge")?.clone())))
+        .collect())
+}
+
+async fn set_status(ctx: &Context, status_id: &str, mut set: Document) -> Result<(), Error> {
+    set.insert("_updated_at", Utc::now());
+    ctx.db
+        .update_documents("_PushStatus", doc! {"_id": status_id}, doc! {"$set": set})
+        .await?;
+    Ok(())
+}
+
+/// Sends the push of a `_PushStatus` to every matching installation through
+/// the adapter of its device type, and records the counts.
+async fn deliver(ctx: &Context, status_id: &str, status: &Document) -> Result<(), Error> {
+    let filter = from_json(status.get_str("query"))?;
+    let data = from_json(status.get_str("payload"))?;
+    let push_time = status
+        .get_str("pushTime")
+        .ok()
+        .and_then(util::parse_iso)
+        .unwrap_or_else(Utc::now);
+    let expiration_time = expiration_time(status, push_time);
+    if expiration_time.is_some_and(|x| x <= Utc::now()) {
+        info!("Push {} expired before it was sent", status_id);
+        let set = doc! {"status": "failed", "errorMessage": "Push expired"};
+        return set_status(ctx, status_id, set).await;
+    }
+    set_status(ctx, status_id, doc! {"status": "running"}).await?;
+
+    fetch_schema(ctx).await?;
+    let req = FindRequest {
+        include: vec![],
+        filter: Some(filter),
+        limit: None,
+        skip: None,
+        sort: None,
+        count: false,
+        keys: vec![],
+        read_preference: None,
+    };
+    let installations = ctx.db.query_objects(&req, ctx).await?;
+    let badges = update_badges(ctx, &data, &installations).await?;
+    let batches = batches(&data, installations, &badges);
+    let set = send_batches(&ctx.config.push, status_id, batches, expiration_time).await;
+    set_status(ctx, status_id, set).await
+}
+
+/// Groups installations by device type, then by the payload they get, so
+/// installations getting the same payload are sent together.
+fn batches(
+    data: &Document,
+    installations: Vec<Document>,
+    badges: &HashMap<String, Bson>,
+) -> HashMap<String, Vec<(Document, Vec<Document>)>> {
+    let increment = badge_increment(data).is_some();
+    let locales = push_locales(data);
+    let mut batches: HashMap<String, Vec<(Document, Vec<Document>)>> = HashMap::new();
+    for installation in installations {
+        let mut payload = localized_data(data, installation_locale(&installation, &locales));
+        match installation
+            .get_str("objectId")
+            .ok()
+            .and_then(|id| badges.get(id))
+        {
+            Some(badge) => {
+                payload.insert("badge", badge.clone());
+            }
+            None if increment => {
+                payload.remove("badge");
+            }
+            None => {}
+        }
+        let device_type = installation.get_str("deviceType").unwrap_or("").to_string();
+        let batches = batches.entry(device_type).or_default();
+        match batches.iter_mut().find(|(x, _)| *x == payload) {
+            Some((_, installations)) => installations.push(installation),
+            None => batches.push((payload, vec![installation])),
+        }
+    }
+    batches
+}
+
+/// Sends each batch through the adapter of its device type. Returns the
+/// final `_PushStatus` fields with the counts.
+async fn send_batches(
+    adapters: &PushAdapters,
+    status_id: &str,
+    batches: HashMap<String, Vec<(Document, Vec<Document>)>>,
+    expiration_time: Option<DateTime<Utc>>,
+) -> Document {
+    let (mut num_sent, mut num_failed) = (0, 0);
+    let (mut sent_per_type, mut failed_per_type) = (doc! {}, doc! {});
+    for (device_type, batches) in batches {
+        let adapter = match adapters.get(&device_type) {
+            Some(adapter) => adapter,
+            None => {
+                debug!("No push adapter for device type {}", device_type);
+                continue;
+            }
+        };
+        let (mut sent, mut failed) = (0, 0);
+        for (payload, installations) in batches {
+            // A failing batch counts as failed, the others are still sent
+            let results = match adapter
+                .send(&payload, &installations, expiration_time)
+                .await
+            {
+                Ok(results) => results,
+                Err(e) => {
+                    error!("Push {} to {} failed: {}", status_id, device_type, e);
+                    failed += installations.len() as i64;
+                    continue;
+                }
+            };
+            let transmitted = results.iter().filter(|x| x.transmitted).count() as i64;
+            sent += transmitted;
+            failed += results.len() as i64 - transmitted;
+        }
+        num_sent += sent;
+        num_failed += failed;
+        sent_per_type.insert(&device_type, sent);
+        failed_per_type.insert(&device_type, failed);
+    }
+
+    info!(
+        "Push {} sent {}, failed {}",
+        status_id, num_sent, num_failed
+    );
+    doc! {
+        "status": "succeeded",
+        "numSent": num_sent,
+        "numFailed": num_failed,
+        "sentPerType": sent_per_type,
+        "failedPerType": failed_per_type,
+        "count": num_sent + num_failed,
+    }
+}
+
+/// Delivers a push, recording errors in its `_PushStatus`.
+async fn dispatch(ctx: &Context, status: &Document) {
+    let id = status.get_str("_id").unwrap_or("");
+    if let Err(e) = deliver(ctx, id, status).await {
+        error!("Push {} failed: {}", id, e);
+        let set = doc! {"status": "failed", "errorMessage": e.message()};
+        if let Err(e) = set_status(ctx, id, set).await {
+            error!("Could not update push status {}: {}", id, e);
+        }
+    }
+}
+
+/// Validates a push request and creates its `_PushStatus`, the push itself
+/// is delivered in the background, or by the scheduler when `push_time` is
+/// in the future. Returns the `_PushStatus` id.
+pub async fn send_push(ctx: &Context, body: &Document) -> Result<String, Error> {
+    if ctx.config.push.is_empty() {
+        return Err(Error::PushMisconfigured(
+            "Missing push configuration".to_string(),
+        ));
+    }
+    let filter = installation_filter(ctx, body).await?;
+    let data = body
+        .get_document("data")
+        .map_err(|_| Error::PushMisconfigured("Missing push data.".to_string()))?;
+
+    let now = Utc::now();
+    let push_time = match body.get("push_time") {
+        Some(push_time) => parse_date("push_time", push_time)?,
+        None => now,
+    };
+    let scheduled = push_time > now;
+
+    let status_id = util::new_object_id();
+    let mut status = doc! {
+        "_id": &status_id,
+        "pushTime": util::to_iso_string(&push_time),
+        "query": to_json(&filter),
+        "payload": to_json(data),
+        "source": "rest",
+        "status": if scheduled { "scheduled" } else { "pending" },
+        "numSent": 0,
+        "_created_at": now,
+        "_updated_at": now,
+        "_rperm": [],
+        "_wperm": [],
+        "_acl": {},
+    };
+    if let Ok(title) = data.get_str("title") {
+        status.insert("title", title);
+    }
+    set_expiration(body, &mut status)?;
+    ctx.db
+        .insert_document("_PushStatus", status.clone())
+        .await?;
+
+    if let Some(audience_id) = audience_id(body) {
+        record_audience_use(ctx, audience_id).await?;
+    }
+
+    if !scheduled {
+        let ctx = ctx.for_class("_Installation");
+        actix_web::rt::spawn(async move { dispatch(&ctx, &status).await });
+    }
+    Ok(status_id)
+}
+
+/// Claims and delivers the scheduled pushes that are due. Claiming is a
+/// conditional update, so only one of several servers sends each push.
+async fn dispatch_scheduled(ctx: &Context) -> Result<(), Error> {
+    let now = util::to_iso_string(&Utc::now());
+    let filter = doc! {"status": "scheduled", "pushTime": {"$lte": now}};
+    for status in ctx.db.find_documents("_PushStatus", filter).await? {
+        let id = status.get_str("_id").unwrap_or("");
+        let claimed = ctx
+            .db
+            .update_documents(
+                "_PushStatus",
+                doc! {"_id": id, "status": "scheduled"},
+                doc! {"$set": {"status": "pending", "_updated_at": Utc::now()}},
+            )
+            .await?;
+        if claimed == 1 {
+            dispatch(ctx, &status).await;
+        }
+    }
+    Ok(())
+}
+
+/// Runs forever, sending scheduled pushes once their `pushTime` has come.
+pub async fn run_scheduler(ctx: Context) {
+    let mut interval = actix_web::rt::time::interval(SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = dispatch_scheduled(&ctx).await {
+            error!("Could not dispatch scheduled pushes: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::{InMemoryPushAdapter, PushAdapter, PushResult};
+    use actix_web::rt::System;
+    use async_trait::async_trait;
+
+    /// Fails every send, like a push service that is down.
+    struct FailingPushAdapter;
+
+    #[async_trait(?Send)]
+    impl PushAdapter for FailingPushAdapter {
+        fn device_types(&self) -> Vec<String> {
+            vec!["android".to_string()]
+        }
+
+        async fn send(
+            &self,
+            _data: &Document,
+            _installations: &[Document],
+            _expiration_time: Option<DateTime<Utc>>,
+        ) -> Result<Vec<PushResult>, Error> {
+            Err(Error::Internal("service unavailable".to_string()))
+        }
+    }
+
+    fn installation(id: &str, device_type: &str, locale: &str) -> Document {
+        doc! {
+            "objectId": id,
+            "deviceType": device_type,
+            "deviceToken": format!("token-{}", id),
+            "localeIdentifier": locale,
+        }
+    }
+
+    fn send(
+        adapters: PushAdapters,
+        data: Document,
+        installations: Vec<Document>,
+        badges: HashMap<String, Bson>,
+    ) -> Document {
+        System::new("test").block_on(async move {
+            let batches = batches(&data, installations, &badges);
+            send_batches(&adapters, "status1", batches, None).await
+        })
+    }
+
+    #[test]
+    fn sends_localized_batches_and_counts_them() {
+        let outbox = InMemoryPushAdapter::default();
+        let mut adapters = PushAdapters::new();
+        adapters.insert(outbox.clone());
+        let data = doc! {"alert": "Hello", "alert-fr": "Bonjour", "badge": "Increment"};
+        let installations = vec![
+            installation("a", "ios", "fr-FR"),
+            installation("b", "ios", "en-US"),
+            installation("c", "android", "fr-CA"),
+            installation("d", "winphone", "en-US"),
+        ];
+        let badges = vec![("a".to_string(), Bson::Int64(3))]
+            .into_iter()
+            .collect();
+        let status = send(adapters, data, installations, badges);
+
+        assert_eq!(status.get_str("status"), Ok("succeeded"));
+        assert_eq!(status.get_i64("numSent"), Ok(3));
+        assert_eq!(status.get_i64("numFailed"), Ok(0));
+        assert_eq!(status.get_i64("count"), Ok(3));
+        let sent_per_type = status.get_document("sentPerType").unwrap();
+        assert_eq!(sent_per_type.get_i64("ios"), Ok(2));
+        assert_eq!(sent_per_type.get_i64("android"), Ok(1));
+
+        let sent = outbox.sent();
+        let push = |token: &str| {
+            let push = sent.iter().find(|x| x.device_token == token).unwrap();
+            push.data.clone()
+        };
+        assert_eq!(push("token-a"), doc! {"alert": "Bonjour", "badge": 3_i64});
+        // Increments without a stored badge are dropped
+        assert_eq!(push("token-b"), doc! {"alert": "Hello"});
+        assert_eq!(push("token-c"), doc! {"alert": "Bonjour"});
+    }
+
+    #[test]
+    fn counts_failed_batches_and_still_sends_the_others() {
+        let outbox = InMemoryPushAdapter::new(&["ios"]);
+        let mut adapters = PushAdapters::new();
+        adapters.insert(outbox.clone());
+        adapters.insert(FailingPushAdapter);
+        let installations = vec![
+            installation("a", "ios", "en"),
+            installation("b", "android", "en"),
+            installation("c", "android", "fr"),
+        ];
+        let status = send(
+            adapters,
+            doc! {"alert": "Hi"},
+            installations,
+            HashMap::new(),
+        );
+
+        assert_eq!(status.get_str("status"), Ok("succeeded"));
+        assert_eq!(status.get_i64("numSent"), Ok(1));
+        assert_eq!(status.get_i64("numFailed"), Ok(2));
+        assert_eq!(status.get_i64("count"), Ok(3));
+        let failed_per_type = status.get_document("failedPerType").unwrap();
+        assert_eq!(failed_per_type.get_i64("ios"), Ok(0));
+        assert_eq!(failed_per_type.get_i64("android"), Ok(2));
+        assert_eq!(outbox.sent().len(), 1);
+    }
+
+    #[test]
+    fn picks_the_most_specific_locale() {
+        let locales = push_locales(&doc! {"alert-en": "a", "title-fr": "b", "alert-fr-CA": "c"});
+        assert_eq!(locales, vec!["en", "fr", "fr-CA"]);
+        let locale = |identifier: &str| {
+            installation_locale(&doc! {"localeIdentifier": identifier}, &locales)
+        };
+        assert_eq!(locale("en-US"), Some("en"));
+        assert_eq!(locale("en_GB"), Some("en"));
+        assert_eq!(locale("fr-CA"), Some("fr-CA"));
+        assert_eq!(locale("fr-FR"), Some("fr"));
+        assert_eq!(locale("de-DE"), None);
+        // `english` is not an `en` locale
+        assert_eq!(locale("english"), None);
+        assert_eq!(installation_locale(&doc! {}, &locales), None);
+    }
+
+    #[test]
+    fn localizes_alert_and_title() {
+        let data = doc! {
+            "alert": "Hello",
+            "title": "Title",
+            "alert-fr": "Bonjour",
+            "title-de": "Titel",
+            "sound": "default",
+        };
+        // Field order changes when a localized value replaces the default
+        let localized = |locale| {
+            let data = localized_data(&data, locale);
+            let field = |key| data.get_str(key).unwrap_or("").to_string();
+            (field("alert"), field("title"), field("sound"), data.len())
+        };
+        let expected = |alert: &str, title: &str| {
+            (
+                alert.to_string(),
+                title.to_string(),
+                "default".to_string(),
+                3,
+            )
+        };
+        assert_eq!(localized(Some("fr")), expected("Bonjour", "Title"));
+        assert_eq!(localized(Some("de")), expected("Hello", "Titel"));
+        assert_eq!(localized(None), expected("Hello", "Title"));
+    }
+
+    #[test]
+    fn reads_badge_increments() {
+        assert_eq!(badge_increment(&doc! {"badge": "Increment"}), Some(1));
+        assert_eq!(badge_increment(&doc! {"badge": "increment"}), Some(1));
+        assert_eq!(
+            badge_increment(&doc! {"badge": {"__op": "Increment", "amount": 3}}),
+            Some(3)
+        );
+        assert_eq!(
+            badge_increment(&doc! {"badge": {"__op": "Increment"}}),
+            Some(1)
+        );
+        assert_eq!(badge_increment(&doc! {"badge": 5}), None);
+        assert_eq!(badge_increment(&doc! {}), None);
+    }
+
+    #[test]
+    fn stores_expiration_time_or_interval() {
+        let mut status = doc! {};
+        let body = doc! {"expiration_time": "2030-01-01T00:00:00.000Z"};
+        assert!(set_expiration(&body, &mut status).is_ok());
+        let expiry = util::parse_iso("2030-01-01T00:00:00.000Z").unwrap();
+        assert_eq!(status.get_i64("expiry"), Ok(expiry.timestamp()));
+
+        let mut status = doc! {};
+        assert!(set_expiration(&doc! {"expiration_interval": 60}, &mut status).is_ok());
+        assert_eq!(status.get_i64("expiration_interval"), Ok(60));
+
+        let invalid = vec![
+            doc! {"expiration_time": "soon"},
+            doc! {"expiration_interval": 0},
+            doc! {"expiration_interval": "60"},
+            doc! {"expiration_time": 1_900_000_000, "expiration_interval": 60},
+        ];
+        for body in invalid {
+            match set_expiration(&body, &mut doc! {}) {
+                Err(Error::PushMisconfigured(_)) => {}
+                _ => panic!("{} was accepted", body),
+            }
+        }
+    }
+
+    #[test]
+    fn expires_relative_to_the_push_time() {
+        let push_time = Utc.timestamp(1_900_000_000, 0);
+        let status = doc! {"expiration_interval": 3600_i64};
+        assert_eq!(
+            expiration_time(&status, push_time),
+            Some(Utc.timestamp(1_900_003_600, 0))
+        );
+        let status = doc! {"expiry": 1_800_000_000_i64};
+        assert_eq!(
+            expiration_time(&status, push_time),
+            Some(Utc.timestamp(1_800_000_000, 0))
+        );
+        assert_eq!(expiration_time(&doc! {}, push_time), None);
+    }
+
+    #[test]
+    fn uses_the_audience_only_without_where_or_channels() {
+        let audience = doc! {"audience_id": "aud1", "data": {"alert": "Hi"}};
+        assert_eq!(audience_id(&audience), Some("aud1"));
+        let mut with_where = audience.clone();
+        with_where.insert("where", doc! {"deviceType": "ios"});
+        assert_eq!(audience_id(&with_where), None);
+        let mut with_channels = audience.clone();
+        with_channels.insert("channels", vec!["news"]);
+        assert_eq!(audience_id(&with_channels), None);
+        assert_eq!(audience_id(&doc! {"channels": ["news"]}), None);
+    }
+}
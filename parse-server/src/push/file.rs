@@ -0,0 +1,107 @@
+use super::{PushAdapter, PushResult};
+use crate::error::Error;
+use crate::util;
+use actix_web::web;
+use async_trait::async_trait;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Appends each push as a JSON line to a file instead of delivering it.
+#[derive(Clone)]
+pub struct FilePushAdapter {
+    pub path: PathBuf,
+    pub device_types: Vec<String>,
+}
+
+impl FilePushAdapter {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FilePushAdapter {
+            path: path.into(),
+            device_types: vec!["ios".to_string(), "android".to_string()],
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl PushAdapter for FilePushAdapter {
+    fn device_types(&self) -> Vec<String> {
+        self.device_types.clone()
+    }
+
+    async fn send(
+        &self,
+        data: &Document,
+        installations: &[Document],
+        expiration_time: Option<DateTime<Utc>>,
+    ) -> Result<Vec<PushResult>, Error> {
+        let lines: Vec<(String, String, String)> = installations
+            .iter()
+            .map(|installation| {
+                let device_type = installation.get_str("deviceType").unwrap_or("");
+                let device_token = installation.get_str("deviceToken").unwrap_or("");
+                let mut line = doc! {
+                    "deviceType": device_type,
+                    "deviceToken": device_token,
+                    "data": data.clone(),
+                };
+                if let Some(expiration_time) = expiration_time {
+                    line.insert("expirationTime", util::to_iso_string(&expiration_time));
+                }
+                let line = Bson::Document(line).into_relaxed_extjson().to_string();
+                (device_type.to_string(), device_token.to_string(), line)
+            })
+            .collect();
+
+        // File writes block, so they run on the thread pool
+        let path = self.path.clone();
+        web::block(move || -> Result<Vec<PushResult>, std::io::Error> {
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            Ok(lines
+                .into_iter()
+                .map(|(device_type, device_token, line)| {
+                    let written = writeln!(file, "{}", line);
+                    PushResult {
+                        device_token,
+                        device_type,
+                        transmitted: written.is_ok(),
+                        error: written.err().map(|e| e.to_string()),
+                    }
+                })
+                .collect())
+        })
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::System;
+    use std::fs;
+
+    #[test]
+    fn appends_a_line_per_installation() {
+        let path = std::env::temp_dir().join(format!("push-{}.jsonl", util::new_object_id()));
+        let adapter = FilePushAdapter::new(&path);
+        let installations = vec![
+            doc! {"deviceType": "ios", "deviceToken": "a"},
+            doc! {"deviceType": "android", "deviceToken": "b"},
+        ];
+        let results = System::new("test").block_on(async move {
+            let data = doc! {"alert": "hi"};
+            adapter.send(&data, &installations, None).await
+        });
+        assert!(results.unwrap().iter().all(|x| x.transmitted));
+
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"deviceToken\":\"a\""));
+        assert!(lines[1].contains("\"alert\":\"hi\""));
+    }
+}
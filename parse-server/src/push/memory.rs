@@ -0,0 +1,83 @@
+use super::{PushAdapter, PushResult};
+use crate::error::Error;
+use async_trait::async_trait;
+use bson::Document;
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex};
+
+/// A push delivered to one installation.
+#[derive(Clone, Debug)]
+pub struct SentPush {
+    pub device_type: String,
+    pub device_token: String,
+    pub data: Document,
+    pub expiration_time: Option<DateTime<Utc>>,
+}
+
+/// Keeps pushes in memory instead of delivering them. Clones share the same
+/// outbox, so a test can keep one and pass the other to the server.
+#[derive(Clone)]
+pub struct InMemoryPushAdapter {
+    device_types: Vec<String>,
+    outbox: Arc<Mutex<Vec<SentPush>>>,
+}
+
+impl Default for InMemoryPushAdapter {
+    fn default() -> Self {
+        InMemoryPushAdapter::new(&["ios", "android"])
+    }
+}
+
+impl InMemoryPushAdapter {
+    pub fn new(device_types: &[&str]) -> Self {
+        InMemoryPushAdapter {
+            device_types: device_types.iter().map(|x| x.to_string()).collect(),
+            outbox: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn sent(&self) -> Vec<SentPush> {
+        self.outbox.lock().expect("Mutex poisoned").clone()
+    }
+
+    pub fn clear(&self) {
+        self.outbox.lock().expect("Mutex poisoned").clear();
+    }
+}
+
+#[async_trait(?Send)]
+impl PushAdapter for InMemoryPushAdapter {
+    fn device_types(&self) -> Vec<String> {
+        self.device_types.clone()
+    }
+
+    async fn send(
+        &self,
+        data: &Document,
+        installations: &[Document],
+        expiration_time: Option<DateTime<Utc>>,
+    ) -> Result<Vec<PushResult>, Error> {
+        let mut outbox = self.outbox.lock().expect("Mutex poisoned");
+        Ok(installations
+            .iter()
+            .map(|installation| {
+                let push = SentPush {
+                    device_type: installation.get_str("deviceType").unwrap_or("").to_string(),
+                    device_token: installation
+                        .get_str("deviceToken")
+                        .unwrap_or("")
+                        .to_string(),
+                    data: data.clone(),
+                    expiration_time,
+                };
+                outbox.push(push.clone());
+                PushResult {
+                    device_token: push.device_token,
+                    device_type: push.device_type,
+                    transmitted: true,
+                    error: None,
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::error::Error;
+use async_trait::async_trait;
+use bson::Document;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+mod controller;
+mod file;
+mod memory;
+
+pub use controller::{run_scheduler, send_push};
+pub use file::FilePushAdapter;
+pub use memory::{InMemoryPushAdapter, SentPush};
+
+/// Outcome of a push for one installation.
+#[derive(Clone, Debug)]
+pub struct PushResult {
+    pub device_token: String,
+    pub device_type: String,
+    pub transmitted: bool,
+    pub error: Option<String>,
+}
+
+/// Delivers push payloads to the installations of some device types.
+#[async_trait(?Send)]
+pub trait PushAdapter: Send + Sync {
+    /// Device types this adapter delivers to, e.g. `ios` or `android`.
+    fn device_types(&self) -> Vec<String>;
+
+    /// Sends the push `data` to installations of one device type, services
+    /// should drop it once `expiration_time` has passed.
+    async fn send(
+        &self,
+        data: &Document,
+        installations: &[Document],
+        expiration_time: Option<DateTime<Utc>>,
+    ) -> Result<Vec<PushResult>, Error>;
+}
+
+/// Push adapters keyed by device type.
+#[derive(Clone, Default)]
+pub struct PushAdapters {
+    adapters: HashMap<String, Arc<dyn PushAdapter>>,
+}
+
+impl PushAdapters {
+    pub fn new() -> Self {
+        PushAdapters::default()
+    }
+
+    /// Registers the adapter for each of its device types.
+    pub fn insert<A: PushAdapter + 'static>(&mut self, adapter: A) {
+        let adapter: Arc<dyn PushAdapter> = Arc::new(adapter);
+        for device_type in adapter.device_types() {
+            self.adapters.insert(device_type, adapter.clone());
+        }
+    }
+
+    pub fn get(&self, device_type: &str) -> Option<Arc<dyn PushAdapter>> {
+        self.adapters.get(device_type).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adapters.is_empty()
+    }
+}
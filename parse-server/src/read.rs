@@ -1,193 +1,13 @@
+use crate::cloud::{self, BeforeFind, FindTriggerRequest, Query};
 use crate::error::Error;
-use crate::operation::{Context, Request};
+use crate::operation::{Context, FindRequest, Request};
+use crate::schema::Schema;
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
 
-// TODO: rewrite to rust
+// TODO: redirectClassNameForKey, relation queries
 async fn redirect_class_name_for_key() -> Result<(), Error> {
     Ok(())
-    // if (this.response || this.className !== '_Installation') {
-    //     return;
-    // }
-    //   if (!this.query && !this.data.deviceToken && !this.data.installationId && !this.auth.installationId) {
-    //     throw new Parse.Error(135, 'at least one ID field (deviceToken, installationId) ' + 'must be specified in this operation');
-    //   } // If the device token is 64 characters long, we assume it is for iOS
-    //   // and lowercase it.
-    //   if (this.data.deviceToken && this.data.deviceToken.length == 64) {
-    //     this.data.deviceToken = this.data.deviceToken.toLowerCase();
-    //   } // We lowercase the installationId if present
-    //   if (this.data.installationId) {
-    //     this.data.installationId = this.data.installationId.toLowerCase();
-    //   }
-    //   let installationId = this.data.installationId; // If data.installationId is not set and we're not master, we can lookup in auth
-    //   if (!installationId && !this.auth.isMaster) {
-    //     installationId = this.auth.installationId;
-    //   }
-    //   if (installationId) {
-    //     installationId = installationId.toLowerCase();
-    //   } // Updating _Installation but not updating anything critical
-    //   if (this.query && !this.data.deviceToken && !installationId && !this.data.deviceType) {
-    //     return;
-    //   }
-    //   var promise = Promise.resolve();
-    //   var idMatch; // Will be a match on either objectId or installationId
-    //   var objectIdMatch;
-    //   var installationIdMatch;
-    //   var deviceTokenMatches = []; // Instead of issuing 3 reads, let's do it with one OR.
-    //   const orQueries = [];
-    //   if (this.query && this.query.objectId) {
-    //     orQueries.push({
-    //       objectId: this.query.objectId
-    //     });
-    //   }
-    //   if (installationId) {
-    //     orQueries.push({
-    //       installationId: installationId
-    //     });
-    //   }
-    //   if (this.data.deviceToken) {
-    //     orQueries.push({
-    //       deviceToken: this.data.deviceToken
-    //     });
-    //   }
-    //   if (orQueries.length == 0) {
-    //     return;
-    //   }
-    //   promise = promise.then(() => {
-    //     return this.config.database.find('_Installation', {
-    //       $or: orQueries
-    //     }, {});
-    //   }).then(results => {
-    //     results.forEach(result => {
-    //       if (this.query && this.query.objectId && result.objectId == this.query.objectId) {
-    //         objectIdMatch = result;
-    //       }
-    //       if (result.installationId == installationId) {
-    //         installationIdMatch = result;
-    //       }
-    //       if (result.deviceToken == this.data.deviceToken) {
-    //         deviceTokenMatches.push(result);
-    //       }
-    //     }); // Sanity checks when running a query
-    //     if (this.query && this.query.objectId) {
-    //       if (!objectIdMatch) {
-    //         throw new Parse.Error(Parse.Error.OBJECT_NOT_FOUND, 'Object not found for update.');
-    //       }
-    //       if (this.data.installationId && objectIdMatch.installationId && this.data.installationId !== objectIdMatch.installationId) {
-    //         throw new Parse.Error(136, 'installationId may not be changed in this ' + 'operation');
-    //       }
-    //       if (this.data.deviceToken && objectIdMatch.deviceToken && this.data.deviceToken !== objectIdMatch.deviceToken && !this.data.installationId && !objectIdMatch.installationId) {
-    //         throw new Parse.Error(136, 'deviceToken may not be changed in this ' + 'operation');
-    //       }
-    //       if (this.data.deviceType && this.data.deviceType && this.data.deviceType !== objectIdMatch.deviceType) {
-    //         throw new Parse.Error(136, 'deviceType may not be changed in this ' + 'operation');
-    //       }
-    //     }
-    //     if (this.query && this.query.objectId && objectIdMatch) {
-    //       idMatch = objectIdMatch;
-    //     }
-    //     if (installationId && installationIdMatch) {
-    //       idMatch = installationIdMatch;
-    //     } // need to specify deviceType only if it's new
-    //     if (!this.query && !this.data.deviceType && !idMatch) {
-    //       throw new Parse.Error(135, 'deviceType must be specified in this operation');
-    //     }
-    //   }).then(() => {
-    //     if (!idMatch) {
-    //       if (!deviceTokenMatches.length) {
-    //         return;
-    //       } else if (deviceTokenMatches.length == 1 && (!deviceTokenMatches[0]['installationId'] || !installationId)) {
-    //         // Single match on device token but none on installationId, and either
-    //         // the passed object or the match is missing an installationId, so we
-    //         // can just return the match.
-    //         return deviceTokenMatches[0]['objectId'];
-    //       } else if (!this.data.installationId) {
-    //         throw new Parse.Error(132, 'Must specify installationId when deviceToken ' + 'matches multiple Installation objects');
-    //       } else {
-    //         // Multiple device token matches and we specified an installation ID,
-    //         // or a single match where both the passed and matching objects have
-    //         // an installation ID. Try cleaning out old installations that match
-    //         // the deviceToken, and return nil to signal that a new object should
-    //         // be created.
-    //         var delQuery = {
-    //           deviceToken: this.data.deviceToken,
-    //           installationId: {
-    //             $ne: installationId
-    //           }
-    //         };
-    //         if (this.data.appIdentifier) {
-    //           delQuery['appIdentifier'] = this.data.appIdentifier;
-    //         }
-    //         this.config.database.destroy('_Installation', delQuery).catch(err => {
-    //           if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //             // no deletions were made. Can be ignored.
-    //             return;
-    //           } // rethrow the error
-    //           throw err;
-    //         });
-    //         return;
-    //       }
-    //     } else {
-    //       if (deviceTokenMatches.length == 1 && !deviceTokenMatches[0]['installationId']) {
-    //         // Exactly one device token match and it doesn't have an installation
-    //         // ID. This is the one case where we want to merge with the existing
-    //         // object.
-    //         const delQuery = {
-    //           objectId: idMatch.objectId
-    //         };
-    //         return this.config.database.destroy('_Installation', delQuery).then(() => {
-    //           return deviceTokenMatches[0]['objectId'];
-    //         }).catch(err => {
-    //           if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //             // no deletions were made. Can be ignored
-    //             return;
-    //           } // rethrow the error
-    //           throw err;
-    //         });
-    //       } else {
-    //         if (this.data.deviceToken && idMatch.deviceToken != this.data.deviceToken) {
-    //           // We're setting the device token on an existing installation, so
-    //           // we should try cleaning out old installations that match this
-    //           // device token.
-    //           const delQuery = {
-    //             deviceToken: this.data.deviceToken
-    //           }; // We have a unique install Id, use that to preserve
-    //           // the interesting installation
-    //           if (this.data.installationId) {
-    //             delQuery['installationId'] = {
-    //               $ne: this.data.installationId
-    //             };
-    //           } else if (idMatch.objectId && this.data.objectId && idMatch.objectId == this.data.objectId) {
-    //             // we passed an objectId, preserve that instalation
-    //             delQuery['objectId'] = {
-    //               $ne: idMatch.objectId
-    //             };
-    //           } else {
-    //             // What to do here? can't really clean up everything...
-    //             return idMatch.objectId;
-    //           }
-    //           if (this.data.appIdentifier) {
-    //             delQuery['appIdentifier'] = this.data.appIdentifier;
-    //           }
-    //           this.config.database.destroy('_Installation', delQuery).catch(err => {
-    //             if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //               // no deletions were made. Can be ignored.
-    //               return;
-    //             } // rethrow the error
-    //             throw err;
-    //           });
-    //         } // In non-merge scenarios, just return the installation match id
-    //         return idMatch.objectId;
-    //       }
-    //     }
-    //   }).then(objId => {
-    //     if (objId) {
-    //       this.query = {
-    //         objectId: objId
-    //       };
-    //       delete this.data.objectId;
-    //       delete this.data.createdAt;
-    //     } // TODO: Validate ops (add/remove on channels, $inc on badge, etc.)
-    //   });
-    //   return promise;
 }
 
 async fn validate_class_creation(req: &Request, ctx: &Context) -> Result<(), Error> {
@@ -321,84 +141,466 @@ async fn replace_in_query(req: &Request, ctx: &Context) -> Result<(), Error> {
     Ok(())
 }
 
-async fn replace_not_query() {}
+async fn run_find(req: &Request, ctx: &Context) -> Result<Document, Error> {
+    match req {
+        Request::Find(req) => {
+            if req.count && req.limit == Some(0) {
+                return Ok(doc! {"results": []});
+            }
+            let results = ctx.db.query_objects(req, ctx).await?;
+            Ok(doc! {"results": results})
+        }
+        Request::Get(req) => {
+            let mut filter = doc! {"objectId": &req.objectId};
+            if let Some(constraint) = &req.filter {
+                filter = doc! {"$and": [filter, constraint.clone()]};
+            }
+            let find = FindRequest {
+                include: req.include.clone(),
+                filter: Some(filter),
+                limit: Some(1),
+                skip: None,
+                sort: None,
+                count: false,
+                keys: vec![],
+                read_preference: req.read_preference.clone(),
+            };
+            match ctx.db.query_objects(&find, ctx).await?.pop() {
+                Some(object) => Ok(object),
+                None => Err(Error::NotFound("Object not found.".to_string())),
+            }
+        }
+        _ => Ok(doc! {}),
+    }
+}
 
-async fn replace_equality() {}
+async fn run_count(req: &Request, ctx: &Context, response: &mut Document) -> Result<(), Error> {
+    if let Request::Find(req) = req {
+        if req.count {
+            let count = ctx.db.count_objects(req, ctx).await?;
+            response.insert("count", count);
+        }
+    }
+    Ok(())
+}
 
-async fn handle_include_all() {}
+async fn handle_include() {}
 
-async fn handle_exclude_keys() {}
+/// Whether a `protectedFields` rule key applies to the current user and object.
+fn protected_rule_applies(key: &str, ctx: &Context, object: &Document) -> bool {
+    let user_id = match &ctx.user.id {
+        Some(id) => id.as_str(),
+        None => return key == "*",
+    };
+    match key {
+        "*" | "authenticated" => true,
+        key if key.starts_with("role:") => ctx.user.user_roles.iter().any(|role| role == key),
+        key if key.starts_with("userField:") => {
+            points_to_user(object.get(&key["userField:".len()..]), user_id)
+        }
+        key => key == user_id,
+    }
+}
 
-async fn run_find(req: &Request, ctx: &Context) -> Result<(), Error> {
-    Ok(())
+fn points_to_user(value: Option<&Bson>, user_id: &str) -> bool {
+    match value {
+        Some(Bson::Document(pointer)) => pointer.get_str("objectId") == Ok(user_id),
+        Some(Bson::Array(values)) => values.iter().any(|x| points_to_user(Some(x), user_id)),
+        _ => false,
+    }
 }
 
-async fn run_count(req: &Request) -> Result<(), Error> {
-    // if req.class_name != "_Session" || req.query.is_some() {
-    //     return Ok(());
-    // }
+/// Fields of an object hidden from the current user. When several rules apply
+/// only the fields protected by all of them are hidden, so the most permissive
+/// rule wins.
+fn protected_fields(schema: &Schema, ctx: &Context, object: &Document) -> Vec<String> {
+    let mut rules = schema.permissions.protected_fields.clone();
+    if schema.name == "_User" {
+        // Users can always see their own data
+        if ctx.user.id.is_some() && object.get_str("objectId").ok() == ctx.user.id.as_deref() {
+            return vec![];
+        }
+        rules
+            .entry("*".to_string())
+            .or_insert_with(|| vec!["email".to_string()]);
+    }
 
-    // let user = req.data.get_document("user");
-    // let installation_id = req.data.get_str("installationId").unwrap_or("");
-    // let session_token = req.data.get_str("sessionToken").unwrap_or("");
+    let mut applicable = rules
+        .iter()
+        .filter(|(key, _)| protected_rule_applies(key, ctx, object))
+        .map(|(_, fields)| fields);
+    let first = match applicable.next() {
+        Some(fields) => fields.clone(),
+        None => return vec![],
+    };
+    applicable.fold(first, |protected, fields| {
+        protected
+            .into_iter()
+            .filter(|field| fields.contains(field))
+            .collect()
+    })
+}
 
-    // if user.is_err() || installation_id == "" {
-    //     return Ok(());
-    // }
-    // if !user.object_id {
-    //     return Ok(())
-    // }
-    Ok(())
-    // TODO: handle
-    // db.destroy_session("").await?;
+fn strip_object(object: &mut Document, class: &str, include: &[String], ctx: &Context) {
+    let schema = ctx
+        .cache
+        .get_schema(class)
+        .unwrap_or_else(|| Schema::new(class.to_string()));
+
+    for field in protected_fields(&schema, ctx, object) {
+        object.remove(&field);
+    }
+    // Linked accounts are only visible to the user itself
+    if class == "_User" && object.get_str("objectId").ok() != ctx.user.id.as_deref() {
+        object.remove("authData");
+    }
+
+    // `a.b` includes `a`, then `b` of the included object
+    let mut nested: HashMap<&str, Vec<String>> = HashMap::new();
+    for path in include {
+        let (key, rest) = match path.find('.') {
+            Some(dot) => (&path[..dot], Some(&path[dot + 1..])),
+            None => (path.as_str(), None),
+        };
+        let paths = nested.entry(key).or_default();
+        if let Some(rest) = rest {
+            paths.push(rest.to_string());
+        }
+    }
+    for (key, paths) in nested {
+        let target = match schema.fields.get(key).and_then(|x| x.target_type.as_ref()) {
+            Some(target) => target,
+            None => continue,
+        };
+        if let Some(Bson::Document(included)) = object.get_mut(key) {
+            strip_object(included, target, &paths, ctx);
+        }
+    }
 }
 
-async fn handle_include() {}
+fn strip_protected_fields(req: &Request, ctx: &Context, response: &mut Document) {
+    if ctx.user.is_master {
+        return;
+    }
+    match req {
+        Request::Find(req) => {
+            if let Some(Bson::Array(results)) = response.get_mut("results") {
+                for result in results.iter_mut() {
+                    if let Bson::Document(object) = result {
+                        strip_object(object, &ctx.class, &req.include, ctx);
+                    }
+                }
+            }
+        }
+        Request::Get(req) => strip_object(response, &ctx.class, &req.include, ctx),
+        _ => {}
+    }
+}
 
-async fn run_after_find_trigger(req: &Request) -> Result<(), Error> {
-    // if req.class_name != "_User" {
-    //     return Ok(());
-    //   } // Don't generate session for updating user (this.query is set) unless authData exists
-    //   if req.query.is_some() && !req.data.contains_key("authData") {
-    //     return Ok(());
-    //   } // Don't generate new sessionToken if linking via sessionToken
-    //   if req.auth.user.is_some() && req.data.contains_key("authData") {
-    //     return Ok(());
-    //   }
-    //   if (!this.storage['authProvider'] && // signup call, with
-    //   this.config.preventLoginWithUnverifiedEmail && // no login without verification
-    //   this.config.verifyUserEmails) {
-    //     // verification is on
-    //     return; // do not create the session token in that case!
-    //   }
-    Ok(())
-    // TODO:
-    //   return this.createSessionToken();
+/// Adapters decide what users see of their authData, e.g. no MFA secrets.
+fn hide_auth_data(req: &Request, ctx: &Context, response: &mut Document) {
+    if ctx.class != "_User" {
+        return;
+    }
+    match req {
+        Request::Find(_) => {
+            if let Some(Bson::Array(results)) = response.get_mut("results") {
+                for result in results.iter_mut() {
+                    if let Bson::Document(object) = result {
+                        ctx.config.auth.hide_auth_data(object);
+                    }
+                }
+            }
+        }
+        Request::Get(_) => ctx.config.auth.hide_auth_data(response),
+        _ => {}
+    }
+}
+
+/// Restricts an object to the requested keys, the default fields and the ACL
+/// are always returned.
+fn select_keys(keys: &[String], object: &mut Document) {
+    if keys.is_empty() {
+        return;
+    }
+    let removed: Vec<String> = object
+        .keys()
+        .filter(|key| !matches!(key.as_str(), "objectId" | "createdAt" | "updatedAt" | "ACL"))
+        .filter(|key| {
+            !keys
+                .iter()
+                .any(|x| x == *key || x.starts_with(&format!("{}.", key)))
+        })
+        .cloned()
+        .collect();
+    for key in removed {
+        object.remove(&key);
+    }
 }
 
-async fn handle_followup() {}
+fn select_response_keys(req: &Request, response: &mut Document) {
+    match req {
+        Request::Find(req) => {
+            if let Some(Bson::Array(results)) = response.get_mut("results") {
+                for result in results.iter_mut() {
+                    if let Bson::Document(object) = result {
+                        select_keys(&req.keys, object);
+                    }
+                }
+            }
+        }
+        Request::Get(req) => select_keys(&req.keys, response),
+        _ => {}
+    }
+}
 
-async fn run_after_save_trigger() {}
+fn trigger_query(req: &Request) -> Query {
+    match req {
+        Request::Find(req) => Query {
+            filter: req.filter.clone().unwrap_or_default(),
+            limit: req.limit,
+            skip: req.skip,
+            include: req.include.clone(),
+            keys: req.keys.clone(),
+            read_preference: req.read_preference.clone(),
+            count: req.count,
+        },
+        Request::Get(req) => {
+            let mut filter = doc! {"objectId": &req.objectId};
+            if let Some(constraint) = &req.filter {
+                filter = doc! {"$and": [filter, constraint.clone()]};
+            }
+            Query {
+                filter,
+                limit: Some(1),
+                include: req.include.clone(),
+                keys: req.keys.clone(),
+                read_preference: req.read_preference.clone(),
+                ..Query::default()
+            }
+        }
+        _ => Query::default(),
+    }
+}
+
+fn find_trigger_request(
+    req: &Request,
+    ctx: &Context,
+    objects: Vec<Document>,
+) -> FindTriggerRequest {
+    FindTriggerRequest {
+        class_name: ctx.class.clone(),
+        query: trigger_query(req),
+        objects,
+        is_get: matches!(req, Request::Get(_)),
+        user: ctx.user.clone(),
+        master: ctx.user.is_master,
+        context: ctx.cloud_context.clone(),
+    }
+}
+
+/// Runs `beforeFind`, before permissions are applied to the query. Returns
+/// the objects to answer with when the trigger skips the database.
+pub async fn run_before_find_trigger(
+    req: &mut Request,
+    ctx: &Context,
+) -> Result<Option<Vec<Document>>, Error> {
+    if !req.is_read() {
+        return Ok(None);
+    }
+    let trigger = match cloud::class_triggers(ctx).await?.before_find {
+        Some(trigger) => trigger,
+        None => return Ok(None),
+    };
+    let query = match trigger(find_trigger_request(req, ctx, vec![]))
+        .await
+        .map_err(cloud::validation_error)?
+    {
+        BeforeFind::Query(query) => query,
+        BeforeFind::Objects(objects) => return Ok(Some(objects)),
+    };
+
+    match req {
+        Request::Find(req) => {
+            req.filter = Some(query.filter).filter(|x| !x.is_empty());
+            req.limit = query.limit;
+            req.skip = query.skip;
+            req.include = query.include;
+            req.keys = query.keys;
+            req.read_preference = query.read_preference;
+            req.count = query.count;
+        }
+        Request::Get(req) => {
+            req.filter = Some(query.filter);
+            req.include = query.include;
+            req.keys = query.keys;
+            req.read_preference = query.read_preference;
+        }
+        _ => {}
+    }
+    Ok(None)
+}
+
+/// The response to objects a `beforeFind` trigger answered with.
+fn objects_response(req: &Request, mut objects: Vec<Document>) -> Result<Document, Error> {
+    match req {
+        Request::Get(_) if objects.is_empty() => {
+            Err(Error::NotFound("Object not found.".to_string()))
+        }
+        Request::Get(_) => Ok(objects.remove(0)),
+        Request::Find(req) if req.count => {
+            let count = objects.len() as i64;
+            let results = if req.limit == Some(0) {
+                vec![]
+            } else {
+                objects
+            };
+            Ok(doc! {"results": results, "count": count})
+        }
+        _ => Ok(doc! {"results": objects}),
+    }
+}
+
+/// Runs `afterFind`, which can change or drop the objects found.
+async fn run_after_find_trigger(
+    req: &Request,
+    ctx: &Context,
+    response: &mut Document,
+) -> Result<(), Error> {
+    let trigger = match cloud::class_triggers(ctx).await?.after_find {
+        Some(trigger) => trigger,
+        None => return Ok(()),
+    };
+    let objects = match (req, response.get_array("results")) {
+        (Request::Get(_), _) => vec![response.clone()],
+        (Request::Find(_), Ok(results)) => results
+            .iter()
+            .filter_map(|x| x.as_document().cloned())
+            .collect(),
+        _ => return Ok(()),
+    };
+    let mut objects = trigger(find_trigger_request(req, ctx, objects))
+        .await
+        .map_err(cloud::validation_error)?;
+
+    match req {
+        Request::Get(_) if objects.is_empty() => {
+            return Err(Error::NotFound("Object not found.".to_string()))
+        }
+        Request::Get(_) => *response = objects.remove(0),
+        _ => {
+            response.insert("results", objects);
+        }
+    }
+    Ok(())
+}
 
 // async fn clean_user_auth_data(req: ReadRequest, doc: &Document) -> Document {
 
 // }
 
-pub async fn read(req: Request, ctx: Context) -> Result<(), Error> {
+/// Runs a find or get, `objects` are the results of a `beforeFind` trigger
+/// that skipped the database.
+pub async fn read(
+    req: Request,
+    ctx: Context,
+    objects: Option<Vec<Document>>,
+) -> Result<Document, Error> {
     // let acl = util::get_acl(request).await?; // TODO
     redirect_class_name_for_key().await?; // TODO
     validate_class_creation(&req, &ctx).await?;
     replace_select(&req, &ctx).await?; // TODO
     replace_dont_select(&req, &ctx).await?; // TODO
     replace_in_query(&req, &ctx).await?; // TODO
-                                         // replace_not_query().await?; // TODO
-                                         // replace_equality().await?; // TODO
-
-    // handle_include_all().await?;
-    // handle_exclude_keys().await?;
-    run_find(&req, &ctx).await?;
-    run_count(&req).await?;
+    let mut response = match objects {
+        Some(objects) => objects_response(&req, objects)?,
+        None => {
+            let mut response = run_find(&req, &ctx).await?;
+            run_count(&req, &ctx, &mut response).await?;
+            response
+        }
+    };
     // handle_include().await?;
-    run_after_find_trigger(&req).await?;
-    Ok(())
+    run_after_find_trigger(&req, &ctx, &mut response).await?;
+    select_response_keys(&req, &mut response);
+    strip_protected_fields(&req, &ctx, &mut response);
+    hide_auth_data(&req, &ctx, &mut response);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_object;
+    use crate::cache::AppCache;
+    use crate::config::Config;
+    use crate::database::DbAdapter;
+    use crate::operation::Context;
+    use crate::schema::{Field, FieldType, Schema};
+    use crate::user::User;
+    use actix_web::rt::System;
+    use bson::{doc, Bson};
+    use std::sync::Arc;
+
+    fn schema(name: &str, pointers: &[(&str, &str)], protected: &[&str]) -> Schema {
+        let mut schema = Schema::new(name.to_string());
+        for (key, target) in pointers {
+            let field = Field {
+                name: key.to_string(),
+                field_type: FieldType::Pointer,
+                target_type: Some(target.to_string()),
+                required: false,
+                default_value: None,
+            };
+            schema.fields.insert(key.to_string(), field);
+        }
+        let protected = protected.iter().map(|x| x.to_string()).collect();
+        schema
+            .permissions
+            .protected_fields
+            .insert("*".to_string(), protected);
+        schema
+    }
+
+    #[test]
+    fn strips_nested_includes() {
+        System::new("test").block_on(async {
+            let cache = AppCache::new();
+            {
+                let mut schemas = cache.schema.write().unwrap();
+                let post = schema("Post", &[("author", "_User")], &["secret"]);
+                let user = schema("_User", &[("company", "Company")], &["email"]);
+                let company = schema("Company", &[], &["revenue"]);
+                for schema in [post, user, company] {
+                    schemas.insert(schema.name.clone(), schema);
+                }
+            }
+            let ctx = Context {
+                class: "Post".to_string(),
+                user: User {
+                    is_master: false,
+                    ..User::master()
+                },
+                db: Arc::new(DbAdapter::connect("mongodb://localhost/test").await),
+                cache: Arc::new(cache),
+                config: Arc::new(Config::default()),
+                cloud_context: doc! {},
+            };
+            let mut object = doc! {
+                "objectId": "p1",
+                "secret": "s",
+                "author": {
+                    "objectId": "u1",
+                    "email": "a@example.com",
+                    "company": {"objectId": "c1", "name": "Acme", "revenue": 10},
+                },
+            };
+            strip_object(&mut object, "Post", &["author.company".to_string()], &ctx);
+            assert!(!object.contains_key("secret"));
+            let author = object.get_document("author").unwrap();
+            assert!(!author.contains_key("email"));
+            let company = author.get_document("company").unwrap();
+            assert_eq!(company.get("name"), Some(&Bson::from("Acme")));
+            assert!(!company.contains_key("revenue"));
+        });
+    }
 }
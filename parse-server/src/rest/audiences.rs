@@ -0,0 +1,147 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
+
+use super::classes::{parse_class_request, parse_object_request};
+use super::{build_context, parse_payload, parse_query_string};
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::operation::{execute, Request};
+
+const CLASS_NAME: &str = "_Audience";
+
+/// Audiences store their installation query as a JSON string, the API takes
+/// and returns it as an object.
+fn stringify_query(payload: &mut Document) {
+    if let Ok(query) = payload.get_document("query") {
+        let query = Bson::Document(query.clone()).into_relaxed_extjson();
+        payload.insert("query", query.to_string());
+    }
+}
+
+fn parse_query(audience: &mut Document) {
+    if let Ok(query) = audience.get_str("query") {
+        if let Ok(query) = parse_payload(query) {
+            audience.insert("query", query);
+        }
+    }
+}
+
+/// Runs a request on `_Audience`, `object_id` selects a single object.
+async fn handle(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: &HttpRequest,
+    method: &str,
+    object_id: Option<String>,
+    mut payload: Document,
+) -> HttpResponse {
+    let context = match build_context(CLASS_NAME, req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    stringify_query(&mut payload);
+    let request = match object_id {
+        Some(object_id) => parse_object_request(method, object_id, &payload),
+        None => parse_class_request(method, &payload),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => return err.to_http_response(),
+    };
+
+    let is_create = matches!(request, Request::Create(_));
+    let mut result = match execute(request, context).await {
+        Ok(result) => result,
+        Err(err) => return err.to_http_response(),
+    };
+    match result.get_mut("results") {
+        Some(Bson::Array(results)) => {
+            for audience in results.iter_mut() {
+                if let Bson::Document(audience) = audience {
+                    parse_query(audience);
+                }
+            }
+        }
+        _ => parse_query(&mut result),
+    }
+    if is_create {
+        HttpResponse::Created().json(result)
+    } else {
+        HttpResponse::Ok().json(result)
+    }
+}
+
+#[post("/parse/push_audiences")]
+pub async fn create_audience(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    match parse_payload(&payload) {
+        Ok(payload) => handle(db, cache, config, &req, "POST", None, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/push_audiences")]
+pub async fn find_audiences(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    match parse_query_string(query.into_inner()) {
+        Ok(payload) => handle(db, cache, config, &req, "GET", None, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/push_audiences/{object_id}")]
+pub async fn get_audience(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    match parse_query_string(query.into_inner()) {
+        Ok(payload) => handle(db, cache, config, &req, "GET", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/push_audiences/{object_id}")]
+pub async fn update_audience(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    match parse_payload(&payload) {
+        Ok(payload) => handle(db, cache, config, &req, "PUT", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/push_audiences/{object_id}")]
+pub async fn delete_audience(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    handle(db, cache, config, &req, "DELETE", object_id, doc! {}).await
+}
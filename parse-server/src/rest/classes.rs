@@ -1,11 +1,15 @@
 use actix_web::{post, web, HttpRequest, HttpResponse};
 use bson::{doc, Bson, Document};
 
+use super::{build_context, parse_payload, strip_meta_keys};
 use crate::cache::AppCache;
+use crate::config::Config;
 use crate::database::DbAdapter;
 use crate::error::Error;
-use crate::operation::{execute, Context, FindRequest, Join, Request};
-use crate::user::User;
+use crate::operation::{
+    execute, Context, CreateRequest, DeleteRequest, FindRequest, GetRequest, Join, Request,
+    UpdateRequest,
+};
 
 fn parse_sort(payload: &Document) -> Option<Document> {
     let order = payload.get("order").and_then(|x| x.as_str()).unwrap_or("");
@@ -87,7 +91,9 @@ fn map_join(doc: &Document) -> Result<Join, Error> {
             limit: Some(1),
             skip: None,
             sort: None,
-            join: vec![],
+            count: false,
+            keys: vec![],
+            read_preference: None,
         },
     })
 }
@@ -113,83 +119,132 @@ fn parse_joins(filter: &Document) -> Result<Vec<Join>, Error> {
     Ok(result)
 }
 
-fn parse_user(payload: &Document) -> User {
-    User {
-        id: None,
-        application_id: payload
-            .get_str("_ApplicationId")
-            .ok()
-            .map(|x| x.to_string()),
-        installation_id: payload
-            .get_str("_InstallationId")
-            .ok()
-            .map(|x| x.to_string()),
-        is_master: false,
-        is_read_only: false,
-        user: None,
-        user_roles: vec![],
-        client_sdk: payload
-            .get_str("_ClientVersion")
-            .ok()
-            .map(|x| x.to_string()),
+/// JSON numbers are parsed as 32-bit integers when they fit.
+fn parse_integer(payload: &Document, key: &str) -> Option<i64> {
+    match payload.get(key) {
+        Some(Bson::Int32(value)) => Some(*value as i64),
+        Some(Bson::Int64(value)) => Some(*value),
+        _ => None,
     }
 }
 
-fn parse_find_request(payload: &Document) -> Result<Request, Error> {
+pub fn parse_include(payload: &Document) -> Vec<String> {
+    payload
+        .get_str("include")
+        .unwrap_or("")
+        .split(",")
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Fields to return, e.g. `keys=name,score`.
+pub fn parse_keys(payload: &Document) -> Vec<String> {
+    payload
+        .get_str("keys")
+        .unwrap_or("")
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect()
+}
+
+pub fn parse_find_request(payload: &Document) -> Result<Request, Error> {
+    // `$select` is only checked for now, the joins aren't run
+    parse_joins(payload)?;
     Ok(Request::Find(FindRequest {
         filter: payload.get_document("where").ok().map(|x| x.clone()),
-        include: payload
-            .get_str("include")
-            .unwrap_or("")
-            .split(",")
-            .map(|x| x.to_string())
-            .collect(),
-        limit: payload.get_i64("limit").ok().map(|x| x.clone()),
-        skip: payload.get_i64("skip").ok().map(|x| x.clone()),
+        include: parse_include(payload),
+        limit: parse_integer(payload, "limit"),
+        skip: parse_integer(payload, "skip"),
         sort: parse_sort(&payload),
-        join: parse_joins(&payload)?,
+        count: payload.get_i32("count").map(|x| x == 1).unwrap_or(false)
+            || payload.get_bool("count").unwrap_or(false),
+        keys: parse_keys(payload),
+        read_preference: payload.get_str("readPreference").ok().map(String::from),
     }))
 }
 
+pub fn invalid_method(method: &str) -> Error {
+    if method.is_empty() {
+        Error::BadFormat("Invalid method".to_string())
+    } else {
+        Error::BadFormat(format!("Invalid method: {}", method))
+    }
+}
+
+/// Find or create on a class, `method` is the `_method` override if any.
+pub fn parse_class_request(method: &str, payload: &Document) -> Result<Request, Error> {
+    match method {
+        "GET" => parse_find_request(payload),
+        "" | "POST" => Ok(Request::Create(CreateRequest {
+            params: strip_meta_keys(payload),
+        })),
+        _ => Err(invalid_method(method)),
+    }
+}
+
+/// Get, update or delete of one object, `method` is the HTTP method or the
+/// `_method` override.
+pub fn parse_object_request(
+    method: &str,
+    object_id: String,
+    payload: &Document,
+) -> Result<Request, Error> {
+    match method {
+        "GET" => Ok(Request::Get(GetRequest {
+            objectId: object_id,
+            include: parse_include(payload),
+            filter: None,
+            keys: parse_keys(payload),
+            read_preference: payload.get_str("readPreference").ok().map(String::from),
+        })),
+        "PUT" => Ok(Request::Update(UpdateRequest {
+            objectId: object_id,
+            filter: None,
+            params: strip_meta_keys(payload),
+        })),
+        "DELETE" => Ok(Request::Delete(DeleteRequest {
+            objectId: object_id,
+            filter: None,
+        })),
+        _ => Err(invalid_method(method)),
+    }
+}
+
+/// Runs a request, new objects are answered with `201 Created`.
+pub async fn respond(request: Request, context: Context) -> HttpResponse {
+    // Creates can be merged into an existing object, e.g. installations
+    let is_create = matches!(request, Request::Create(_));
+    match execute(request, context).await {
+        Ok(result) if is_create && result.contains_key("createdAt") => {
+            HttpResponse::Created().json(result)
+        }
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
+
 #[post("/parse/classes/{class_name}")]
 pub async fn query_documents(
     db: web::Data<DbAdapter>,
     cache: web::Data<AppCache>,
+    config: web::Data<Config>,
     payload: String,
-    _req: HttpRequest,
+    req: HttpRequest,
     class_name: web::Path<String>,
 ) -> HttpResponse {
-    trace!("REST message IN: {}", &payload);
-
-    let payload = match serde_json::from_str::<Document>(&payload) {
-        Ok(map) => map,
-        Err(e) => {
-            let message = format!("Could not parse json request: {}", e.to_string());
-            error!("{}", &message);
-            return Error::BadFormat(message).to_http_response();
-        }
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context(&class_name, &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
     };
 
-    let method = payload.get_str("_method").unwrap_or("");
-
-    match method {
-        "GET" => {
-            let context = Context {
-                class: class_name.to_string(),
-                db: db.into_inner(),
-                cache: cache.into_inner(),
-                user: parse_user(&payload),
-            };
-            let request = match parse_find_request(&payload) {
-                Ok(request) => request,
-                Err(err) => return err.to_http_response(),
-            };
-            match execute(request, context).await {
-                Ok(result) => HttpResponse::Ok().json(result),
-                Err(err) => err.to_http_response(),
-            }
-        }
-        _ => Error::BadFormat("".to_string()).to_http_response(),
+    match parse_class_request(payload.get_str("_method").unwrap_or(""), &payload) {
+        Ok(request) => respond(request, context).await,
+        Err(err) => err.to_http_response(),
     }
 
     // let allow_client_class_creation = true;
@@ -361,6 +416,32 @@ pub async fn query_documents(
     // }
 }
 
+#[post("/parse/classes/{class_name}/{object_id}")]
+pub async fn get_document(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let (class_name, object_id) = path.into_inner();
+    let context = match build_context(&class_name, &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+
+    let method = payload.get_str("_method").unwrap_or("");
+    match parse_object_request(method, object_id, &payload) {
+        Ok(request) => respond(request, context).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
 // #[get("/parse/classes/{class_name}")]
 // async fn asd() {
 
@@ -0,0 +1,182 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Bson, Document};
+
+use super::classes::{parse_class_request, parse_object_request};
+use super::{build_context, parse_payload};
+use crate::cache::AppCache;
+use crate::cloud;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::{execute, Context};
+
+const CLASS_NAME: &str = "_JobSchedule";
+
+async fn schedule_context(
+    req: &HttpRequest,
+    payload: &Document,
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+) -> Result<Context, Error> {
+    let context = build_context(CLASS_NAME, req, payload, db, cache, config).await?;
+    if !context.user.is_master {
+        return Err(Error::Forbidden(
+            "unauthorized: master key is required".to_string(),
+        ));
+    }
+    Ok(context)
+}
+
+/// The `job_schedule` of a request body, with `params` stored as a JSON
+/// string.
+fn job_schedule(payload: &Document) -> Result<Document, Error> {
+    let mut schedule = payload
+        .get_document("job_schedule")
+        .map_err(|_| Error::BadFormat("job_schedule is required".to_string()))?
+        .clone();
+    if let Ok(params) = schedule.get_document("params") {
+        let params = Bson::Document(params.clone()).into_relaxed_extjson();
+        schedule.insert("params", params.to_string());
+    }
+    Ok(schedule)
+}
+
+async fn find_schedules(ctx: Context) -> Result<Vec<Bson>, Error> {
+    let request = parse_class_request("GET", &doc! {})?;
+    match execute(request, ctx).await?.remove("results") {
+        Some(Bson::Array(schedules)) => Ok(schedules),
+        _ => Ok(vec![]),
+    }
+}
+
+#[get("/parse/cloud_code/jobs")]
+pub async fn find_jobs(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let context = match schedule_context(&req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    match find_schedules(context).await {
+        Ok(schedules) => HttpResponse::Ok().json(schedules),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+/// The registered jobs, and the ones already scheduled.
+#[get("/parse/cloud_code/jobs/data")]
+pub async fn jobs_data(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let context = match schedule_context(&req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let jobs = context.config.cloud.job_names();
+    match find_schedules(context).await {
+        Ok(schedules) => {
+            let in_use: Vec<Bson> = schedules
+                .iter()
+                .filter_map(|x| x.as_document()?.get("jobName").cloned())
+                .collect();
+            HttpResponse::Ok().json(doc! {"jobs": jobs, "in_use": in_use})
+        }
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/cloud_code/jobs")]
+pub async fn create_job_schedule(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match schedule_context(&req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let schedule = match job_schedule(&payload) {
+        Ok(schedule) => schedule,
+        Err(err) => return err.to_http_response(),
+    };
+    if let Err(err) = cloud::validate_schedule(&context, &schedule) {
+        return err.to_http_response();
+    }
+    let request = match parse_class_request("POST", &schedule) {
+        Ok(request) => request,
+        Err(err) => return err.to_http_response(),
+    };
+    match execute(request, context).await {
+        Ok(result) => HttpResponse::Created().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/cloud_code/jobs/{object_id}")]
+pub async fn update_job_schedule(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match schedule_context(&req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let schedule = match job_schedule(&payload) {
+        Ok(schedule) => schedule,
+        Err(err) => return err.to_http_response(),
+    };
+    if let Err(err) = cloud::validate_schedule(&context, &schedule) {
+        return err.to_http_response();
+    }
+    let request = match parse_object_request("PUT", object_id.into_inner(), &schedule) {
+        Ok(request) => request,
+        Err(err) => return err.to_http_response(),
+    };
+    match execute(request, context).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/cloud_code/jobs/{object_id}")]
+pub async fn delete_job_schedule(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let context = match schedule_context(&req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let request = match parse_object_request("DELETE", object_id.into_inner(), &doc! {}) {
+        Ok(request) => request,
+        Err(err) => return err.to_http_response(),
+    };
+    match execute(request, context).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
@@ -0,0 +1,306 @@
+use actix_web::dev::SizedStream;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use bson::doc;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::build_context;
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::files::{self, FileStream, FilesAdapter};
+use crate::util;
+
+fn files_adapter(config: &Config) -> Result<Arc<dyn FilesAdapter>, Error> {
+    config
+        .files
+        .clone()
+        .ok_or_else(|| Error::FileSaveError("Files adapter is not configured".to_string()))
+}
+
+/// The bytes a `Range` header asks for. `Ok(None)` serves the whole file,
+/// as for headers this ignores, and `Err` means the range is outside of it.
+fn parse_range(header: &str, length: u64) -> Result<Option<Range<u64>>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => Err(()),
+            Ok(suffix) if length > 0 => Ok(Some(length.saturating_sub(suffix)..length)),
+            Ok(_) => Err(()),
+            Err(_) => Ok(None),
+        };
+    }
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return Ok(None),
+    };
+    let end = match end {
+        "" => length,
+        end => match end.parse::<u64>() {
+            Ok(end) => end.saturating_add(1).min(length),
+            Err(_) => return Ok(None),
+        },
+    };
+    if start >= end {
+        return Err(());
+    }
+    Ok(Some(start..end))
+}
+
+fn check_file_name(file_name: &str) -> Result<(), Error> {
+    if files::is_valid_file_name(file_name) {
+        Ok(())
+    } else {
+        Err(Error::InvalidFileName(
+            "Filename contains invalid characters.".to_string(),
+        ))
+    }
+}
+
+async fn save_file(
+    config: &Config,
+    file_name: &str,
+    content_type: Option<&str>,
+    payload: web::Payload,
+) -> Result<String, Error> {
+    check_file_name(file_name)?;
+    let files = files_adapter(config)?;
+    let max_upload_size = config.max_upload_size;
+    let mut received = 0;
+    let data: FileStream = payload
+        .map_err(|e| Error::FileSaveError(e.to_string()))
+        .and_then(move |bytes| {
+            received += bytes.len();
+            let result = if received > max_upload_size {
+                let message = format!("File is larger than {} bytes", max_upload_size);
+                Err(Error::FileTooLarge(message))
+            } else {
+                Ok(bytes)
+            };
+            futures::future::ready(result)
+        })
+        .boxed_local();
+
+    let name = format!("{}_{}", util::new_token(), file_name);
+    let content_type = content_type
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| files::guess_content_type(file_name));
+    match files.create_file(&name, data, content_type).await {
+        Ok(()) => Ok(name),
+        Err(Error::FileTooLarge(message)) => Err(Error::FileTooLarge(message)),
+        Err(e) => {
+            error!("Could not store file {}: {}", name, e);
+            Err(Error::FileSaveError("Could not store file.".to_string()))
+        }
+    }
+}
+
+#[post("/parse/files/{file_name}")]
+pub async fn upload_file(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: web::Payload,
+    req: HttpRequest,
+    file_name: web::Path<String>,
+) -> HttpResponse {
+    let context = match build_context("", &req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let content_type = req
+        .headers()
+        .get("Content-Type")
+        .and_then(|x| x.to_str().ok());
+    match save_file(&context.config, &file_name, content_type, payload).await {
+        Ok(name) => {
+            let url = files::file_url(&context.config, &name);
+            HttpResponse::Created()
+                .header("Location", url.as_str())
+                .json(doc! {"url": &url, "name": name})
+        }
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn read_file(
+    config: &Config,
+    file_name: &str,
+    range: Option<&str>,
+) -> Result<HttpResponse, Error> {
+    check_file_name(file_name)?;
+    let files = files_adapter(config)?;
+    let info = files.file_info(file_name).await?;
+    let length = info.length;
+    let content_type = info
+        .content_type
+        .unwrap_or_else(|| files::guess_content_type(file_name).to_string());
+    let range = match range.map(|x| parse_range(x, length)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .header("Content-Range", format!("bytes */{}", length))
+                .finish())
+        }
+        None => None,
+    };
+    let mut response = match &range {
+        Some(range) => {
+            let mut response = HttpResponse::PartialContent();
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, length);
+            response.header("Content-Range", content_range);
+            response
+        }
+        None => HttpResponse::Ok(),
+    };
+    let range = range.unwrap_or(0..length);
+    let data = if range.start < range.end {
+        files.file_data(file_name, range.clone()).await?
+    } else {
+        stream::empty().boxed_local()
+    };
+    let data = data.map_err(actix_web::error::ErrorInternalServerError);
+    Ok(response
+        .header("Accept-Ranges", "bytes")
+        .content_type(content_type)
+        .body(SizedStream::new(range.end - range.start, data)))
+}
+
+#[get("/parse/files/{app_id}/{file_name}")]
+pub async fn download_file(
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (app_id, file_name) = path.into_inner();
+    if !config.app_id.is_empty() && app_id != config.app_id {
+        return Error::Forbidden("Invalid application ID.".to_string()).to_http_response();
+    }
+    let range = req.headers().get("Range").and_then(|x| x.to_str().ok());
+    match read_file(&config, &file_name, range).await {
+        Ok(response) => response,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/files/{file_name}")]
+pub async fn delete_file(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    file_name: web::Path<String>,
+) -> HttpResponse {
+    let context = match build_context("", &req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    if !context.user.is_master || context.user.is_read_only {
+        return Error::Forbidden("unauthorized: master key is required".to_string())
+            .to_http_response();
+    }
+    if let Err(err) = check_file_name(&file_name) {
+        return err.to_http_response();
+    }
+    let result = match files_adapter(&context.config) {
+        Ok(files) => files.delete_file(&file_name).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => HttpResponse::Ok().json(doc! {}),
+        Err(Error::NotFound(message)) => Error::NotFound(message).to_http_response(),
+        Err(e) => {
+            error!("Could not delete file {}: {}", file_name, e);
+            Error::FileDeleteError("Could not delete file.".to_string()).to_http_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::FileSystemAdapter;
+    use actix_web::dev::{BodySize, MessageBody, ServiceResponse};
+    use actix_web::{http::StatusCode, rt::System, test, App};
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Ok(Some(0..10)));
+        assert_eq!(parse_range("bytes=90-", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=-10", 100), Ok(Some(90..100)));
+        assert_eq!(parse_range("bytes=-200", 100), Ok(Some(0..100)));
+        assert_eq!(parse_range("bytes=50-500", 100), Ok(Some(50..100)));
+    }
+
+    #[test]
+    fn clamps_open_ended_ranges_without_overflow() {
+        let max = u64::MAX.to_string();
+        assert_eq!(
+            parse_range(&format!("bytes=0-{}", max), 100),
+            Ok(Some(0..100))
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Err(()));
+        assert_eq!(parse_range("bytes=10-5", 100), Err(()));
+        assert_eq!(parse_range("bytes=-0", 100), Err(()));
+        assert_eq!(parse_range("bytes=-10", 0), Err(()));
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        assert_eq!(parse_range("items=0-9", 100), Ok(None));
+        assert_eq!(parse_range("bytes=0-9,20-29", 100), Ok(None));
+        assert_eq!(parse_range("bytes=a-9", 100), Ok(None));
+        assert_eq!(parse_range("bytes=5", 100), Ok(None));
+    }
+
+    #[test]
+    fn streams_downloads_with_their_length() {
+        let dir = std::env::temp_dir().join(format!("files-{}", util::new_object_id()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "Hello, world").unwrap();
+        let config = Config {
+            files: Some(Arc::new(FileSystemAdapter::new(&dir))),
+            ..Config::default()
+        };
+        System::new("test").block_on(async move {
+            let mut app = test::init_service(App::new().data(config).service(download_file)).await;
+            let download = |range: Option<&'static str>| {
+                let mut req = test::TestRequest::get().uri("/parse/files/app/a.txt");
+                if let Some(range) = range {
+                    req = req.header("Range", range);
+                }
+                req.to_request()
+            };
+            let header = |response: &ServiceResponse, name: &str| {
+                let value = response.headers().get(name).unwrap();
+                value.to_str().unwrap().to_string()
+            };
+
+            let response = test::call_service(&mut app, download(None)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.response().body().size(), BodySize::Sized(12));
+            assert_eq!(header(&response, "Content-Type"), "text/plain");
+            assert_eq!(test::read_body(response).await, "Hello, world");
+
+            let response = test::call_service(&mut app, download(Some("bytes=7-"))).await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(response.response().body().size(), BodySize::Sized(5));
+            assert_eq!(header(&response, "Content-Range"), "bytes 7-11/12");
+            assert_eq!(test::read_body(response).await, "world");
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
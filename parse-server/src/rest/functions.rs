@@ -0,0 +1,32 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use bson::doc;
+
+use super::{build_context, parse_payload, strip_meta_keys};
+use crate::cache::AppCache;
+use crate::cloud;
+use crate::config::Config;
+use crate::database::DbAdapter;
+
+#[post("/parse/functions/{name}")]
+pub async fn call_function(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context("", &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+
+    match cloud::run_function(&context, &name, strip_meta_keys(&payload)).await {
+        Ok(result) => HttpResponse::Ok().json(doc! {"result": result}),
+        Err(err) => err.to_http_response(),
+    }
+}
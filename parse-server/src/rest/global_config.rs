@@ -0,0 +1,142 @@
+use actix_web::{get, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Bson, Document};
+use chrono::Utc;
+
+use super::{build_context, parse_payload};
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::Context;
+
+const CLASS_NAME: &str = "_GlobalConfig";
+/// `_GlobalConfig` holds a single document.
+const CONFIG_ID: &str = "1";
+
+/// The stored `params` and `masterKeyOnly` flags, cached for a few seconds.
+async fn load_config(ctx: &Context) -> Result<Document, Error> {
+    if let Some(global_config) = ctx.cache.get_global_config() {
+        return Ok(global_config);
+    }
+    let stored = ctx
+        .db
+        .find_documents(CLASS_NAME, doc! {"_id": CONFIG_ID})
+        .await?;
+    let stored = stored.into_iter().next().unwrap_or_default();
+    let global_config = doc! {
+        "params": stored.get_document("params").cloned().unwrap_or_default(),
+        "masterKeyOnly": stored.get_document("masterKeyOnly").cloned().unwrap_or_default(),
+    };
+    ctx.cache.set_global_config(global_config.clone());
+    Ok(global_config)
+}
+
+/// The config a caller sees, `masterKeyOnly` params are left out unless
+/// the master key is used.
+async fn find_config(ctx: &Context) -> Result<Document, Error> {
+    let mut global_config = load_config(ctx).await?;
+    if ctx.user.is_master {
+        return Ok(global_config);
+    }
+    let master_key_only = global_config
+        .remove("masterKeyOnly")
+        .and_then(|x| x.as_document().cloned())
+        .unwrap_or_default();
+    let params = global_config
+        .get_document("params")
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(key, _)| !master_key_only.get_bool(key).unwrap_or(false))
+        .collect::<Document>();
+    Ok(doc! {"params": params})
+}
+
+/// Sets or deletes the params of the body along with their `masterKeyOnly`
+/// flags, other params are kept.
+async fn save_config(ctx: &Context, payload: &Document) -> Result<(), Error> {
+    let params = payload
+        .get_document("params")
+        .map_err(|_| Error::BadFormat("params is required".to_string()))?;
+    let master_key_only = payload
+        .get_document("masterKeyOnly")
+        .cloned()
+        .unwrap_or_default();
+    let mut set = doc! {};
+    let mut unset = doc! {};
+    for (key, value) in params {
+        // Keys become part of mongo field paths
+        if key.is_empty() || key.contains('.') || key.starts_with('$') {
+            return Err(Error::InvalidKeyName(format!(
+                "Invalid config param name: {}",
+                key
+            )));
+        }
+        let is_delete = match value {
+            Bson::Document(value) => value.get_str("__op") == Ok("Delete"),
+            _ => false,
+        };
+        if is_delete {
+            unset.insert(format!("params.{}", key), "");
+            unset.insert(format!("masterKeyOnly.{}", key), "");
+            continue;
+        }
+        set.insert(format!("params.{}", key), value.clone());
+        if let Some(Bson::Boolean(flag)) = master_key_only.get(key) {
+            set.insert(format!("masterKeyOnly.{}", key), *flag);
+        }
+    }
+    set.insert("_updated_at", Utc::now());
+    let mut update = doc! {"$set": set};
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    ctx.db
+        .upsert_document(CLASS_NAME, doc! {"_id": CONFIG_ID}, update)
+        .await?;
+    ctx.cache.clear_global_config();
+    Ok(())
+}
+
+#[get("/parse/config")]
+pub async fn get_config(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let context = match build_context(CLASS_NAME, &req, &doc! {}, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    match find_config(&context).await {
+        Ok(global_config) => HttpResponse::Ok().json(global_config),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/config")]
+pub async fn update_config(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context(CLASS_NAME, &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    if !context.user.is_master || context.user.is_read_only {
+        return Error::Forbidden("unauthorized: master key is required".to_string())
+            .to_http_response();
+    }
+    match save_config(&context, &payload).await {
+        Ok(()) => HttpResponse::Ok().json(doc! {"result": true}),
+        Err(err) => err.to_http_response(),
+    }
+}
@@ -0,0 +1,231 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+
+use super::{build_context, parse_payload, strip_meta_keys};
+use crate::cache::AppCache;
+use crate::cloud;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::Context;
+
+/// Hooks are managed with the master key, the read-only one can list them.
+async fn hook_context(
+    req: &HttpRequest,
+    payload: &str,
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    write: bool,
+) -> Result<(Context, Document), Error> {
+    let payload = parse_payload(payload)?;
+    let context = build_context("_Hooks", req, &payload, db, cache, config).await?;
+    if !context.user.is_master || (write && context.user.is_read_only) {
+        return Err(Error::Forbidden(
+            "unauthorized: master key is required".to_string(),
+        ));
+    }
+    Ok((context, strip_meta_keys(&payload)))
+}
+
+fn missing_function(name: &str) -> String {
+    format!("no function named: {} is defined", name)
+}
+
+fn missing_trigger(class_name: &str) -> String {
+    format!("class {} does not exist", class_name)
+}
+
+#[get("/parse/hooks/functions")]
+pub async fn find_functions(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, false).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let filter = doc! {"functionName": {"$exists": true}};
+    match cloud::find_hooks(&ctx, filter).await {
+        Ok(hooks) => HttpResponse::Ok().json(hooks),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/hooks/functions/{name}")]
+pub async fn get_function(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, false).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let filter = doc! {"functionName": name.as_str()};
+    match cloud::get_hook(&ctx, filter, missing_function(&name)).await {
+        Ok(hook) => HttpResponse::Ok().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/hooks/functions")]
+pub async fn create_function(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (ctx, body) = match hook_context(&req, &payload, db, cache, config, true).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    match cloud::create_function_hook(&ctx, &body).await {
+        Ok(hook) => HttpResponse::Created().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/hooks/functions/{name}")]
+pub async fn update_function(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let (ctx, body) = match hook_context(&req, &payload, db, cache, config, true).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let filter = doc! {"functionName": name.as_str()};
+    match cloud::update_hook(&ctx, filter, &body, missing_function(&name)).await {
+        Ok(hook) => HttpResponse::Ok().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/hooks/functions/{name}")]
+pub async fn delete_function(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, true).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let filter = doc! {"functionName": name.as_str()};
+    match cloud::delete_hook(&ctx, filter, missing_function(&name)).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/hooks/triggers")]
+pub async fn find_triggers(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, false).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let filter = doc! {"className": {"$exists": true}};
+    match cloud::find_hooks(&ctx, filter).await {
+        Ok(hooks) => HttpResponse::Ok().json(hooks),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/hooks/triggers/{class_name}/{trigger_name}")]
+pub async fn get_trigger(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, false).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let (class_name, trigger_name) = path.into_inner();
+    let filter = doc! {"className": &class_name, "triggerName": &trigger_name};
+    let missing = missing_trigger(&class_name);
+    match cloud::get_hook(&ctx, filter, missing).await {
+        Ok(hook) => HttpResponse::Ok().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/hooks/triggers")]
+pub async fn create_trigger(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let (ctx, body) = match hook_context(&req, &payload, db, cache, config, true).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    match cloud::create_trigger_hook(&ctx, &body).await {
+        Ok(hook) => HttpResponse::Created().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/hooks/triggers/{class_name}/{trigger_name}")]
+pub async fn update_trigger(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (ctx, body) = match hook_context(&req, &payload, db, cache, config, true).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let (class_name, trigger_name) = path.into_inner();
+    let filter = doc! {"className": &class_name, "triggerName": &trigger_name};
+    let missing = missing_trigger(&class_name);
+    match cloud::update_hook(&ctx, filter, &body, missing).await {
+        Ok(hook) => HttpResponse::Ok().json(hook),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/hooks/triggers/{class_name}/{trigger_name}")]
+pub async fn delete_trigger(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let ctx = match hook_context(&req, "", db, cache, config, true).await {
+        Ok((ctx, _)) => ctx,
+        Err(err) => return err.to_http_response(),
+    };
+    let (class_name, trigger_name) = path.into_inner();
+    let filter = doc! {"className": &class_name, "triggerName": &trigger_name};
+    let missing = missing_trigger(&class_name);
+    match cloud::delete_hook(&ctx, filter, missing).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
@@ -0,0 +1,125 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Document};
+use std::collections::HashMap;
+
+use super::classes::{parse_class_request, parse_object_request, respond};
+use super::{build_context, parse_payload, parse_query_string};
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+
+const CLASS_NAME: &str = "_Installation";
+
+/// Runs a request on `_Installation`, `object_id` selects a single object.
+async fn handle(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: &HttpRequest,
+    method: &str,
+    object_id: Option<String>,
+    payload: Document,
+) -> HttpResponse {
+    let context = match build_context(CLASS_NAME, req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    // The JS SDK tunnels every method through POST
+    let method = payload.get_str("_method").unwrap_or(method);
+    let request = match object_id {
+        Some(object_id) => parse_object_request(method, object_id, &payload),
+        None => parse_class_request(method, &payload),
+    };
+    match request {
+        Ok(request) => respond(request, context).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/installations")]
+pub async fn create_installation(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    match parse_payload(&payload) {
+        Ok(payload) => handle(db, cache, config, &req, "POST", None, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/installations")]
+pub async fn find_installations(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    match parse_query_string(query.into_inner()) {
+        Ok(payload) => handle(db, cache, config, &req, "GET", None, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/installations/{object_id}")]
+pub async fn get_installation(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    match parse_query_string(query.into_inner()) {
+        Ok(payload) => handle(db, cache, config, &req, "GET", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/installations/{object_id}")]
+pub async fn installation(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    match parse_payload(&payload) {
+        Ok(payload) => handle(db, cache, config, &req, "", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/installations/{object_id}")]
+pub async fn update_installation(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    match parse_payload(&payload) {
+        Ok(payload) => handle(db, cache, config, &req, "PUT", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/installations/{object_id}")]
+pub async fn delete_installation(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = Some(object_id.into_inner());
+    handle(db, cache, config, &req, "DELETE", object_id, doc! {}).await
+}
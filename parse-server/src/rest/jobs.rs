@@ -0,0 +1,95 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use bson::doc;
+
+use super::{build_context, parse_payload, strip_meta_keys};
+use crate::cache::AppCache;
+use crate::cloud;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+
+#[post("/parse/jobs/{name}")]
+pub async fn start_job(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context("_JobStatus", &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    if !context.user.is_master || context.user.is_read_only {
+        return Error::Forbidden("unauthorized: master key is required".to_string())
+            .to_http_response();
+    }
+
+    match cloud::start_job(&context, &name, strip_meta_keys(&payload), "api").await {
+        Ok(job_id) => HttpResponse::Ok()
+            .header("X-Parse-Job-Status-Id", job_id)
+            .json(doc! {}),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::CloudCode;
+    use actix_web::{http::StatusCode, rt::System, test, App};
+    use serde_json::Value;
+
+    /// Starts the job `name` with the master key `key`, the database is only
+    /// reached once the job is started.
+    fn start(key: Option<&'static str>, name: &'static str) -> (StatusCode, Value) {
+        System::new("test").block_on(async move {
+            let db = DbAdapter::connect("mongodb://localhost/test").await;
+            let config = Config {
+                master_key: "master".to_string(),
+                read_only_master_key: Some("read-only".to_string()),
+                cloud: CloudCode::new().job("cleanup", |_| async { Ok(None) }),
+                ..Config::default()
+            };
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(db))
+                    .app_data(web::Data::new(AppCache::new()))
+                    .app_data(web::Data::new(config))
+                    .service(start_job),
+            )
+            .await;
+            let mut req = test::TestRequest::post()
+                .uri(&format!("/parse/jobs/{}", name))
+                .set_payload("{}");
+            if let Some(key) = key {
+                req = req.header("X-Parse-Master-Key", key);
+            }
+            let response = test::call_service(&mut app, req.to_request()).await;
+            let status = response.status();
+            let body = test::read_body(response).await;
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        })
+    }
+
+    #[test]
+    fn requires_the_master_key() {
+        for key in &[None, Some("wrong"), Some("read-only")] {
+            let (status, body) = start(*key, "cleanup");
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], 119);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_jobs() {
+        let (status, body) = start(Some("master"), "missing");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], 141);
+    }
+}
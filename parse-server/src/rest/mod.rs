@@ -1 +1,161 @@
+pub mod audiences;
 pub mod classes;
+pub mod cloud_code;
+pub mod files;
+pub mod functions;
+pub mod global_config;
+pub mod hooks;
+pub mod installations;
+pub mod jobs;
+pub mod pages;
+pub mod push;
+pub mod users;
+
+use actix_web::{web, HttpRequest};
+use bson::Document;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::idempotency;
+use crate::operation::Context;
+use crate::user::{self, User};
+
+pub fn parse_payload(payload: &str) -> Result<Document, Error> {
+    trace!("REST message IN: {}", payload);
+    if payload.trim().is_empty() {
+        return Ok(Document::new());
+    }
+    // bson rejects unsigned JSON numbers, so go through serde_json values
+    serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(payload)
+        .map_err(|e| e.to_string())
+        .and_then(|x| Document::try_from(x).map_err(|e| e.to_string()))
+        .map_err(|e| {
+            let message = format!("Could not parse json request: {}", e);
+            error!("{}", &message);
+            Error::BadFormat(message)
+        })
+}
+
+/// Turns the query string of a GET request into the payload the SDKs would
+/// POST, `where` is JSON and numbers are converted.
+pub fn parse_query_string(query: HashMap<String, String>) -> Result<Document, Error> {
+    let mut payload = Document::new();
+    for (key, value) in query {
+        match key.as_str() {
+            "where" => {
+                payload.insert(key, parse_payload(&value)?);
+            }
+            "limit" | "skip" => {
+                let value = value
+                    .parse::<i64>()
+                    .map_err(|_| Error::BadFormat(format!("Invalid {}: {}", key, value)))?;
+                payload.insert(key, value);
+            }
+            "count" => {
+                payload.insert(key, value == "1" || value == "true");
+            }
+            _ => {
+                payload.insert(key, value);
+            }
+        }
+    }
+    Ok(payload)
+}
+
+/// Drops the `_`-prefixed keys the SDKs use to send client keys in the body.
+pub fn strip_meta_keys(payload: &Document) -> Document {
+    payload
+        .iter()
+        .filter(|(key, _)| !key.starts_with('_'))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// A client key from its `X-Parse-*` header, or from the payload key the JS
+/// SDK uses instead.
+fn client_key(req: &HttpRequest, payload: &Document, header: &str, key: &str) -> Option<String> {
+    req.headers()
+        .get(header)
+        .and_then(|x| x.to_str().ok())
+        .or_else(|| payload.get_str(key).ok())
+        .map(|x| x.to_string())
+}
+
+pub async fn parse_user(
+    req: &HttpRequest,
+    payload: &Document,
+    config: &Config,
+    db: &DbAdapter,
+    cache: &AppCache,
+) -> Result<User, Error> {
+    let application_id = client_key(req, payload, "X-Parse-Application-Id", "_ApplicationId");
+    if !config.app_id.is_empty() && application_id.as_deref() != Some(config.app_id.as_str()) {
+        return Err(Error::Forbidden("unauthorized".to_string()));
+    }
+
+    let master_key = client_key(req, payload, "X-Parse-Master-Key", "_MasterKey");
+    let is_master =
+        !config.master_key.is_empty() && master_key.as_ref() == Some(&config.master_key);
+    let is_read_only =
+        config.read_only_master_key.is_some() && master_key == config.read_only_master_key;
+
+    let mut user = User {
+        id: None,
+        application_id,
+        installation_id: client_key(req, payload, "X-Parse-Installation-Id", "_InstallationId"),
+        is_master: is_master || is_read_only,
+        is_read_only,
+        user: None,
+        user_roles: vec![],
+        client_sdk: client_key(req, payload, "X-Parse-Client-Version", "_ClientVersion"),
+        session_token: client_key(req, payload, "X-Parse-Session-Token", "_SessionToken"),
+    };
+
+    if let Some(session_token) = &user.session_token {
+        let user_id = match cache.get_session(session_token) {
+            Some(user_id) => user_id,
+            None => {
+                let (user_id, expires_at) = user::find_session_user(db, session_token)
+                    .await?
+                    .ok_or_else(|| {
+                        Error::InvalidSessionToken("Invalid session token".to_string())
+                    })?;
+                cache.set_session(session_token, &user_id, expires_at);
+                user_id
+            }
+        };
+        user.id = Some(user_id);
+    }
+    Ok(user)
+}
+
+pub async fn build_context(
+    class: &str,
+    req: &HttpRequest,
+    payload: &Document,
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+) -> Result<Context, Error> {
+    let user = parse_user(req, payload, &config, &db, &cache).await?;
+    idempotency::enforce(req, &config, &db).await?;
+    let cloud_context = match req.headers().get("X-Parse-Cloud-Context") {
+        Some(header) => parse_payload(header.to_str().unwrap_or(""))?,
+        None => payload
+            .get_document("_context")
+            .cloned()
+            .unwrap_or_default(),
+    };
+    Ok(Context {
+        class: class.to_string(),
+        user,
+        db: db.into_inner(),
+        cache: cache.into_inner(),
+        config: config.into_inner(),
+        cloud_context,
+    })
+}
@@ -0,0 +1,208 @@
+use actix_web::{get, post, web, HttpResponse};
+use std::collections::HashMap;
+
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::Context;
+use crate::user::{self, User};
+
+type Params = web::Query<HashMap<String, String>>;
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn page(title: &str, body: &str) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n{}\n</body>\n</html>\n",
+        title, title, body
+    ))
+}
+
+fn invalid_link() -> HttpResponse {
+    page(
+        "Invalid Link",
+        "<p>This link is invalid or has expired.</p>",
+    )
+}
+
+/// Links in emails are opened without any client keys, so pages act as master
+/// once the app id of the link matches.
+fn page_context(
+    app_id: &str,
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+) -> Option<Context> {
+    if !config.app_id.is_empty() && app_id != config.app_id {
+        return None;
+    }
+    Some(Context {
+        class: "_User".to_string(),
+        user: User::master(),
+        db: db.into_inner(),
+        cache: cache.into_inner(),
+        config: config.into_inner(),
+        cloud_context: bson::Document::new(),
+    })
+}
+
+fn param<'a>(params: &'a HashMap<String, String>, key: &str) -> &'a str {
+    params.get(key).map(|x| x.as_str()).unwrap_or("")
+}
+
+#[get("/apps/{app_id}/verify_email")]
+pub async fn verify_email(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    app_id: web::Path<String>,
+    query: Params,
+) -> HttpResponse {
+    let context = match page_context(&app_id, db, cache, config) {
+        Some(context) => context,
+        None => return invalid_link(),
+    };
+    let username = param(&query, "username");
+    match user::verify_email(&context, username, param(&query, "token")).await {
+        Ok(()) => page(
+            "Email Verified",
+            &format!(
+                "<p>Successfully verified your email for account: {}.</p>",
+                escape_html(username)
+            ),
+        ),
+        Err(_) => invalid_link(),
+    }
+}
+
+fn choose_password(app_id: &str, username: &str, token: &str, error: &str) -> HttpResponse {
+    let error = if error.is_empty() {
+        String::new()
+    } else {
+        format!("<p class=\"error\">{}</p>\n", escape_html(error))
+    };
+    page(
+        "Reset Your Password",
+        &format!(
+            "<p>You can set a new password for your account: {username}</p>\n{error}<form method=\"POST\" action=\"/apps/{app_id}/request_password_reset\">\n<input type=\"hidden\" name=\"username\" value=\"{username}\">\n<input type=\"hidden\" name=\"token\" value=\"{token}\">\n<input type=\"password\" name=\"new_password\" placeholder=\"New Password\">\n<button type=\"submit\">Change Password</button>\n</form>",
+            username = escape_html(username),
+            token = escape_html(token),
+            app_id = escape_html(app_id),
+            error = error,
+        ),
+    )
+}
+
+#[get("/apps/{app_id}/request_password_reset")]
+pub async fn password_reset_form(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    app_id: web::Path<String>,
+    query: Params,
+) -> HttpResponse {
+    let context = match page_context(&app_id, db, cache, config) {
+        Some(context) => context,
+        None => return invalid_link(),
+    };
+    let (username, token) = (param(&query, "username"), param(&query, "token"));
+    match user::check_reset_token(&context, username, token).await {
+        Ok(_) => choose_password(&app_id, username, token, ""),
+        Err(_) => invalid_link(),
+    }
+}
+
+#[post("/apps/{app_id}/request_password_reset")]
+pub async fn reset_password(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    app_id: web::Path<String>,
+    form: web::Form<HashMap<String, String>>,
+) -> HttpResponse {
+    let context = match page_context(&app_id, db, cache, config) {
+        Some(context) => context,
+        None => return invalid_link(),
+    };
+    let (username, token) = (param(&form, "username"), param(&form, "token"));
+    let password = param(&form, "new_password");
+    let result = user::reset_password(&context, username, token, password).await;
+    reset_password_page(&app_id, username, token, result)
+}
+
+/// Passwords the policy rejects go back to the form with the reason.
+fn reset_password_page(
+    app_id: &str,
+    username: &str,
+    token: &str,
+    result: Result<(), Error>,
+) -> HttpResponse {
+    match result {
+        Ok(()) => page(
+            "Password Reset",
+            &format!(
+                "<p>Your password has been updated for account: {}.</p>",
+                escape_html(username)
+            ),
+        ),
+        Err(Error::PasswordMissing(message)) | Err(Error::ValidationError(message)) => {
+            choose_password(app_id, username, token, &message)
+        }
+        Err(_) => invalid_link(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reset_password_page;
+    use crate::error::Error;
+    use actix_web::body::Body;
+    use actix_web::HttpResponse;
+
+    fn body(response: &HttpResponse) -> String {
+        match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => String::from_utf8_lossy(bytes).to_string(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn rejected_passwords_go_back_to_the_form() {
+        let error = Error::ValidationError("Password cannot contain your username.".to_string());
+        let response = reset_password_page("app", "alice", "token1", Err(error));
+        let body = body(&response);
+        assert!(body.contains("Reset Your Password"));
+        assert!(body.contains("<p class=\"error\">Password cannot contain your username.</p>"));
+        assert!(body.contains("name=\"token\" value=\"token1\""));
+        assert!(body.contains("action=\"/apps/app/request_password_reset\""));
+    }
+
+    #[test]
+    fn missing_passwords_go_back_to_the_form() {
+        let error = Error::PasswordMissing("password is required.".to_string());
+        let body = body(&reset_password_page("app", "alice", "token1", Err(error)));
+        assert!(body.contains("<p class=\"error\">password is required.</p>"));
+    }
+
+    #[test]
+    fn invalid_tokens_show_the_invalid_link_page() {
+        let error = Error::OtherCause("Failed to reset password.".to_string());
+        let body = body(&reset_password_page("app", "alice", "token1", Err(error)));
+        assert!(body.contains("Invalid Link"));
+        assert!(!body.contains("<form"));
+    }
+
+    #[test]
+    fn reset_passwords_show_the_account() {
+        let body = body(&reset_password_page("app", "<alice>", "token1", Ok(())));
+        assert!(body.contains("Your password has been updated for account: &lt;alice&gt;."));
+    }
+}
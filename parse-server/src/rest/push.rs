@@ -0,0 +1,38 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use bson::doc;
+
+use super::{build_context, parse_payload, strip_meta_keys};
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::push;
+
+#[post("/parse/push")]
+pub async fn send_push(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context("_PushStatus", &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    if !context.user.is_master || context.user.is_read_only {
+        return Error::Forbidden("unauthorized: master key is required".to_string())
+            .to_http_response();
+    }
+
+    match push::send_push(&context, &strip_meta_keys(&payload)).await {
+        Ok(status_id) => HttpResponse::Ok()
+            .header("X-Parse-Push-Status-Id", status_id)
+            .json(doc! {"result": true}),
+        Err(err) => err.to_http_response(),
+    }
+}
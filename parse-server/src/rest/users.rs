@@ -0,0 +1,320 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
+
+use super::classes::{invalid_method, parse_find_request, parse_object_request, respond};
+use super::{build_context, parse_payload, parse_query_string, strip_meta_keys};
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::error::Error;
+use crate::operation::{execute, CreateRequest, Request};
+use crate::user;
+
+/// Signs up a user, or logs in a user whose authData is already linked.
+#[post("/parse/users")]
+pub async fn sign_up(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context("_User", &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+
+    let request = match payload.get_str("_method").unwrap_or("") {
+        "GET" => match parse_find_request(&payload) {
+            Ok(request) => request,
+            Err(err) => return err.to_http_response(),
+        },
+        "" | "POST" => Request::Create(CreateRequest {
+            params: strip_meta_keys(&payload),
+        }),
+        method => return invalid_method(method).to_http_response(),
+    };
+
+    match execute(request, context).await {
+        // Signups only return the new id, logins return the whole user
+        Ok(result) if result.contains_key("createdAt") && !result.contains_key("updatedAt") => {
+            HttpResponse::Created().json(result)
+        }
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn current_user(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: &Document,
+    req: &HttpRequest,
+) -> Result<Document, Error> {
+    let context = build_context("_User", req, payload, db, cache, config).await?;
+    let (user_id, session_token) = match (&context.user.id, &context.user.session_token) {
+        (Some(user_id), Some(session_token)) => (user_id.clone(), session_token.clone()),
+        _ => {
+            return Err(Error::InvalidSessionToken(
+                "Invalid session token".to_string(),
+            ))
+        }
+    };
+    let mut user = user::get_user(&context, &user_id).await?;
+    user.insert("sessionToken", session_token);
+    Ok(user)
+}
+
+#[get("/parse/users/me")]
+pub async fn get_me(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> HttpResponse {
+    match current_user(db, cache, config, &doc! {}, &req).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/users/me")]
+pub async fn post_me(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    match current_user(db, cache, config, &payload, &req).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+/// Gets, updates or deletes a user, updates with authData link new providers.
+async fn handle_user(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: &HttpRequest,
+    method: &str,
+    object_id: String,
+    payload: Document,
+) -> HttpResponse {
+    let context = match build_context("_User", req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let method = payload.get_str("_method").unwrap_or(method);
+    match parse_object_request(method, object_id, &payload) {
+        Ok(request) => respond(request, context).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[get("/parse/users/{object_id}")]
+pub async fn get_user(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = object_id.into_inner();
+    match parse_query_string(query.into_inner()) {
+        Ok(payload) => handle_user(db, cache, config, &req, "GET", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/users/{object_id}")]
+pub async fn user_document(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = object_id.into_inner();
+    match parse_payload(&payload) {
+        Ok(payload) => handle_user(db, cache, config, &req, "", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[put("/parse/users/{object_id}")]
+pub async fn update_user(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = object_id.into_inner();
+    match parse_payload(&payload) {
+        Ok(payload) => handle_user(db, cache, config, &req, "PUT", object_id, payload).await,
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[delete("/parse/users/{object_id}")]
+pub async fn delete_user(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+) -> HttpResponse {
+    let object_id = object_id.into_inner();
+    handle_user(db, cache, config, &req, "DELETE", object_id, doc! {}).await
+}
+
+async fn log_in(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: &Document,
+    req: &HttpRequest,
+) -> Result<Document, Error> {
+    let context = build_context("_User", req, payload, db, cache, config).await?;
+    user::log_in(
+        &context,
+        payload.get_str("username").ok(),
+        payload.get_str("email").ok(),
+        payload.get_str("password").unwrap_or(""),
+        payload.get_document("authData").ok(),
+    )
+    .await
+}
+
+#[get("/parse/login")]
+pub async fn get_login(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload: Document = query
+        .into_inner()
+        .into_iter()
+        .map(|(key, value)| (key, value.into()))
+        .collect();
+    match log_in(db, cache, config, &payload, &req).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/login")]
+pub async fn post_login(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    match log_in(db, cache, config, &payload, &req).await {
+        Ok(user) => HttpResponse::Ok().json(user),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/logout")]
+pub async fn log_out(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    let payload = match parse_payload(&payload) {
+        Ok(payload) => payload,
+        Err(err) => return err.to_http_response(),
+    };
+    let context = match build_context("_Session", &req, &payload, db, cache, config).await {
+        Ok(context) => context,
+        Err(err) => return err.to_http_response(),
+    };
+    let session_token = match &context.user.session_token {
+        Some(session_token) => session_token,
+        None => {
+            return Error::InvalidSessionToken("Invalid session token".to_string())
+                .to_http_response()
+        }
+    };
+
+    context.cache.remove_session(session_token);
+    match context
+        .db
+        .delete_documents("_Session", doc! {"_session_token": session_token})
+        .await
+    {
+        Ok(_) => HttpResponse::Ok().json(doc! {}),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+async fn email_request(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: &str,
+    req: &HttpRequest,
+    password_reset: bool,
+) -> Result<(), Error> {
+    let payload = parse_payload(payload)?;
+    let context = build_context("_User", req, &payload, db, cache, config).await?;
+    let email = payload.get("email").cloned().unwrap_or(Bson::Null);
+    if password_reset {
+        user::request_password_reset(&context, &email).await
+    } else {
+        user::resend_verification_email(&context, &email).await
+    }
+}
+
+#[post("/parse/verificationEmailRequest")]
+pub async fn verification_email_request(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    match email_request(db, cache, config, &payload, &req, false).await {
+        Ok(()) => HttpResponse::Ok().json(doc! {}),
+        Err(err) => err.to_http_response(),
+    }
+}
+
+#[post("/parse/requestPasswordReset")]
+pub async fn request_password_reset(
+    db: web::Data<DbAdapter>,
+    cache: web::Data<AppCache>,
+    config: web::Data<Config>,
+    payload: String,
+    req: HttpRequest,
+) -> HttpResponse {
+    match email_request(db, cache, config, &payload, &req, true).await {
+        Ok(()) => HttpResponse::Ok().json(doc! {}),
+        Err(err) => err.to_http_response(),
+    }
+}
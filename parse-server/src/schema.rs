@@ -43,21 +43,59 @@ pub struct Permissions {
     pub delete: HashMap<String, bool>,
     pub find: HashMap<String, bool>,
     pub get: HashMap<String, bool>,
-    pub protected_fields: HashMap<String, bool>,
+    /// Fields hidden from non-master callers, keyed by `*`, `authenticated`,
+    /// a user id, `role:Name` or `userField:pointerField`.
+    pub protected_fields: HashMap<String, Vec<String>>,
     pub update: HashMap<String, bool>,
+    pub read_user_fields: Vec<String>,
+    pub write_user_fields: Vec<String>,
+}
+
+/// Permission map granting access to everyone.
+pub fn public_permission() -> HashMap<String, bool> {
+    let mut map = HashMap::new();
+    map.insert("*".to_string(), true);
+    map
 }
 
 impl Permissions {
+    /// Default class level permissions, every operation is public.
     pub fn new() -> Self {
         Permissions {
-            add_field: HashMap::new(),
-            count: HashMap::new(),
-            creat: HashMap::new(),
-            delete: HashMap::new(),
-            find: HashMap::new(),
-            get: HashMap::new(),
+            add_field: public_permission(),
+            count: public_permission(),
+            creat: public_permission(),
+            delete: public_permission(),
+            find: public_permission(),
+            get: public_permission(),
             protected_fields: HashMap::new(),
-            update: HashMap::new(),
+            update: public_permission(),
+            read_user_fields: vec![],
+            write_user_fields: vec![],
+        }
+    }
+
+    /// Returns the permission map for a CLP operation name
+    /// (`find`, `get`, `count`, `create`, `update`, `delete`, `addField`).
+    pub fn for_operation(&self, operation: &str) -> Option<&HashMap<String, bool>> {
+        match operation {
+            "addField" => Some(&self.add_field),
+            "count" => Some(&self.count),
+            "create" => Some(&self.creat),
+            "delete" => Some(&self.delete),
+            "find" => Some(&self.find),
+            "get" => Some(&self.get),
+            "update" => Some(&self.update),
+            _ => None,
+        }
+    }
+
+    /// Pointer permission fields that apply to an operation.
+    pub fn pointer_fields(&self, operation: &str) -> &[String] {
+        match operation {
+            "get" | "find" | "count" => &self.read_user_fields,
+            "update" | "delete" => &self.write_user_fields,
+            _ => &[],
         }
     }
 }
@@ -71,4 +109,191 @@ impl Schema {
             fields: HashMap::new(),
         }
     }
+
+    /// Adds the fields every object has plus the built-in fields of system classes.
+    pub fn add_default_fields(&mut self) {
+        let fields = DEFAULT_FIELDS
+            .iter()
+            .chain(class_default_fields(&self.name).iter());
+        for (name, type_name) in fields {
+            if self.fields.contains_key(*name) {
+                continue;
+            }
+            let (field_type, target_type) = parse_type(type_name);
+            self.fields.insert(
+                name.to_string(),
+                Field {
+                    name: name.to_string(),
+                    field_type,
+                    target_type,
+                    required: false,
+                    default_value: None,
+                },
+            );
+        }
+    }
+}
+
+/// Params stored in fields of their own, e.g. `ACL` in `_acl`, `_rperm` and
+/// `_wperm`, so they never add a field to the schema.
+pub fn is_special_param(key: &str) -> bool {
+    matches!(key, "ACL" | "authData" | "password")
+}
+
+const DEFAULT_FIELDS: &[(&str, &str)] = &[
+    ("objectId", "string"),
+    ("createdAt", "date"),
+    ("updatedAt", "date"),
+];
+
+fn class_default_fields(class_name: &str) -> &'static [(&'static str, &'static str)] {
+    match class_name {
+        "_User" => &[
+            ("username", "string"),
+            ("email", "string"),
+            ("emailVerified", "boolean"),
+        ],
+        "_Installation" => &[
+            ("installationId", "string"),
+            ("deviceToken", "string"),
+            ("channels", "array"),
+            ("deviceType", "string"),
+            ("pushType", "string"),
+            ("GCMSenderId", "string"),
+            ("timeZone", "string"),
+            ("localeIdentifier", "string"),
+            ("badge", "number"),
+            ("appVersion", "string"),
+            ("appName", "string"),
+            ("appIdentifier", "string"),
+            ("parseVersion", "string"),
+        ],
+        "_Role" => &[
+            ("name", "string"),
+            ("users", "relation<_User>"),
+            ("roles", "relation<_Role>"),
+        ],
+        "_Session" => &[
+            ("restricted", "boolean"),
+            ("user", "*_User"),
+            ("installationId", "string"),
+            ("createdWith", "object"),
+        ],
+        "_PushStatus" => &[
+            ("pushTime", "string"),
+            ("source", "string"),
+            ("query", "string"),
+            ("payload", "string"),
+            ("title", "string"),
+            ("expiry", "number"),
+            ("expiration_interval", "number"),
+            ("status", "string"),
+            ("numSent", "number"),
+            ("numFailed", "number"),
+            ("pushHash", "string"),
+            ("errorMessage", "object"),
+            ("sentPerType", "object"),
+            ("failedPerType", "object"),
+            ("count", "number"),
+        ],
+        "_JobStatus" => &[
+            ("jobName", "string"),
+            ("source", "string"),
+            ("status", "string"),
+            ("message", "string"),
+            ("params", "object"),
+            ("startedAt", "date"),
+            ("finishedAt", "date"),
+        ],
+        "_JobSchedule" => &[
+            ("jobName", "string"),
+            ("description", "string"),
+            ("params", "string"),
+            ("startAfter", "string"),
+            ("daysOfWeek", "array"),
+            ("timeOfDay", "string"),
+            ("lastRun", "number"),
+            ("repeatMinutes", "number"),
+        ],
+        "_Idempotency" => &[("reqId", "string"), ("expire", "date")],
+        "_Audience" => &[
+            ("name", "string"),
+            ("query", "string"),
+            ("lastUsed", "date"),
+            ("timesUsed", "number"),
+        ],
+        _ => &[],
+    }
+}
+
+/// Parses a `_SCHEMA` type name such as `string`, `*_User` or `relation<_Role>`.
+pub fn parse_type(type_name: &str) -> (FieldType, Option<String>) {
+    match type_name {
+        "number" => (FieldType::Number, None),
+        "string" => (FieldType::String, None),
+        "boolean" => (FieldType::Boolean, None),
+        "date" => (FieldType::Date, None),
+        "map" => (FieldType::Object, None),
+        "object" => (FieldType::Object, None),
+        "array" => (FieldType::Array, None),
+        "geopoint" => (FieldType::GeoPoint, None),
+        "file" => (FieldType::File, None),
+        "bytes" => (FieldType::Bytes, None),
+        "polygon" => (FieldType::Polygon, None),
+        field_type => {
+            if let Some(target) = field_type.strip_prefix('*') {
+                (FieldType::Pointer, Some(String::from(target)))
+            } else if field_type.starts_with("relation<") && field_type.ends_with('>') {
+                (
+                    FieldType::Relation,
+                    Some(String::from(&field_type[9..field_type.len() - 1])),
+                )
+            } else {
+                (FieldType::Unknown, None)
+            }
+        }
+    }
+}
+
+/// Infers the `_SCHEMA` type name of a REST value, `None` when it carries no type
+/// (null or a delete operation).
+pub fn infer_type(value: &Bson) -> Option<String> {
+    let type_name = match value {
+        Bson::Null => return None,
+        Bson::Boolean(_) => "boolean",
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => "number",
+        Bson::String(_) => "string",
+        Bson::Array(_) => "array",
+        Bson::DateTime(_) => "date",
+        Bson::Document(doc) => {
+            let class_name = || {
+                doc.get_array("objects")
+                    .ok()
+                    .and_then(|x| x.first())
+                    .and_then(|x| x.as_document())
+                    .and_then(|x| x.get_str("className").ok())
+                    .or_else(|| doc.get_str("className").ok())
+                    .unwrap_or("")
+                    .to_string()
+            };
+            match (doc.get_str("__op"), doc.get_str("__type")) {
+                (Ok("Delete"), _) => return None,
+                (Ok("Increment"), _) => "number",
+                (Ok("Add"), _) | (Ok("AddUnique"), _) | (Ok("Remove"), _) => "array",
+                (Ok("AddRelation"), _) | (Ok("RemoveRelation"), _) => {
+                    return Some(format!("relation<{}>", class_name()))
+                }
+                (_, Ok("Pointer")) => return Some(format!("*{}", class_name())),
+                (_, Ok("Relation")) => return Some(format!("relation<{}>", class_name())),
+                (_, Ok("Date")) => "date",
+                (_, Ok("File")) => "file",
+                (_, Ok("GeoPoint")) => "geopoint",
+                (_, Ok("Polygon")) => "polygon",
+                (_, Ok("Bytes")) => "bytes",
+                _ => "object",
+            }
+        }
+        _ => "object",
+    };
+    Some(type_name.to_string())
 }
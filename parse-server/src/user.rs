@@ -1,3 +1,15 @@
+use crate::cache::AppCache;
+use crate::config::Config;
+use crate::database::DbAdapter;
+use crate::email::Mail;
+use crate::error::Error;
+use crate::operation::{Context, FindRequest};
+use crate::password;
+use crate::util;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+
 #[derive(Clone)]
 pub struct User {
     pub id: Option<String>,
@@ -8,4 +20,610 @@ pub struct User {
     pub user: Option<String>,
     pub user_roles: Vec<String>,
     pub client_sdk: Option<String>,
+    pub session_token: Option<String>,
+}
+
+impl User {
+    /// The server acting on its own, e.g. for links opened from emails.
+    pub fn master() -> User {
+        User {
+            id: None,
+            application_id: None,
+            installation_id: None,
+            is_master: true,
+            is_read_only: false,
+            user: None,
+            user_roles: vec![],
+            client_sdk: None,
+            session_token: None,
+        }
+    }
+}
+
+/// Creates a `_Session` for the user and returns its token.
+pub async fn create_session(
+    ctx: &Context,
+    user_id: &str,
+    created_with: Document,
+) -> Result<String, Error> {
+    let session_token = format!("r:{}", util::new_token());
+    let now = Utc::now();
+    let expires_at = now + Duration::seconds(ctx.config.session_length);
+    let mut session = doc! {
+        "_id": util::new_object_id(),
+        "_session_token": &session_token,
+        "_p_user": format!("_User${}", user_id),
+        "createdWith": created_with,
+        "restricted": false,
+        "_expiresAt": expires_at,
+        "_created_at": now,
+        "_updated_at": now,
+    };
+    if let Some(installation_id) = &ctx.user.installation_id {
+        session.insert("installationId", installation_id);
+    }
+    ctx.db.insert_document("_Session", session).await?;
+    Ok(session_token)
+}
+
+/// Resolves the user id and expiry of a session token, `None` for unknown or
+/// expired sessions.
+pub async fn find_session_user(
+    db: &DbAdapter,
+    session_token: &str,
+) -> Result<Option<(String, Option<DateTime<Utc>>)>, Error> {
+    let sessions = db
+        .find_documents("_Session", doc! {"_session_token": session_token})
+        .await?;
+    let session = match sessions.first() {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let expires_at = session.get_datetime("_expiresAt").ok().cloned();
+    if let Some(expires_at) = expires_at {
+        if expires_at < Utc::now() {
+            return Ok(None);
+        }
+    }
+    Ok(session
+        .get_str("_p_user")
+        .ok()
+        .and_then(|x| x.split('$').nth(1))
+        .map(|x| (x.to_string(), expires_at)))
+}
+
+/// Fetches the REST representation of a user.
+pub async fn get_user(ctx: &Context, user_id: &str) -> Result<Document, Error> {
+    let ctx = ctx.for_class("_User");
+    let req = FindRequest {
+        include: vec![],
+        filter: Some(doc! {"objectId": user_id}),
+        limit: Some(1),
+        skip: None,
+        sort: None,
+        count: false,
+        keys: vec![],
+        read_preference: None,
+    };
+    let mut user = ctx
+        .db
+        .query_objects(&req, &ctx)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::NotFound("Object not found.".to_string()))?;
+    ctx.config.auth.hide_auth_data(&mut user);
+    Ok(user)
+}
+
+fn lockout_error(duration: i64) -> Error {
+    Error::NotFound(format!(
+        "Your account is locked due to multiple failed login attempts. Please try again after {} minute(s)",
+        duration
+    ))
+}
+
+fn check_account_lockout(ctx: &Context, stored: &Document) -> Result<(), Error> {
+    let lockout = match &ctx.config.account_lockout {
+        Some(lockout) => lockout,
+        None => return Ok(()),
+    };
+    match stored.get_datetime("_account_lockout_expires_at") {
+        Ok(expires_at) if *expires_at > Utc::now() => Err(lockout_error(lockout.duration)),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a failed login, locking the account once the threshold is reached.
+async fn record_failed_login(ctx: &Context, stored: &Document) -> Result<(), Error> {
+    let lockout = match &ctx.config.account_lockout {
+        Some(lockout) => lockout,
+        None => return Ok(()),
+    };
+    // Counted in the database so concurrent attempts all add up
+    let user_id = stored.get_str("_id").unwrap_or("");
+    let update = doc! {"$inc": {"_failed_login_count": 1_i64}};
+    let updated = ctx
+        .db
+        .update_document("_User", doc! {"_id": user_id}, update)
+        .await?;
+    let failed_logins = match updated.as_ref().and_then(|x| x.get("_failed_login_count")) {
+        Some(Bson::Int32(count)) => *count as i64,
+        Some(Bson::Int64(count)) => *count,
+        _ => 0,
+    };
+    if failed_logins < lockout.threshold {
+        return Ok(());
+    }
+
+    // Only the attempts that still see the count over the threshold lock
+    let filter = doc! {"_id": user_id, "_failed_login_count": {"$gte": lockout.threshold}};
+    let expires_at = Utc::now() + Duration::minutes(lockout.duration);
+    let update =
+        doc! {"$set": {"_failed_login_count": 0_i64, "_account_lockout_expires_at": expires_at}};
+    ctx.db.update_documents("_User", filter, update).await?;
+    Err(lockout_error(lockout.duration))
+}
+
+async fn clear_failed_logins(ctx: &Context, stored: &Document) -> Result<(), Error> {
+    if !stored.contains_key("_failed_login_count")
+        && !stored.contains_key("_account_lockout_expires_at")
+    {
+        return Ok(());
+    }
+    let filter = doc! {"_id": stored.get_str("_id").unwrap_or("")};
+    let update = doc! {"$unset": {"_failed_login_count": "", "_account_lockout_expires_at": ""}};
+    ctx.db.update_documents("_User", filter, update).await?;
+    Ok(())
+}
+
+/// Rejects logins with a password older than the `max_password_age` policy.
+async fn check_password_age(ctx: &Context, stored: &Document) -> Result<(), Error> {
+    let max_age = match ctx
+        .config
+        .password_policy
+        .as_ref()
+        .and_then(|x| x.max_password_age)
+    {
+        Some(max_age) => max_age,
+        None => return Ok(()),
+    };
+    match stored.get_datetime("_password_changed_at") {
+        Ok(changed_at) if *changed_at + Duration::days(max_age) < Utc::now() => Err(
+            Error::NotFound("Your password has expired. Please reset your password.".to_string()),
+        ),
+        Ok(_) => Ok(()),
+        // Users created before the policy start their password age now
+        Err(_) => {
+            let filter = doc! {"_id": stored.get_str("_id").unwrap_or("")};
+            let update = doc! {"$set": {"_password_changed_at": Utc::now()}};
+            ctx.db.update_documents("_User", filter, update).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Prefixes the keys of a document with the path of the stored data.
+fn prefix_keys(document: &Document, prefix: &str) -> Document {
+    document
+        .iter()
+        .map(|(key, value)| (format!("{}.{}", prefix, key), value.clone()))
+        .collect()
+}
+
+/// Checks the additional factors, e.g. `mfa`, a user enrolled in against the
+/// `authData` sent at login.
+pub async fn validate_additional_factors(
+    ctx: &Context,
+    stored: &Document,
+    auth_data: Option<&Document>,
+) -> Result<(), Error> {
+    for (provider, adapter) in ctx.config.auth.additional() {
+        let key = format!("_auth_data_{}", provider);
+        let enrolled = match stored.get_document(&key) {
+            Ok(enrolled) => enrolled,
+            Err(_) => continue,
+        };
+        let data = auth_data
+            .and_then(|x| x.get_document(&provider).ok())
+            .ok_or_else(|| {
+                Error::MfaTokenRequired(format!("Missing additional authData {}", provider))
+            })?;
+        if let Some(change) = adapter.validate_login(data, enrolled).await? {
+            let mut filter = prefix_keys(&change.filter, &key);
+            filter.insert("_id", stored.get_str("_id").unwrap_or(""));
+            let update = change
+                .update
+                .iter()
+                .map(|(operator, fields)| match fields {
+                    Bson::Document(fields) => {
+                        (operator.clone(), Bson::Document(prefix_keys(fields, &key)))
+                    }
+                    _ => (operator.clone(), fields.clone()),
+                })
+                .collect();
+            // Someone else changed the stored data first, e.g. used the same
+            // recovery code
+            if ctx.db.update_documents("_User", filter, update).await? != 1 {
+                return Err(Error::MfaError(format!("Invalid {} token", provider)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the password of the user with the given username or email and
+/// returns the user with a new session token.
+pub async fn log_in(
+    ctx: &Context,
+    username: Option<&str>,
+    email: Option<&str>,
+    password: &str,
+    auth_data: Option<&Document>,
+) -> Result<Document, Error> {
+    let filter = match (username, email) {
+        (Some(username), _) => doc! {"username": username},
+        (None, Some(email)) => doc! {"email": email},
+        (None, None) => {
+            return Err(Error::UsernameMissing(
+                "username/email is required.".to_string(),
+            ))
+        }
+    };
+    if password.is_empty() {
+        return Err(Error::PasswordMissing("password is required.".to_string()));
+    }
+
+    let invalid = || Error::NotFound("Invalid username/password.".to_string());
+    let users = ctx.db.find_documents("_User", filter).await?;
+    let stored = users.first().ok_or_else(invalid)?;
+    let user_id = stored.get_str("_id").map_err(|_| invalid())?;
+    check_account_lockout(ctx, stored)?;
+    let hashed_password = stored.get_str("_hashed_password").unwrap_or("");
+    if !bcrypt::verify(password, hashed_password).unwrap_or(false) {
+        record_failed_login(ctx, stored).await?;
+        return Err(invalid());
+    }
+    match validate_additional_factors(ctx, stored, auth_data).await {
+        Err(Error::MfaError(message)) => {
+            record_failed_login(ctx, stored).await?;
+            return Err(Error::MfaError(message));
+        }
+        result => result?,
+    }
+    clear_failed_logins(ctx, stored).await?;
+    check_password_age(ctx, stored).await?;
+    if ctx.config.verify_user_emails
+        && ctx.config.prevent_login_with_unverified_email
+        && !stored.get_bool("emailVerified").unwrap_or(false)
+    {
+        return Err(Error::EmailNotFound(
+            "User email is not verified.".to_string(),
+        ));
+    }
+
+    let mut user = get_user(ctx, user_id).await?;
+    let created_with = doc! {"action": "login", "authProvider": "password"};
+    let session_token = create_session(ctx, user_id, created_with).await?;
+    user.insert("sessionToken", session_token);
+    Ok(user)
+}
+
+/// Resolves every role the user belongs to, directly or inherited through the
+/// `roles` relation of parent roles, as `role:Name` entries.
+pub async fn load_roles(
+    user_id: &str,
+    db: &DbAdapter,
+    cache: &AppCache,
+) -> Result<Vec<String>, Error> {
+    if let Some(roles) = cache.get_roles(user_id) {
+        return Ok(roles);
+    }
+
+    let mut visited = HashSet::new();
+    let mut queue = db
+        .find_relation_owners("users", "_Role", &[user_id.to_string()])
+        .await?;
+    while !queue.is_empty() {
+        let ids: Vec<String> = queue
+            .into_iter()
+            .filter(|id| visited.insert(id.clone()))
+            .collect();
+        if ids.is_empty() {
+            break;
+        }
+        queue = db.find_relation_owners("roles", "_Role", &ids).await?;
+    }
+
+    let ids: Vec<String> = visited.into_iter().collect();
+    let roles: Vec<String> = db
+        .find_role_names(&ids)
+        .await?
+        .into_iter()
+        .map(|name| format!("role:{}", name))
+        .collect();
+    debug!("Resolved {} roles for user {}", roles.len(), user_id);
+
+    cache.set_roles(user_id, roles.clone());
+    Ok(roles)
+}
+
+/// Expiry date of a token valid for `duration` seconds, `None` never expires.
+pub fn token_expires_at(duration: Option<i64>) -> Option<DateTime<Utc>> {
+    duration.map(|x| Utc::now() + Duration::seconds(x))
+}
+
+fn is_expired(user: &Document, key: &str) -> bool {
+    match user.get_datetime(key) {
+        Ok(expires_at) => *expires_at < Utc::now(),
+        Err(_) => false,
+    }
+}
+
+/// Link to one of the `/apps/{appId}/...` pages for the given user.
+fn app_link(config: &Config, page: &str, token: &str, username: &str) -> String {
+    let query =
+        serde_urlencoded::to_string([("token", token), ("username", username)]).unwrap_or_default();
+    format!(
+        "{}/apps/{}/{}?{}",
+        config.public_server_url(),
+        config.app_id,
+        page,
+        query
+    )
+}
+
+async fn send_mail(config: &Config, mail: Mail) -> Result<(), Error> {
+    match &config.email {
+        Some(adapter) => adapter.send_mail(&mail).await,
+        None => {
+            warn!("No email adapter configured, dropping mail to {}", mail.to);
+            Ok(())
+        }
+    }
+}
+
+pub async fn find_user(ctx: &Context, filter: Document) -> Result<Option<Document>, Error> {
+    Ok(ctx
+        .db
+        .find_documents("_User", filter)
+        .await?
+        .into_iter()
+        .next())
+}
+
+async fn find_user_by_email(ctx: &Context, email: &Bson) -> Result<Document, Error> {
+    let email = match email {
+        Bson::String(email) => email,
+        Bson::Null => return Err(Error::EmailMissing("you must provide an email".to_string())),
+        _ => {
+            return Err(Error::InvalidEmailAddress(
+                "you must provide a valid email string".to_string(),
+            ))
+        }
+    };
+    find_user(ctx, doc! {"email": email})
+        .await?
+        .ok_or_else(|| Error::EmailNotFound(format!("No user found with email {}.", email)))
+}
+
+/// Sends the verification link for the pending `_email_verify_token` of a user.
+pub async fn send_verification_email(ctx: &Context, user_id: &str) -> Result<(), Error> {
+    let user = match find_user(ctx, doc! {"_id": user_id}).await? {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    let (email, token) = match (user.get_str("email"), user.get_str("_email_verify_token")) {
+        (Ok(email), Ok(token)) => (email, token),
+        _ => return Ok(()),
+    };
+    let username = user.get_str("username").unwrap_or("");
+    let link = app_link(&ctx.config, "verify_email", token, username);
+    let mail = Mail {
+        to: email.to_string(),
+        subject: format!("Please verify your e-mail for {}", ctx.config.app_name),
+        text: format!(
+            "Hi,\n\nYou are being asked to confirm the e-mail address {} with {}\n\nClick here to confirm it:\n{}",
+            email, ctx.config.app_name, link
+        ),
+    };
+    send_mail(&ctx.config, mail).await
+}
+
+/// Sends the verification email again, with a new token when the pending one
+/// expired.
+pub async fn resend_verification_email(ctx: &Context, email: &Bson) -> Result<(), Error> {
+    let user = find_user_by_email(ctx, email).await?;
+    let user_id = user.get_str("_id").unwrap_or("");
+    if user.get_bool("emailVerified").unwrap_or(false) {
+        return Err(Error::OtherCause(format!(
+            "Email {} is already verified.",
+            user.get_str("email").unwrap_or("")
+        )));
+    }
+
+    if user.get_str("_email_verify_token").is_err()
+        || is_expired(&user, "_email_verify_token_expires_at")
+    {
+        let mut set = doc! {"_email_verify_token": util::new_token()};
+        if let Some(expires_at) = token_expires_at(ctx.config.email_verify_token_validity_duration)
+        {
+            set.insert("_email_verify_token_expires_at", expires_at);
+        }
+        ctx.db
+            .update_documents("_User", doc! {"_id": user_id}, doc! {"$set": set})
+            .await?;
+    }
+    send_verification_email(ctx, user_id).await
+}
+
+/// Marks the email of the user as verified when the token matches.
+pub async fn verify_email(ctx: &Context, username: &str, token: &str) -> Result<(), Error> {
+    let invalid = || Error::OtherCause("Failed to verify the email.".to_string());
+    if token.is_empty() {
+        return Err(invalid());
+    }
+    let filter = doc! {"username": username, "_email_verify_token": token};
+    let user = find_user(ctx, filter).await?.ok_or_else(invalid)?;
+    if is_expired(&user, "_email_verify_token_expires_at") {
+        return Err(invalid());
+    }
+
+    let update = doc! {
+        "$set": {"emailVerified": true},
+        "$unset": {"_email_verify_token": "", "_email_verify_token_expires_at": ""},
+    };
+    let filter = doc! {"_id": user.get_str("_id").unwrap_or(""), "_email_verify_token": token};
+    if ctx.db.update_documents("_User", filter, update).await? != 1 {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Sends a password reset link, reusing a pending token that is still valid.
+pub async fn request_password_reset(ctx: &Context, email: &Bson) -> Result<(), Error> {
+    let user = find_user_by_email(ctx, email).await?;
+    let user_id = user.get_str("_id").unwrap_or("");
+
+    let token = match user.get_str("_perishable_token") {
+        Ok(token) if !is_expired(&user, "_perishable_token_expires_at") => token.to_string(),
+        _ => {
+            let token = util::new_token();
+            let mut set = doc! {"_perishable_token": &token};
+            if let Some(expires_at) = token_expires_at(ctx.config.reset_token_validity_duration) {
+                set.insert("_perishable_token_expires_at", expires_at);
+            }
+            ctx.db
+                .update_documents("_User", doc! {"_id": user_id}, doc! {"$set": set})
+                .await?;
+            token
+        }
+    };
+
+    send_mail(&ctx.config, password_reset_mail(&ctx.config, &user, &token)).await
+}
+
+fn password_reset_mail(config: &Config, user: &Document, token: &str) -> Mail {
+    let username = user.get_str("username").unwrap_or("");
+    let link = app_link(config, "request_password_reset", token, username);
+    Mail {
+        to: user.get_str("email").unwrap_or("").to_string(),
+        subject: format!("Password Reset for {}", config.app_name),
+        text: format!(
+            "Hi,\n\nYou requested to reset your password for {} ({}).\n\nClick here to reset it:\n{}",
+            config.app_name, username, link
+        ),
+    }
+}
+
+/// Id of the user a password reset token was issued for.
+pub async fn check_reset_token(
+    ctx: &Context,
+    username: &str,
+    token: &str,
+) -> Result<String, Error> {
+    let invalid = || Error::OtherCause("Failed to reset password: invalid link.".to_string());
+    if token.is_empty() {
+        return Err(invalid());
+    }
+    let filter = doc! {"username": username, "_perishable_token": token};
+    let user = find_user(ctx, filter).await?.ok_or_else(invalid)?;
+    if is_expired(&user, "_perishable_token_expires_at") {
+        return Err(invalid());
+    }
+    user.get_str("_id")
+        .map(|x| x.to_string())
+        .map_err(|_| invalid())
+}
+
+/// Sets a new password with a reset token, the token can only be used once.
+pub async fn reset_password(
+    ctx: &Context,
+    username: &str,
+    token: &str,
+    password: &str,
+) -> Result<(), Error> {
+    if password.is_empty() {
+        return Err(Error::PasswordMissing("password is required.".to_string()));
+    }
+    let user_id = check_reset_token(ctx, username, token).await?;
+    let stored = find_user(ctx, doc! {"_id": &user_id}).await?;
+    let set = password::new_password_fields(&ctx.config, stored.as_ref(), None, password)?;
+    let mut unset = doc! {"_perishable_token": "", "_perishable_token_expires_at": ""};
+    let unlock = ctx
+        .config
+        .account_lockout
+        .as_ref()
+        .map(|x| x.unlock_on_password_reset);
+    if unlock == Some(true) {
+        unset.insert("_failed_login_count", "");
+        unset.insert("_account_lockout_expires_at", "");
+    }
+    let update = doc! {"$set": set, "$unset": unset};
+    // The token is only used once even when the link is submitted twice
+    let filter = doc! {"_id": &user_id, "_perishable_token": token};
+    if ctx.db.update_documents("_User", filter, update).await? != 1 {
+        return Err(Error::OtherCause(
+            "Failed to reset password: invalid link.".to_string(),
+        ));
+    }
+    revoke_sessions(ctx, &user_id, None).await
+}
+
+/// Deletes the sessions of a user but `keep`, e.g. after a password change.
+pub async fn revoke_sessions(
+    ctx: &Context,
+    user_id: &str,
+    keep: Option<&str>,
+) -> Result<(), Error> {
+    let mut filter = doc! {"_p_user": format!("_User${}", user_id)};
+    if let Some(keep) = keep {
+        filter.insert("_session_token", doc! {"$ne": keep});
+    }
+    ctx.db.delete_documents("_Session", filter).await?;
+    ctx.cache.remove_user_sessions(user_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{password_reset_mail, send_mail};
+    use crate::config::Config;
+    use crate::email::InMemoryEmailAdapter;
+    use actix_web::rt::System;
+    use bson::doc;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn password_reset_mail_links_to_the_reset_form() {
+        let outbox = InMemoryEmailAdapter::new();
+        let config = Config {
+            app_id: "app".to_string(),
+            app_name: "Test App".to_string(),
+            public_server_url: Some("https://example.com/parse".to_string()),
+            email: Some(Arc::new(outbox.clone())),
+            ..Config::default()
+        };
+
+        let user = doc! {"username": "alice b", "email": "alice@example.com"};
+        let mail = password_reset_mail(&config, &user, "token1");
+        System::new("test")
+            .block_on(async move { send_mail(&config, mail).await })
+            .unwrap();
+
+        let sent = outbox.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "alice@example.com");
+        assert_eq!(sent[0].subject, "Password Reset for Test App");
+        let link = sent[0].text.lines().last().unwrap();
+        let (page, query) = link.split_once('?').unwrap();
+        assert_eq!(
+            page,
+            "https://example.com/parse/apps/app/request_password_reset"
+        );
+        let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+        assert_eq!(params["token"], "token1");
+        assert_eq!(params["username"], "alice b");
+    }
 }
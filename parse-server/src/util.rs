@@ -1,4 +1,7 @@
-use bson::Bson;
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use std::collections::VecDeque;
 
 fn find(mut path: VecDeque<&str>, value: &'static Bson) -> Option<&'static Bson> {
@@ -18,3 +21,44 @@ pub fn r_get_str(path: Vec<&str>, value: &'static Bson) -> Option<&'static str>
     let object = find(path, value);
     object.and_then(|x| x.as_str())
 }
+
+pub fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .collect()
+}
+
+/// Random 10 character alphanumeric id, the format Parse uses for objectIds.
+pub fn new_object_id() -> String {
+    random_string(10)
+}
+
+/// Random 32 character hex token.
+pub fn new_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..16)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+pub fn to_iso_string(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Parses an ISO 8601 date, dates without a timezone are UTC.
+pub fn parse_iso(iso: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(iso)
+        .map(|x| x.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDateTime::parse_from_str(iso, "%Y-%m-%dT%H:%M:%S%.f")
+                .ok()
+                .map(|x| DateTime::from_utc(x, Utc))
+        })
+}
+
+/// REST representation of a date, `{"__type": "Date", "iso": "..."}`.
+pub fn date_value(date: &DateTime<Utc>) -> Document {
+    doc! {"__type": "Date", "iso": to_iso_string(date)}
+}
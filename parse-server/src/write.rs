@@ -1,193 +1,241 @@
+use crate::cloud::{self, ClassTriggers, TriggerRequest};
 use crate::error::Error;
-use crate::operation::{Context, Request};
+use crate::operation::{
+    enforce_write_permissions, reload_schema, Context, FindRequest, Request, UpdateRequest,
+};
+use crate::password;
+use crate::schema::{infer_type, is_special_param, Schema};
+use crate::user;
+use crate::util;
+use bson::{doc, Bson, Document};
+use chrono::Utc;
 
-// TODO: rewrite to rust
-async fn handle_installation() -> Result<(), Error> {
+fn lowercase_param(params: &mut Document, key: &str) {
+    if let Ok(value) = params.get_str(key).map(|x| x.to_lowercase()) {
+        params.insert(key, value);
+    }
+}
+
+/// Finds the existing installation a write refers to, and cleans up stale
+/// installations sharing its device token.
+///
+/// Returns the objectId to update instead of creating a new installation.
+async fn find_installation(
+    params: &Document,
+    object_id: Option<&str>,
+    installation_id: Option<&str>,
+    ctx: &Context,
+) -> Result<Option<String>, Error> {
+    let device_token = params.get_str("deviceToken").ok();
+
+    // One query for all the ids instead of three
+    let mut or_queries = vec![];
+    if let Some(object_id) = object_id {
+        or_queries.push(Bson::Document(doc! {"_id": object_id}));
+    }
+    if let Some(installation_id) = installation_id {
+        or_queries.push(Bson::Document(doc! {"installationId": installation_id}));
+    }
+    if let Some(device_token) = device_token {
+        or_queries.push(Bson::Document(doc! {"deviceToken": device_token}));
+    }
+    if or_queries.is_empty() {
+        return Ok(None);
+    }
+    let results = ctx
+        .db
+        .find_documents("_Installation", doc! {"$or": or_queries})
+        .await?;
+
+    let mut object_id_match = None;
+    let mut installation_id_match = None;
+    let mut device_token_matches = vec![];
+    for result in &results {
+        if object_id.is_some() && result.get_str("_id").ok() == object_id {
+            object_id_match = Some(result);
+        }
+        if installation_id.is_some() && result.get_str("installationId").ok() == installation_id {
+            installation_id_match = Some(result);
+        }
+        if device_token.is_some() && result.get_str("deviceToken").ok() == device_token {
+            device_token_matches.push(result);
+        }
+    }
+
+    // Sanity checks when updating
+    if object_id.is_some() {
+        let object_id_match = object_id_match
+            .ok_or_else(|| Error::NotFound("Object not found for update.".to_string()))?;
+        let changed = |key: &str| match (params.get_str(key), object_id_match.get_str(key)) {
+            (Ok(new), Ok(old)) => new != old,
+            _ => false,
+        };
+        if changed("installationId") {
+            return Err(Error::ChangedImmutableField(
+                "installationId may not be changed in this operation".to_string(),
+            ));
+        }
+        if changed("deviceToken")
+            && !params.contains_key("installationId")
+            && !object_id_match.contains_key("installationId")
+        {
+            return Err(Error::ChangedImmutableField(
+                "deviceToken may not be changed in this operation".to_string(),
+            ));
+        }
+        if changed("deviceType") {
+            return Err(Error::ChangedImmutableField(
+                "deviceType may not be changed in this operation".to_string(),
+            ));
+        }
+    }
+
+    let id_match = match installation_id {
+        Some(_) if installation_id_match.is_some() => installation_id_match,
+        _ => object_id_match,
+    };
+    // deviceType is only needed for new installations
+    if object_id.is_none() && id_match.is_none() && !params.contains_key("deviceType") {
+        return Err(Error::MissingRequiredField(
+            "deviceType must be specified in this operation".to_string(),
+        ));
+    }
+
+    let id_of = |x: &Document| x.get_str("_id").ok().map(|x| x.to_string());
+    let mut delete_filter = doc! {"deviceToken": device_token.unwrap_or("")};
+    if let Ok(app_identifier) = params.get_str("appIdentifier") {
+        delete_filter.insert("appIdentifier", app_identifier);
+    }
+
+    let id_match = match id_match {
+        Some(id_match) => id_match,
+        None => {
+            if device_token_matches.is_empty() {
+                return Ok(None);
+            }
+            let single = device_token_matches.len() == 1;
+            if single
+                && (!device_token_matches[0].contains_key("installationId")
+                    || installation_id.is_none())
+            {
+                // A single device token match and either side has no
+                // installationId, merge with it
+                return Ok(id_of(device_token_matches[0]));
+            }
+            let installation_id = match params.get_str("installationId") {
+                Ok(installation_id) => installation_id,
+                Err(_) => {
+                    return Err(Error::InvalidInstallationId(
+                        "Must specify installationId when deviceToken matches multiple Installation objects"
+                            .to_string(),
+                    ))
+                }
+            };
+            // Clean out old installations with the device token, a new one
+            // is created
+            delete_filter.insert("installationId", doc! {"$ne": installation_id});
+            ctx.db
+                .delete_documents("_Installation", delete_filter)
+                .await?;
+            return Ok(None);
+        }
+    };
+
+    if device_token_matches.len() == 1 && !device_token_matches[0].contains_key("installationId") {
+        // The one case where an installation is merged into the device token
+        // match that has no installationId
+        let merged = id_of(device_token_matches[0]);
+        if let Some(id) = id_of(id_match).filter(|x| Some(x) != merged.as_ref()) {
+            ctx.db
+                .delete_documents("_Installation", doc! {"_id": id})
+                .await?;
+        }
+        return Ok(merged);
+    }
+
+    if device_token.is_some() && id_match.get_str("deviceToken").ok() != device_token {
+        // Setting the device token on an existing installation, clean out
+        // older installations with that device token
+        if let Ok(installation_id) = params.get_str("installationId") {
+            delete_filter.insert("installationId", doc! {"$ne": installation_id});
+        } else if object_id.is_some() && id_of(id_match).as_deref() == object_id {
+            delete_filter.insert("_id", doc! {"$ne": object_id.unwrap_or("")});
+        } else {
+            return Ok(id_of(id_match));
+        }
+        ctx.db
+            .delete_documents("_Installation", delete_filter)
+            .await?;
+    }
+    Ok(id_of(id_match))
+}
+
+/// Deduplicates `_Installation` writes by objectId, installationId and
+/// deviceToken, as devices may register again without knowing their objectId.
+async fn handle_installation(req: &mut Request, ctx: &Context) -> Result<(), Error> {
+    if ctx.class != "_Installation" {
+        return Ok(());
+    }
+    let (params, object_id) = match req {
+        Request::Create(req) => (&mut req.params, None),
+        Request::Update(req) => (&mut req.params, Some(req.objectId.clone())),
+        _ => return Ok(()),
+    };
+
+    if object_id.is_none()
+        && !params.contains_key("deviceToken")
+        && !params.contains_key("installationId")
+        && ctx.user.installation_id.is_none()
+    {
+        return Err(Error::MissingRequiredField(
+            "at least one ID field (deviceToken, installationId) must be specified in this operation"
+                .to_string(),
+        ));
+    }
+
+    // 64 character device tokens are from iOS, which are case insensitive
+    if params.get_str("deviceToken").map(|x| x.len()) == Ok(64) {
+        lowercase_param(params, "deviceToken");
+    }
+    lowercase_param(params, "installationId");
+
+    let mut installation_id = params.get_str("installationId").ok().map(|x| x.to_string());
+    if installation_id.is_none() && !ctx.user.is_master {
+        installation_id = ctx.user.installation_id.as_ref().map(|x| x.to_lowercase());
+        if let (Some(installation_id), None) = (&installation_id, &object_id) {
+            params.insert("installationId", installation_id);
+        }
+    }
+
+    // Updating an installation without touching anything critical
+    if object_id.is_some()
+        && !params.contains_key("deviceToken")
+        && installation_id.is_none()
+        && !params.contains_key("deviceType")
+    {
+        return Ok(());
+    }
+
+    let found = find_installation(
+        params,
+        object_id.as_deref(),
+        installation_id.as_deref(),
+        ctx,
+    )
+    .await?;
+    if let Some(found) = found {
+        params.remove("objectId");
+        params.remove("createdAt");
+        let params = params.clone();
+        *req = Request::Update(UpdateRequest {
+            objectId: found,
+            filter: None,
+            params,
+        });
+        // Permissions were checked for the request as it was sent
+        enforce_write_permissions(req, ctx)?;
+    }
     Ok(())
-    // if (this.response || this.className !== '_Installation') {
-    //     return;
-    // }
-    //   if (!this.query && !this.data.deviceToken && !this.data.installationId && !this.auth.installationId) {
-    //     throw new Parse.Error(135, 'at least one ID field (deviceToken, installationId) ' + 'must be specified in this operation');
-    //   } // If the device token is 64 characters long, we assume it is for iOS
-    //   // and lowercase it.
-    //   if (this.data.deviceToken && this.data.deviceToken.length == 64) {
-    //     this.data.deviceToken = this.data.deviceToken.toLowerCase();
-    //   } // We lowercase the installationId if present
-    //   if (this.data.installationId) {
-    //     this.data.installationId = this.data.installationId.toLowerCase();
-    //   }
-    //   let installationId = this.data.installationId; // If data.installationId is not set and we're not master, we can lookup in auth
-    //   if (!installationId && !this.auth.isMaster) {
-    //     installationId = this.auth.installationId;
-    //   }
-    //   if (installationId) {
-    //     installationId = installationId.toLowerCase();
-    //   } // Updating _Installation but not updating anything critical
-    //   if (this.query && !this.data.deviceToken && !installationId && !this.data.deviceType) {
-    //     return;
-    //   }
-    //   var promise = Promise.resolve();
-    //   var idMatch; // Will be a match on either objectId or installationId
-    //   var objectIdMatch;
-    //   var installationIdMatch;
-    //   var deviceTokenMatches = []; // Instead of issuing 3 reads, let's do it with one OR.
-    //   const orQueries = [];
-    //   if (this.query && this.query.objectId) {
-    //     orQueries.push({
-    //       objectId: this.query.objectId
-    //     });
-    //   }
-    //   if (installationId) {
-    //     orQueries.push({
-    //       installationId: installationId
-    //     });
-    //   }
-    //   if (this.data.deviceToken) {
-    //     orQueries.push({
-    //       deviceToken: this.data.deviceToken
-    //     });
-    //   }
-    //   if (orQueries.length == 0) {
-    //     return;
-    //   }
-    //   promise = promise.then(() => {
-    //     return this.config.database.find('_Installation', {
-    //       $or: orQueries
-    //     }, {});
-    //   }).then(results => {
-    //     results.forEach(result => {
-    //       if (this.query && this.query.objectId && result.objectId == this.query.objectId) {
-    //         objectIdMatch = result;
-    //       }
-    //       if (result.installationId == installationId) {
-    //         installationIdMatch = result;
-    //       }
-    //       if (result.deviceToken == this.data.deviceToken) {
-    //         deviceTokenMatches.push(result);
-    //       }
-    //     }); // Sanity checks when running a query
-    //     if (this.query && this.query.objectId) {
-    //       if (!objectIdMatch) {
-    //         throw new Parse.Error(Parse.Error.OBJECT_NOT_FOUND, 'Object not found for update.');
-    //       }
-    //       if (this.data.installationId && objectIdMatch.installationId && this.data.installationId !== objectIdMatch.installationId) {
-    //         throw new Parse.Error(136, 'installationId may not be changed in this ' + 'operation');
-    //       }
-    //       if (this.data.deviceToken && objectIdMatch.deviceToken && this.data.deviceToken !== objectIdMatch.deviceToken && !this.data.installationId && !objectIdMatch.installationId) {
-    //         throw new Parse.Error(136, 'deviceToken may not be changed in this ' + 'operation');
-    //       }
-    //       if (this.data.deviceType && this.data.deviceType && this.data.deviceType !== objectIdMatch.deviceType) {
-    //         throw new Parse.Error(136, 'deviceType may not be changed in this ' + 'operation');
-    //       }
-    //     }
-    //     if (this.query && this.query.objectId && objectIdMatch) {
-    //       idMatch = objectIdMatch;
-    //     }
-    //     if (installationId && installationIdMatch) {
-    //       idMatch = installationIdMatch;
-    //     } // need to specify deviceType only if it's new
-    //     if (!this.query && !this.data.deviceType && !idMatch) {
-    //       throw new Parse.Error(135, 'deviceType must be specified in this operation');
-    //     }
-    //   }).then(() => {
-    //     if (!idMatch) {
-    //       if (!deviceTokenMatches.length) {
-    //         return;
-    //       } else if (deviceTokenMatches.length == 1 && (!deviceTokenMatches[0]['installationId'] || !installationId)) {
-    //         // Single match on device token but none on installationId, and either
-    //         // the passed object or the match is missing an installationId, so we
-    //         // can just return the match.
-    //         return deviceTokenMatches[0]['objectId'];
-    //       } else if (!this.data.installationId) {
-    //         throw new Parse.Error(132, 'Must specify installationId when deviceToken ' + 'matches multiple Installation objects');
-    //       } else {
-    //         // Multiple device token matches and we specified an installation ID,
-    //         // or a single match where both the passed and matching objects have
-    //         // an installation ID. Try cleaning out old installations that match
-    //         // the deviceToken, and return nil to signal that a new object should
-    //         // be created.
-    //         var delQuery = {
-    //           deviceToken: this.data.deviceToken,
-    //           installationId: {
-    //             $ne: installationId
-    //           }
-    //         };
-    //         if (this.data.appIdentifier) {
-    //           delQuery['appIdentifier'] = this.data.appIdentifier;
-    //         }
-    //         this.config.database.destroy('_Installation', delQuery).catch(err => {
-    //           if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //             // no deletions were made. Can be ignored.
-    //             return;
-    //           } // rethrow the error
-    //           throw err;
-    //         });
-    //         return;
-    //       }
-    //     } else {
-    //       if (deviceTokenMatches.length == 1 && !deviceTokenMatches[0]['installationId']) {
-    //         // Exactly one device token match and it doesn't have an installation
-    //         // ID. This is the one case where we want to merge with the existing
-    //         // object.
-    //         const delQuery = {
-    //           objectId: idMatch.objectId
-    //         };
-    //         return this.config.database.destroy('_Installation', delQuery).then(() => {
-    //           return deviceTokenMatches[0]['objectId'];
-    //         }).catch(err => {
-    //           if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //             // no deletions were made. Can be ignored
-    //             return;
-    //           } // rethrow the error
-    //           throw err;
-    //         });
-    //       } else {
-    //         if (this.data.deviceToken && idMatch.deviceToken != this.data.deviceToken) {
-    //           // We're setting the device token on an existing installation, so
-    //           // we should try cleaning out old installations that match this
-    //           // device token.
-    //           const delQuery = {
-    //             deviceToken: this.data.deviceToken
-    //           }; // We have a unique install Id, use that to preserve
-    //           // the interesting installation
-    //           if (this.data.installationId) {
-    //             delQuery['installationId'] = {
-    //               $ne: this.data.installationId
-    //             };
-    //           } else if (idMatch.objectId && this.data.objectId && idMatch.objectId == this.data.objectId) {
-    //             // we passed an objectId, preserve that instalation
-    //             delQuery['objectId'] = {
-    //               $ne: idMatch.objectId
-    //             };
-    //           } else {
-    //             // What to do here? can't really clean up everything...
-    //             return idMatch.objectId;
-    //           }
-    //           if (this.data.appIdentifier) {
-    //             delQuery['appIdentifier'] = this.data.appIdentifier;
-    //           }
-    //           this.config.database.destroy('_Installation', delQuery).catch(err => {
-    //             if (err.code == Parse.Error.OBJECT_NOT_FOUND) {
-    //               // no deletions were made. Can be ignored.
-    //               return;
-    //             } // rethrow the error
-    //             throw err;
-    //           });
-    //         } // In non-merge scenarios, just return the installation match id
-    //         return idMatch.objectId;
-    //       }
-    //     }
-    //   }).then(objId => {
-    //     if (objId) {
-    //       this.query = {
-    //         objectId: objId
-    //       };
-    //       delete this.data.objectId;
-    //       delete this.data.createdAt;
-    //     } // TODO: Validate ops (add/remove on channels, $inc on badge, etc.)
-    //   });
-    //   return promise;
 }
 
 async fn handle_session(req: &Request, ctx: &Context) -> Result<(), Error> {
@@ -255,60 +303,528 @@ async fn handle_session(req: &Request, ctx: &Context) -> Result<(), Error> {
     Ok(())
 }
 
-async fn validate_auth_data(req: &Request, ctx: &Context) -> Result<(), Error> {
-    // if req.class_name != "_User" {
-    //     return Ok(());
-    // }
+/// Validates `authData` on `_User` writes with the configured auth adapters.
+///
+/// A signup carrying authData already linked to a user becomes a login of that
+/// user: the request is turned into an update of the existing object. Returns
+/// the auth provider used, if any.
+async fn validate_auth_data(req: &mut Request, ctx: &Context) -> Result<Option<String>, Error> {
+    if ctx.class != "_User" {
+        return Ok(None);
+    }
 
-    // if req.query.is_none() && !req.data.contains_key("authData") {
-    //     let username = req.data.get_str("username").unwrap_or("");
-    //     let password = req.data.get_str("password").unwrap_or("");
+    let (params, object_id) = match req {
+        Request::Create(req) => (&req.params, None),
+        Request::Update(req) => (&req.params, Some(req.objectId.clone())),
+        _ => return Ok(None),
+    };
 
-    //     if username == "" {
-    //         return Err(Error::BadFormat(String::new()));
-    //         // throw new Parse.Error(Parse.Error.USERNAME_MISSING, 'bad or missing username');
-    //     }
-    //     if password == "" {
-    //         return Err(Error::BadFormat(String::new()));
-    //         // throw new Parse.Error(Parse.Error.PASSWORD_MISSING, 'password is required');
-    //     }
-    // }
-    //   if (this.data.authData && !Object.keys(this.data.authData).length || !Object.prototype.hasOwnProperty.call(this.data, 'authData')) {
-    //     // Handle saving authData to {} or if authData doesn't exist
-    //     return;
-    //   } else if (Object.prototype.hasOwnProperty.call(this.data, 'authData') && !this.data.authData) {
-    //     // Handle saving authData to null
-    //     throw new Parse.Error(Parse.Error.UNSUPPORTED_SERVICE, 'This authentication method is unsupported.');
-    //   }
+    if object_id.is_none() && !params.contains_key("authData") {
+        if params.get_str("username").unwrap_or("").is_empty() {
+            return Err(Error::UsernameMissing(
+                "bad or missing username".to_string(),
+            ));
+        }
+        if params.get_str("password").unwrap_or("").is_empty() {
+            return Err(Error::PasswordMissing("password is required".to_string()));
+        }
+    }
 
-    // let auth_data = req.data.get_document("authData").unwrap_or(doc! {});
-    // let providers = auth_data.keys();
-
-    // if providers.len() > 0 {
-    //     // const canHandleAuthData = providers.reduce((canHandle, provider) => {
-    //     //   var providerAuthData = authData[provider];
-    //     //   var hasToken = providerAuthData && providerAuthData.id;
-    //     //   return canHandle && (hasToken || providerAuthData == null);
-    //     // }, true);
-    //     // if (canHandleAuthData) {
-    //     //   return this.handleAuthData(authData);
-    //     // }
-    // }
-    Ok(())
-    //   throw new Parse.Error(Parse.Error.UNSUPPORTED_SERVICE, 'This authentication method is unsupported.');
+    let auth_data = match params.get("authData") {
+        None => return Ok(None),
+        Some(Bson::Document(auth_data)) if auth_data.is_empty() => return Ok(None),
+        Some(Bson::Document(auth_data)) => auth_data.clone(),
+        Some(_) => {
+            return Err(Error::UnsupportedService(
+                "This authentication method is unsupported.".to_string(),
+            ))
+        }
+    };
+
+    // Every provider needs an id, null unlinks it
+    let mut linked = vec![];
+    for (provider, data) in &auth_data {
+        let additional = ctx.config.auth.get(provider).map(|x| x.is_additional());
+        match data {
+            Bson::Null => {}
+            // Additional factors are checked by enroll_additional_factors
+            Bson::Document(_) if additional == Some(true) => {}
+            Bson::Document(data) if data.get_str("id").is_ok() => linked.push((provider, data)),
+            _ => {
+                return Err(Error::UnsupportedService(
+                    "This authentication method is unsupported.".to_string(),
+                ))
+            }
+        }
+    }
+    let provider = match linked.first() {
+        Some((provider, _)) => provider.to_string(),
+        None => return Ok(None),
+    };
+
+    for (provider, data) in &linked {
+        let adapter = ctx.config.auth.get(provider).ok_or_else(|| {
+            Error::UnsupportedService("This authentication method is unsupported.".to_string())
+        })?;
+        adapter.validate_auth_data(data).await?;
+        adapter.validate_app_id(data).await?;
+    }
+
+    let constraints: Vec<Bson> = linked
+        .iter()
+        .map(|(provider, data)| {
+            let mut constraint = doc! {};
+            constraint.insert(
+                format!("_auth_data_{}.id", provider),
+                data.get_str("id").unwrap_or(""),
+            );
+            Bson::Document(constraint)
+        })
+        .collect();
+    let users = ctx
+        .db
+        .find_documents("_User", doc! {"$or": constraints})
+        .await?;
+    if users.len() > 1 {
+        return Err(Error::AccountAlreadyLinked(
+            "this auth is already used".to_string(),
+        ));
+    }
+
+    let stored = match users.first() {
+        Some(stored) => stored,
+        None => return Ok(Some(provider)),
+    };
+    let user_id = stored.get_str("_id").unwrap_or("").to_string();
+    match object_id {
+        Some(object_id) if object_id != user_id => Err(Error::AccountAlreadyLinked(
+            "this auth is already used".to_string(),
+        )),
+        Some(_) => Ok(Some(provider)),
+        None => {
+            debug!("Logging in user {} with {} authData", user_id, provider);
+            user::validate_additional_factors(ctx, stored, Some(&auth_data)).await?;
+            let auth_data: Document = linked
+                .iter()
+                .map(|(provider, data)| (provider.to_string(), Bson::Document((*data).clone())))
+                .collect();
+            *req = Request::Update(UpdateRequest {
+                objectId: user_id,
+                filter: None,
+                params: doc! {"authData": auth_data},
+            });
+            Ok(Some(provider))
+        }
+    }
+}
+
+/// Enrolls additional factors like `mfa` sent in the `authData` of an existing
+/// user. Returns what the adapters send back to the client, e.g. recovery
+/// codes.
+async fn enroll_additional_factors(req: &mut Request, ctx: &Context) -> Result<Document, Error> {
+    let mut response = doc! {};
+    if ctx.class != "_User" {
+        return Ok(response);
+    }
+    let (params, object_id) = match req {
+        Request::Create(req) => (&mut req.params, None),
+        Request::Update(req) => (&mut req.params, Some(req.objectId.as_str())),
+        _ => return Ok(response),
+    };
+    let auth_data = match params.get_document_mut("authData") {
+        Ok(auth_data) => auth_data,
+        Err(_) => return Ok(response),
+    };
+
+    for (provider, adapter) in ctx.config.auth.additional() {
+        let data = match auth_data.get(&provider) {
+            Some(Bson::Document(data)) => Some(data.clone()),
+            Some(Bson::Null) => None,
+            _ => continue,
+        };
+        let object_id = object_id.ok_or_else(|| {
+            Error::UnsupportedService(format!(
+                "{} can only be enabled for existing users.",
+                provider
+            ))
+        })?;
+
+        // Replacing or removing an enrolled factor needs proof of it, null
+        // carries none so only the master key can send it
+        let stored = user::find_user(ctx, doc! {"_id": object_id}).await?;
+        let key = format!("_auth_data_{}", provider);
+        if let Some(Ok(enrolled)) = stored.as_ref().map(|x| x.get_document(&key)) {
+            if !ctx.user.is_master {
+                let data = data.as_ref().ok_or_else(|| {
+                    Error::MfaTokenRequired(format!(
+                        "Missing additional authData {} oldToken",
+                        provider
+                    ))
+                })?;
+                adapter.validate_update(data, enrolled).await?;
+            }
+        }
+
+        let data = match data {
+            Some(data) => data,
+            None => continue,
+        };
+        let (save, data_response) = adapter.validate_setup(&data).await?;
+        auth_data.insert(provider.clone(), save.map_or(Bson::Null, Bson::Document));
+        if let Some(data_response) = data_response {
+            response.insert(provider, data_response);
+        }
+    }
+    Ok(response)
 }
 
-async fn run_before_save_trigger(req: &Request, ctx: &Context) -> Result<(), Error> {
+/// The stored object an update or delete applies to, only loaded for classes
+/// with triggers.
+async fn load_original(
+    req: &Request,
+    ctx: &Context,
+    triggers: &ClassTriggers,
+) -> Result<Option<Document>, Error> {
+    let object_id = match req {
+        Request::Update(req) => &req.objectId,
+        Request::Delete(req) => &req.objectId,
+        _ => return Ok(None),
+    };
+    if !triggers.has_write_triggers() {
+        return Ok(None);
+    }
+    find_object(ctx, object_id).await
+}
+
+async fn find_object(ctx: &Context, object_id: &str) -> Result<Option<Document>, Error> {
+    let find = FindRequest {
+        include: vec![],
+        filter: Some(doc! {"objectId": object_id}),
+        limit: Some(1),
+        skip: None,
+        sort: None,
+        count: false,
+        keys: vec![],
+        read_preference: None,
+    };
+    Ok(ctx.db.query_objects(&find, ctx).await?.pop())
+}
+
+/// The object as stored after the write, for `afterSave`.
+async fn load_saved(
+    req: &Request,
+    ctx: &Context,
+    triggers: &ClassTriggers,
+    response: &Document,
+) -> Result<Option<Document>, Error> {
+    if triggers.after_save.is_none() {
+        return Ok(None);
+    }
+    let object_id = match req {
+        Request::Create(_) => response.get_str("objectId").unwrap_or(""),
+        Request::Update(req) => &req.objectId,
+        _ => return Ok(None),
+    };
+    find_object(ctx, object_id).await
+}
+
+/// Fields the server maintains, triggers can not change them.
+fn is_default_field(key: &str) -> bool {
+    matches!(key, "objectId" | "createdAt" | "updatedAt")
+}
+
+/// The result of an `__op` on the current value of a field, `None` when the
+/// field is removed.
+fn apply_operation(current: Option<&Bson>, op: &Document) -> Option<Bson> {
+    let objects = || op.get_array("objects").cloned().unwrap_or_default();
+    let array = || match current {
+        Some(Bson::Array(array)) => array.clone(),
+        _ => vec![],
+    };
+    match op.get_str("__op").unwrap_or("") {
+        "Delete" => None,
+        "Increment" => Some(match (current, op.get("amount")) {
+            (Some(Bson::Double(x)), Some(amount)) => Bson::Double(x + as_f64(amount)),
+            (current, Some(Bson::Double(amount))) => {
+                Bson::Double(current.map_or(0.0, as_f64) + amount)
+            }
+            (current, amount) => Bson::Int64(current.map_or(0, as_i64) + amount.map_or(0, as_i64)),
+        }),
+        "Add" => Some(Bson::Array(array().into_iter().chain(objects()).collect())),
+        "AddUnique" => {
+            let mut array = array();
+            for object in objects() {
+                if !array.contains(&object) {
+                    array.push(object);
+                }
+            }
+            Some(Bson::Array(array))
+        }
+        "Remove" => {
+            let objects = objects();
+            Some(Bson::Array(
+                array()
+                    .into_iter()
+                    .filter(|x| !objects.contains(x))
+                    .collect(),
+            ))
+        }
+        // Relations aren't part of the object
+        _ => current.cloned(),
+    }
+}
+
+fn as_f64(value: &Bson) -> f64 {
+    match value {
+        Bson::Double(x) => *x,
+        Bson::Int32(x) => *x as f64,
+        Bson::Int64(x) => *x as f64,
+        _ => 0.0,
+    }
+}
+
+fn as_i64(value: &Bson) -> i64 {
+    match value {
+        Bson::Double(x) => *x as i64,
+        Bson::Int32(x) => *x as i64,
+        Bson::Int64(x) => *x,
+        _ => 0,
+    }
+}
+
+/// The object a save results in, as triggers see it.
+fn trigger_object(params: &Document, original: Option<&Document>) -> Document {
+    let mut object = original.cloned().unwrap_or_default();
+    for (key, value) in params.iter().filter(|(key, _)| !key.starts_with('_')) {
+        let value = match value {
+            Bson::Document(op) if op.contains_key("__op") => apply_operation(object.get(key), op),
+            value => Some(value.clone()),
+        };
+        match value {
+            Some(value) => object.insert(key.clone(), value),
+            None => object.remove(key),
+        };
+    }
+    object
+}
+
+fn trigger_request(ctx: &Context, object: Document, original: Option<Document>) -> TriggerRequest {
+    TriggerRequest {
+        class_name: ctx.class.clone(),
+        object,
+        original,
+        user: ctx.user.clone(),
+        master: ctx.user.is_master,
+        context: ctx.cloud_context.clone(),
+    }
+}
+
+/// Runs `beforeSave` or `beforeDelete`. Fields the trigger changed on the
+/// object are written back to the params.
+async fn run_before_save_trigger(
+    req: &mut Request,
+    ctx: &Context,
+    triggers: &ClassTriggers,
+    original: Option<&Document>,
+) -> Result<(), Error> {
+    let (params, is_create) = match req {
+        Request::Create(req) => (&mut req.params, true),
+        Request::Update(req) => (&mut req.params, false),
+        Request::Delete(_) => {
+            if let (Some(trigger), Some(original)) = (&triggers.before_delete, original) {
+                let req = trigger_request(ctx, original.clone(), Some(original.clone()));
+                trigger(req).await.map_err(cloud::validation_error)?;
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    let trigger = match &triggers.before_save {
+        Some(trigger) => trigger,
+        None => return Ok(()),
+    };
+
+    let object = trigger_object(params, original);
+    let request = trigger_request(ctx, object.clone(), original.cloned());
+    let saved = trigger(request).await.map_err(cloud::validation_error)?;
+    for (key, value) in saved.iter() {
+        if object.get(key) != Some(value) && !is_default_field(key) {
+            params.insert(key.clone(), value.clone());
+        }
+    }
+    for key in object.keys().filter(|key| !saved.contains_key(key)) {
+        if is_create {
+            params.remove(key);
+        } else if !is_default_field(key) {
+            params.insert(key.clone(), doc! {"__op": "Delete"});
+        }
+    }
     Ok(())
 }
 
-async fn delete_email_reset_token_if_needed() {}
+/// Changing the email or password invalidates a pending password reset.
+fn delete_email_reset_token_if_needed(req: &mut Request, ctx: &Context) {
+    if ctx.class != "_User" {
+        return;
+    }
+    if let Request::Update(req) = req {
+        if req.params.contains_key("email") || req.params.contains_key("password") {
+            req.params
+                .insert("_perishable_token", doc! {"__op": "Delete"});
+            req.params
+                .insert("_perishable_token_expires_at", doc! {"__op": "Delete"});
+        }
+    }
+}
+
+fn is_valid_field_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() => {
+            chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Rejects invalid keys and adds unknown fields to the class schema.
+async fn validate_schema(req: &Request, ctx: &Context) -> Result<(), Error> {
+    let params = match req {
+        Request::Create(req) => &req.params,
+        Request::Update(req) => &req.params,
+        _ => return Ok(()),
+    };
+    let schema = ctx.cache.get_schema(&ctx.class);
 
-async fn validate_schema() {}
+    let mut new_fields = vec![];
+    for (key, value) in params {
+        match key.as_str() {
+            key if is_special_param(key) => continue,
+            // Internal fields set by the server, REST strips them from payloads
+            key if key.starts_with('_') => continue,
+            "objectId" | "createdAt" | "updatedAt" => {
+                return Err(Error::InvalidKeyName(format!(
+                    "{} is an invalid field name.",
+                    key
+                )))
+            }
+            key if !is_valid_field_name(key) => {
+                return Err(Error::InvalidKeyName(format!(
+                    "Invalid field name: {}.",
+                    key
+                )))
+            }
+            _ => {}
+        }
+        let known = schema
+            .as_ref()
+            .map(|x| x.fields.contains_key(key))
+            .unwrap_or(false);
+        if !known {
+            if let Some(type_name) = infer_type(value) {
+                new_fields.push((key.clone(), type_name));
+            }
+        }
+    }
+
+    if new_fields.is_empty() && schema.is_some() {
+        return Ok(());
+    }
+    info!("Adding {} fields to {}", new_fields.len(), ctx.class);
+    ctx.db.update_schema(&ctx.class, &new_fields).await?;
+    reload_schema(ctx).await
+}
 
 async fn set_required_fields_if_needed() {}
 
-async fn transform_user() {}
+async fn is_taken(
+    ctx: &Context,
+    key: &str,
+    value: &str,
+    object_id: Option<&str>,
+) -> Result<bool, Error> {
+    let mut filter = doc! {};
+    filter.insert(key, value);
+    if let Some(object_id) = object_id {
+        filter.insert("_id", doc! {"$ne": object_id});
+    }
+    Ok(!ctx.db.find_documents("_User", filter).await?.is_empty())
+}
+
+/// Hashes passwords and checks username and email uniqueness.
+async fn transform_user(req: &mut Request, ctx: &Context) -> Result<(), Error> {
+    if ctx.class != "_User" {
+        return Ok(());
+    }
+    let (params, object_id) = match req {
+        Request::Create(req) => (&mut req.params, None),
+        Request::Update(req) => (&mut req.params, Some(req.objectId.as_str())),
+        _ => return Ok(()),
+    };
+
+    // Users signing up with authData only get a random username
+    if object_id.is_none() && !params.contains_key("username") && params.contains_key("authData") {
+        params.insert("username", util::random_string(25));
+    }
+    // Setting a password upgrades an anonymous user to a regular one
+    if object_id.is_some() && params.contains_key("password") {
+        if !params.contains_key("authData") {
+            params.insert("authData", doc! {});
+        }
+        if let Ok(auth_data) = params.get_document_mut("authData") {
+            if !auth_data.contains_key("anonymous") {
+                auth_data.insert("anonymous", Bson::Null);
+            }
+        }
+    }
+
+    if let Ok(username) = params.get_str("username") {
+        if is_taken(ctx, "username", username, object_id).await? {
+            return Err(Error::UsernameTaken(
+                "Account already exists for this username.".to_string(),
+            ));
+        }
+    }
+    if let Ok(email) = params.get_str("email") {
+        if is_taken(ctx, "email", email, object_id).await? {
+            return Err(Error::EmailTaken(
+                "Account already exists for this email address.".to_string(),
+            ));
+        }
+    }
+
+    if params.contains_key("emailVerified") && !ctx.user.is_master {
+        return Err(Error::Forbidden(
+            "Clients aren't allowed to manually update email verification.".to_string(),
+        ));
+    }
+    if ctx.config.verify_user_emails && params.get_str("email").is_ok() {
+        params.insert("emailVerified", false);
+        params.insert("_email_verify_token", util::new_token());
+        let validity = ctx.config.email_verify_token_validity_duration;
+        if let Some(expires_at) = user::token_expires_at(validity) {
+            params.insert(
+                "_email_verify_token_expires_at",
+                util::date_value(&expires_at),
+            );
+        }
+    }
+
+    if let Ok(password) = params.get_str("password").map(|x| x.to_string()) {
+        let stored = match object_id {
+            Some(object_id) => user::find_user(ctx, doc! {"_id": object_id}).await?,
+            None => None,
+        };
+        let username = params.get_str("username").ok();
+        let fields =
+            password::new_password_fields(&ctx.config, stored.as_ref(), username, &password)?;
+        params.remove("password");
+        for (key, value) in fields {
+            params.insert(key, value);
+        }
+    }
+    Ok(())
+}
 
 async fn expand_files_for_existing_objects() {}
 
@@ -332,54 +848,243 @@ async fn destroy_uplicated_sessions(req: &Request, ctx: &Context) -> Result<(),
     // db.destroy_session("").await?;
 }
 
-async fn run_database_operation() {}
+async fn run_database_operation(req: &Request, ctx: &Context) -> Result<Document, Error> {
+    let schema = ctx
+        .cache
+        .get_schema(&ctx.class)
+        .unwrap_or_else(|| Schema::new(ctx.class.clone()));
+    let now = Utc::now();
 
-async fn create_session_token_if_needed(req: &Request, ctx: &Context) -> Result<(), Error> {
-    if ctx.class != "_User" {
+    match req {
+        Request::Create(req) => {
+            let object_id = util::new_object_id();
+            let mut params = req.params.clone();
+            params.insert("createdAt", util::date_value(&now));
+            params.insert("updatedAt", util::date_value(&now));
+            ctx.db.create_object(&schema, &object_id, &params).await?;
+            Ok(doc! {"objectId": object_id, "createdAt": util::to_iso_string(&now)})
+        }
+        Request::Update(req) => {
+            let mut params = req.params.clone();
+            params.insert("updatedAt", util::date_value(&now));
+            ctx.db
+                .update_object(&schema, &req.objectId, req.filter.as_ref(), &params)
+                .await?;
+            Ok(doc! {"updatedAt": util::to_iso_string(&now)})
+        }
+        Request::Delete(req) => {
+            ctx.db
+                .delete_object(&schema, &req.objectId, req.filter.as_ref())
+                .await?;
+            Ok(doc! {})
+        }
+        _ => Ok(doc! {}),
+    }
+}
+
+/// Role writes can change the role graph of any user.
+fn invalidate_role_cache(ctx: &Context) {
+    if ctx.class == "_Role" {
+        ctx.cache.clear_roles();
+    }
+}
+
+/// Cached sessions must not outlive a `_Session` write, and a password change
+/// revokes every other session of the user.
+async fn revoke_sessions_if_needed(req: &Request, ctx: &Context) -> Result<(), Error> {
+    match req {
+        Request::Update(_) | Request::Delete(_) if ctx.class == "_Session" => {
+            ctx.cache.clear_sessions();
+            Ok(())
+        }
+        Request::Update(req)
+            if ctx.class == "_User" && req.params.contains_key("_hashed_password") =>
+        {
+            let keep = ctx.user.session_token.as_deref();
+            user::revoke_sessions(ctx, &req.objectId, keep).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Mails the verification link after the email of a user was set.
+async fn send_verification_email_if_needed(
+    req: &Request,
+    ctx: &Context,
+    response: &Document,
+) -> Result<(), Error> {
+    if ctx.class != "_User" || !ctx.config.verify_user_emails {
         return Ok(());
-    } // Don't generate session for updating user (this.query is set) unless authData exists
-      //   if req.query.is_some() && !req.data.contains_key("authData") {
-      //     return Ok(());
-      //   } // Don't generate new sessionToken if linking via sessionToken
-      //   if req.auth.user.is_some() && req.data.contains_key("authData") {
-      //     return Ok(());
-      //   }
-      //   if (!this.storage['authProvider'] && // signup call, with
-      //   this.config.preventLoginWithUnverifiedEmail && // no login without verification
-      //   this.config.verifyUserEmails) {
-      //     // verification is on
-      //     return; // do not create the session token in that case!
-      //   }
+    }
+    let user_id = match req {
+        Request::Create(req) if req.params.contains_key("_email_verify_token") => {
+            response.get_str("objectId").unwrap_or("")
+        }
+        Request::Update(req) if req.params.contains_key("_email_verify_token") => &req.objectId,
+        _ => return Ok(()),
+    };
+    if let Err(e) = user::send_verification_email(ctx, user_id).await {
+        error!("Could not send verification email: {}", e);
+    }
     Ok(())
-    // TODO:
-    //   return this.createSessionToken();
 }
 
-async fn handle_followup() {}
+/// Signups, and logins through authData, get a new session.
+async fn create_session_token_if_needed(
+    req: &Request,
+    ctx: &Context,
+    signup: bool,
+    auth_provider: Option<&str>,
+    response: &mut Document,
+) -> Result<(), Error> {
+    if ctx.class != "_User" || !signup {
+        return Ok(());
+    }
+    let (user_id, action) = match req {
+        // No session until the email is verified
+        Request::Create(_)
+            if ctx.config.verify_user_emails && ctx.config.prevent_login_with_unverified_email =>
+        {
+            return Ok(())
+        }
+        Request::Create(_) => (
+            response.get_str("objectId").unwrap_or("").to_string(),
+            "signup",
+        ),
+        // A signup with authData that is already linked logs the user in
+        Request::Update(req) => {
+            *response = user::get_user(ctx, &req.objectId).await?;
+            (req.objectId.clone(), "login")
+        }
+        _ => return Ok(()),
+    };
 
-async fn run_after_save_trigger() {}
+    let created_with = doc! {
+        "action": action,
+        "authProvider": auth_provider.unwrap_or("password"),
+    };
+    let session_token = user::create_session(ctx, &user_id, created_with).await?;
+    response.insert("sessionToken", session_token);
+    Ok(())
+}
+
+/// Starts `afterSave` or `afterDelete` once the write is committed.
+fn run_after_save_trigger(
+    req: &Request,
+    ctx: &Context,
+    triggers: &ClassTriggers,
+    original: Option<Document>,
+    saved: Option<Document>,
+    response: &Document,
+) {
+    let (name, trigger, object) = match req {
+        Request::Create(_) | Request::Update(_) if saved.is_some() => {
+            ("afterSave", &triggers.after_save, saved.unwrap_or_default())
+        }
+        // The saved object isn't readable by the user
+        Request::Create(req) => {
+            let mut object = trigger_object(&req.params, None);
+            object.extend(response.clone());
+            ("afterSave", &triggers.after_save, object)
+        }
+        Request::Update(req) => {
+            let mut object = trigger_object(&req.params, original.as_ref());
+            object.extend(response.clone());
+            ("afterSave", &triggers.after_save, object)
+        }
+        Request::Delete(_) => match &original {
+            Some(original) => ("afterDelete", &triggers.after_delete, original.clone()),
+            None => return,
+        },
+        _ => return,
+    };
+    if let Some(trigger) = trigger {
+        let req = trigger_request(ctx, object, original);
+        cloud::spawn_trigger(name, trigger.clone(), req);
+    }
+}
 
 // async fn clean_user_auth_data(req: Request<'_>, doc: &Document) -> Document {
 
 // }
 
-pub async fn write(req: Request, ctx: Context) -> Result<(), Error> {
+pub async fn write(mut req: Request, ctx: Context) -> Result<Document, Error> {
     // let acl = util::get_acl(request).await?;
     // util::validate_class_creation(request).await?;
-    handle_installation().await?;
+    let is_create = matches!(req, Request::Create(_));
+    handle_installation(&mut req, &ctx).await?;
     handle_session(&req, &ctx).await?;
-    validate_auth_data(&req, &ctx).await?;
-    run_before_save_trigger(&req, &ctx).await?;
-    // delete_email_reset_token_if_needed().await?;
-    // validate_schema().await?;
+    let auth_data_response = enroll_additional_factors(&mut req, &ctx).await?;
+    let auth_provider = validate_auth_data(&mut req, &ctx).await?;
+    let triggers = cloud::class_triggers(&ctx).await?;
+    let original = load_original(&req, &ctx, &triggers).await?;
+    run_before_save_trigger(&mut req, &ctx, &triggers, original.as_ref()).await?;
+    delete_email_reset_token_if_needed(&mut req, &ctx);
+    validate_schema(&req, &ctx).await?;
     // &set_required_fields_if_needed, &ctx().await?;
-    // transform_user().await?;
+    transform_user(&mut req, &ctx).await?;
     // expand_files_for_existing_objects().await?;
     destroy_uplicated_sessions(&req, &ctx).await?;
-    // run_database_operation().await?;
-    create_session_token_if_needed(&req, &ctx).await?;
-    // handle_followup().await?;
-    // run_after_save_trigger().await?;
+    let mut response = run_database_operation(&req, &ctx).await?;
+    // Creates merged into an existing object still tell the client its id
+    if let (true, Request::Update(req)) = (is_create, &req) {
+        response.insert("objectId", &req.objectId);
+    }
+    invalidate_role_cache(&ctx);
+    revoke_sessions_if_needed(&req, &ctx).await?;
+    if !auth_data_response.is_empty() {
+        response.insert("authDataResponse", auth_data_response);
+    }
+    send_verification_email_if_needed(&req, &ctx, &response).await?;
+    create_session_token_if_needed(
+        &req,
+        &ctx,
+        is_create,
+        auth_provider.as_deref(),
+        &mut response,
+    )
+    .await?;
+    let saved = load_saved(&req, &ctx, &triggers, &response).await?;
+    run_after_save_trigger(&req, &ctx, &triggers, original, saved, &response);
     // let response = clean_user_auth_data(doc!{}).await?;
-    Ok(())
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::trigger_object;
+    use bson::{doc, Bson};
+
+    #[test]
+    fn trigger_object_applies_operations() {
+        let original = doc! {"score": 5, "tags": ["a"], "ratio": 0.5, "name": "x"};
+        let params = doc! {
+            "score": {"__op": "Increment", "amount": 2},
+            "tags": {"__op": "AddUnique", "objects": ["a", "b"]},
+            "ratio": {"__op": "Increment", "amount": 1},
+            "name": {"__op": "Delete"},
+            "count": {"__op": "Increment", "amount": 1},
+        };
+        let object = trigger_object(&params, Some(&original));
+        assert_eq!(object.get("score"), Some(&Bson::Int64(7)));
+        assert_eq!(object.get("tags"), Some(&Bson::from(vec!["a", "b"])));
+        assert_eq!(object.get("ratio"), Some(&Bson::Double(1.5)));
+        assert_eq!(object.get("name"), None);
+        assert_eq!(object.get("count"), Some(&Bson::Int64(1)));
+    }
+
+    #[test]
+    fn trigger_object_adds_and_removes_array_items() {
+        let original = doc! {"tags": ["a", "b", "a"]};
+        let params = doc! {"tags": {"__op": "Remove", "objects": ["a"]}};
+        let object = trigger_object(&params, Some(&original));
+        assert_eq!(object.get("tags"), Some(&Bson::from(vec!["b"])));
+
+        let params = doc! {"tags": {"__op": "Add", "objects": ["a"]}};
+        let object = trigger_object(&params, Some(&original));
+        assert_eq!(
+            object.get("tags"),
+            Some(&Bson::from(vec!["a", "b", "a", "a"]))
+        );
+    }
 }